@@ -0,0 +1,52 @@
+//! Loopback throughput comparison of the UDP batching strategies.
+//!
+//! Run with `cargo bench --bench udp_batch`.
+
+use std::time::Instant;
+
+use crab_net::batch::{BatchMode, UdpBatcher};
+use tokio::{net::UdpSocket, runtime::Builder};
+
+const DATAGRAMS: usize = 500_000;
+const PAYLOAD_LEN: usize = 64;
+const BATCH_SIZE: usize = 64;
+
+fn main() {
+    let rt = Builder::new_current_thread().enable_all().build().unwrap();
+    rt.block_on(async {
+        for mode in [BatchMode::Off, BatchMode::Sendmmsg, BatchMode::Gso] {
+            bench_mode(mode).await;
+        }
+    });
+}
+
+async fn bench_mode(mode: BatchMode) {
+    let sink = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    socket.connect(sink.local_addr().unwrap()).await.unwrap();
+
+    // Keep the receive queue drained so the sender is not throttled by drops
+    let drain = tokio::spawn(async move {
+        let mut buf = vec![0u8; 65536];
+        while sink.recv(&mut buf).await.is_ok() {}
+    });
+
+    let mut batcher = UdpBatcher::new(mode, BATCH_SIZE);
+    let batch = vec![vec![0xAB; PAYLOAD_LEN]; BATCH_SIZE];
+
+    let start = Instant::now();
+    let mut packets = 0;
+    while packets < DATAGRAMS {
+        let (sent, _) = batcher.send(&socket, &batch).await;
+        packets += sent.max(1);
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    drain.abort();
+
+    println!(
+        "{:<10} effective: {:<10} {:>12.0} pkt/s",
+        format!("{mode:?}"),
+        format!("{:?}", batcher.mode()),
+        packets as f64 / elapsed
+    );
+}
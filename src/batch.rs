@@ -0,0 +1,283 @@
+//! Batched UDP transmission.
+//!
+//! On Linux a batch of datagrams can be handed to the kernel with a single
+//! `sendmmsg(2)` call, or, when every datagram has the same size, as one large
+//! buffer split by the kernel through UDP generic segmentation offload
+//! (`UDP_SEGMENT`). Both paths degrade automatically to the next slower one
+//! when the running kernel or NIC rejects them. On other platforms every batch
+//! is sent one datagram at a time.
+
+use std::{
+    str::FromStr,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use log::{debug, warn};
+use tokio::{io::Interest, net::UdpSocket};
+
+/// Maximum number of segments the kernel accepts in a single GSO send.
+const GSO_MAX_SEGMENTS: usize = 64;
+/// Maximum size of the buffer handed to a single GSO send.
+const GSO_MAX_BYTES: usize = 65000;
+
+// Every client probes the fast paths on its own, the fallbacks are logged once
+static GSO_FALLBACK: AtomicBool = AtomicBool::new(false);
+static SENDMMSG_FALLBACK: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchMode {
+    /// One `send` per datagram.
+    Off,
+    /// One `sendmmsg` per batch.
+    Sendmmsg,
+    /// UDP GSO for runs of equally sized payloads, `sendmmsg` for the rest.
+    Gso,
+}
+
+impl FromStr for BatchMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "sendmmsg" => Ok(Self::Sendmmsg),
+            "gso" => Ok(Self::Gso),
+            _ => Err(format!("unknown batch mode: {s}")),
+        }
+    }
+}
+
+/// Syscall batching of the UDP senders.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchOptions {
+    pub mode: BatchMode,
+    /// Datagrams handed to the kernel per batched send.
+    pub size: usize,
+}
+
+impl Default for BatchOptions {
+    fn default() -> Self {
+        Self {
+            mode: BatchMode::Off,
+            size: 32,
+        }
+    }
+}
+
+/// Per-socket batching state. The effective mode only ever degrades, so a
+/// failed fast path is probed once per client rather than once per batch.
+#[derive(Debug, Clone)]
+pub struct UdpBatcher {
+    mode: BatchMode,
+    batch_size: usize,
+}
+
+impl UdpBatcher {
+    pub fn new(mode: BatchMode, batch_size: usize) -> Self {
+        Self {
+            mode,
+            batch_size: batch_size.max(1),
+        }
+    }
+
+    pub fn mode(&self) -> BatchMode {
+        self.mode
+    }
+
+    /// Number of payloads the caller should accumulate before calling [`UdpBatcher::send`].
+    pub fn batch_size(&self) -> usize {
+        match self.mode {
+            BatchMode::Off => 1,
+            _ => self.batch_size,
+        }
+    }
+
+    /// Sends `payloads` over the connected `socket` and returns the number of
+    /// datagrams and bytes accepted by the kernel, the other datagrams were
+    /// dropped.
+    pub async fn send(&mut self, socket: &UdpSocket, payloads: &[Vec<u8>]) -> (usize, usize) {
+        let mut packets_sent = 0;
+        let mut bytes_sent = 0;
+        let mut pending = payloads;
+
+        while !pending.is_empty() {
+            let gso_segments = match self.mode {
+                BatchMode::Gso => gso_segments(pending),
+                _ => 0,
+            };
+
+            if gso_segments > 0 {
+                let chunk = &pending[..gso_segments];
+                match socket
+                    .async_io(Interest::WRITABLE, || sys::send_gso(socket, chunk))
+                    .await
+                {
+                    Ok(bytes) => {
+                        packets_sent += chunk.len();
+                        bytes_sent += bytes;
+                    }
+                    Err(err) if sys::is_unsupported(&err) => {
+                        if !GSO_FALLBACK.swap(true, Ordering::Relaxed) {
+                            warn!("UDP GSO unavailable ({err}), falling back to sendmmsg");
+                        }
+                        self.mode = BatchMode::Sendmmsg;
+                        continue;
+                    }
+                    // The whole chunk is dropped, the caller counts it as errors
+                    Err(err) => debug!("UDP GSO send of {} datagrams failed: {err}", chunk.len()),
+                }
+                pending = &pending[gso_segments..];
+            } else if self.mode != BatchMode::Off {
+                match socket
+                    .async_io(Interest::WRITABLE, || sys::send_mmsg(socket, pending))
+                    .await
+                {
+                    Ok(sent) => {
+                        packets_sent += sent;
+                        bytes_sent += pending[..sent].iter().map(Vec::len).sum::<usize>();
+                        pending = &pending[sent.max(1)..];
+                    }
+                    Err(err) if sys::is_unsupported(&err) => {
+                        if !SENDMMSG_FALLBACK.swap(true, Ordering::Relaxed) {
+                            warn!("sendmmsg unavailable ({err}), falling back to single sends");
+                        }
+                        self.mode = BatchMode::Off;
+                    }
+                    // The first datagram of the batch was rejected: skip it and go on
+                    Err(_) => pending = &pending[1..],
+                }
+            } else {
+                for payload in pending {
+                    if socket.send(payload).await.is_ok() {
+                        packets_sent += 1;
+                        bytes_sent += payload.len();
+                    }
+                }
+                pending = &[];
+            }
+        }
+
+        (packets_sent, bytes_sent)
+    }
+}
+
+/// Length of the leading run of `payloads` that can be coalesced into one GSO
+/// send, or 0 when the batch is not worth segmenting.
+fn gso_segments(payloads: &[Vec<u8>]) -> usize {
+    let segment = payloads[0].len();
+    if segment == 0 {
+        return 0;
+    }
+    let max_segments = GSO_MAX_SEGMENTS.min(GSO_MAX_BYTES / segment);
+    let run = payloads
+        .iter()
+        .take(max_segments)
+        .take_while(|p| p.len() == segment)
+        .count();
+    if run > 1 {
+        run
+    } else {
+        0
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use std::{io, mem, os::fd::AsRawFd, ptr};
+
+    use tokio::net::UdpSocket;
+
+    pub fn send_mmsg(socket: &UdpSocket, payloads: &[Vec<u8>]) -> io::Result<usize> {
+        let mut iovecs: Vec<libc::iovec> = payloads
+            .iter()
+            .map(|p| libc::iovec {
+                iov_base: p.as_ptr() as *mut libc::c_void,
+                iov_len: p.len(),
+            })
+            .collect();
+        let mut msgs: Vec<libc::mmsghdr> = iovecs
+            .iter_mut()
+            .map(|iov| {
+                // SAFETY: mmsghdr is a plain C struct for which all-zeroes is a valid value
+                let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+                msg.msg_hdr.msg_iov = iov;
+                msg.msg_hdr.msg_iovlen = 1;
+                msg
+            })
+            .collect();
+
+        // SAFETY: every header points to an iovec borrowed from `payloads`, all of which outlive the call
+        let sent = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                msgs.as_mut_ptr(),
+                msgs.len() as libc::c_uint,
+                0,
+            )
+        };
+        if sent < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(sent as usize)
+        }
+    }
+
+    pub fn send_gso(socket: &UdpSocket, payloads: &[Vec<u8>]) -> io::Result<usize> {
+        let segment = payloads[0].len() as u16;
+        let buf = payloads.concat();
+        let mut iov = libc::iovec {
+            iov_base: buf.as_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        // u64 storage keeps the control buffer aligned for cmsghdr
+        let mut control = [0u64; 4];
+
+        // SAFETY: msghdr and cmsghdr only reference `iov`, `buf` and `control`, which live until sendmsg returns
+        let sent = unsafe {
+            let mut msg: libc::msghdr = mem::zeroed();
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = libc::CMSG_SPACE(mem::size_of::<u16>() as u32) as _;
+
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_UDP;
+            (*cmsg).cmsg_type = libc::UDP_SEGMENT;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(mem::size_of::<u16>() as u32) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut u16, segment);
+
+            libc::sendmsg(socket.as_raw_fd(), &msg, 0)
+        };
+        if sent < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(sent as usize)
+        }
+    }
+
+    pub fn is_unsupported(err: &io::Error) -> bool {
+        matches!(
+            err.raw_os_error(),
+            Some(libc::ENOSYS | libc::EINVAL | libc::EIO | libc::ENOPROTOOPT | libc::EOPNOTSUPP)
+        )
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod sys {
+    use std::io;
+
+    use tokio::net::UdpSocket;
+
+    pub fn send_mmsg(_socket: &UdpSocket, _payloads: &[Vec<u8>]) -> io::Result<usize> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn send_gso(_socket: &UdpSocket, _payloads: &[Vec<u8>]) -> io::Result<usize> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
+
+    pub fn is_unsupported(err: &io::Error) -> bool {
+        err.kind() == io::ErrorKind::Unsupported
+    }
+}
@@ -0,0 +1,419 @@
+//! Local side of the client sockets: source addresses, interface and
+//! socket options.
+
+use std::{
+    fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+
+use socket2::{Domain, Protocol, Socket, TcpKeepalive, Type};
+use tokio::net::TcpSocket;
+
+use crate::ports::PortOptions;
+
+/// A single source address or a CIDR block of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddrRange {
+    V4 { first: u32, count: u32 },
+    V6 { first: u128, count: u128 },
+}
+
+impl AddrRange {
+    fn is_ipv6(&self) -> bool {
+        matches!(self, AddrRange::V6 { .. })
+    }
+
+    fn len(&self) -> u128 {
+        match self {
+            AddrRange::V4 { count, .. } => *count as u128,
+            AddrRange::V6 { count, .. } => *count,
+        }
+    }
+
+    fn nth(&self, n: u128) -> IpAddr {
+        match self {
+            AddrRange::V4 { first, .. } => Ipv4Addr::from(first + n as u32).into(),
+            AddrRange::V6 { first, .. } => Ipv6Addr::from(first + n).into(),
+        }
+    }
+}
+
+impl FromStr for AddrRange {
+    type Err = String;
+
+    /// Parses `IP` or `IP/PREFIX`. The network and broadcast addresses of a
+    /// block are skipped, as they cannot be used as source.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ip, prefix) = match s.split_once('/') {
+            Some((ip, prefix)) => {
+                let prefix: u32 = prefix
+                    .parse()
+                    .map_err(|_| format!("invalid prefix length: {s}"))?;
+                (ip, Some(prefix))
+            }
+            None => (s, None),
+        };
+        let ip: IpAddr = ip
+            .parse()
+            .map_err(|_| format!("invalid bind address: {s}"))?;
+        match (ip, prefix) {
+            (IpAddr::V4(ip), prefix) => {
+                let prefix = prefix.unwrap_or(32);
+                if prefix > 32 {
+                    return Err(format!("invalid prefix length: {s}"));
+                }
+                let mask = u32::MAX.checked_shl(32 - prefix).unwrap_or(0);
+                let network = u32::from(ip) & mask;
+                let size = 1u64 << (32 - prefix);
+                Ok(match size > 2 {
+                    true => AddrRange::V4 {
+                        first: network + 1,
+                        count: (size - 2) as u32,
+                    },
+                    false => AddrRange::V4 {
+                        first: network,
+                        count: size as u32,
+                    },
+                })
+            }
+            (IpAddr::V6(ip), prefix) => {
+                let prefix = prefix.unwrap_or(128);
+                if prefix > 128 {
+                    return Err(format!("invalid prefix length: {s}"));
+                }
+                let mask = u128::MAX.checked_shl(128 - prefix).unwrap_or(0);
+                let network = u128::from(ip) & mask;
+                // Large blocks are capped, there are never that many clients
+                let size = 1u128
+                    .checked_shl(128 - prefix)
+                    .unwrap_or(u128::MAX)
+                    .min(u64::MAX as u128);
+                Ok(match size > 1 {
+                    true => AddrRange::V6 {
+                        first: network + 1,
+                        count: size - 1,
+                    },
+                    false => AddrRange::V6 {
+                        first: network,
+                        count: 1,
+                    },
+                })
+            }
+        }
+    }
+}
+
+impl fmt::Display for AddrRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.len() {
+            1 => write!(f, "{}", self.nth(0)),
+            len => write!(f, "{}-{}", self.nth(0), self.nth(len - 1)),
+        }
+    }
+}
+
+/// Parses a comma separated list of addresses and CIDR blocks.
+pub fn parse_bind_list(s: &str) -> Result<Vec<AddrRange>, String> {
+    s.split(',').map(|item| item.trim().parse()).collect()
+}
+
+/// Source addresses and ports of the client sockets.
+#[derive(Debug, Clone)]
+pub struct SourceOptions {
+    pub bind: BindOptions,
+    pub ports: PortOptions,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct BindOptions {
+    /// Source addresses the clients are distributed across, the wildcard
+    /// address when empty.
+    pub addrs: Vec<AddrRange>,
+    /// Network interface the sockets are bound to (`SO_BINDTODEVICE`).
+    pub interface: Option<String>,
+    pub socket: SocketOptions,
+}
+
+/// Options set on every client socket before it binds, options that do not
+/// apply to the socket type are skipped.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// TCP_NODELAY, disables Nagle's algorithm.
+    pub nodelay: bool,
+    /// SO_SNDBUF in bytes, the kernel may double or clamp it.
+    pub send_buffer: Option<usize>,
+    /// SO_RCVBUF in bytes, the kernel may double or clamp it.
+    pub recv_buffer: Option<usize>,
+    /// IP_TOS, or the IPv6 traffic class, DSCP being the upper six bits.
+    pub tos: Option<u32>,
+    /// IP TTL, or the IPv6 unicast hop limit.
+    pub ttl: Option<u32>,
+    pub keepalive: Option<Keepalive>,
+    /// SO_LINGER timeout, zero resets connections on close.
+    pub linger: Option<Duration>,
+    /// SO_REUSEPORT, lets clients share a source address and port.
+    pub reuse_port: bool,
+}
+
+/// TCP keepalive probing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Keepalive {
+    /// Idle time before the first probe.
+    pub time: Duration,
+    pub interval: Option<Duration>,
+    pub retries: Option<u32>,
+}
+
+impl SocketOptions {
+    fn apply(&self, socket: &Socket, remote: &SocketAddr, stream: bool) -> io::Result<()> {
+        if let Some(size) = self.send_buffer {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer {
+            socket.set_recv_buffer_size(size)?;
+        }
+        if let Some(tos) = self.tos {
+            match remote {
+                SocketAddr::V4(_) => socket.set_tos_v4(tos)?,
+                #[cfg(unix)]
+                SocketAddr::V6(_) => socket.set_tclass_v6(tos)?,
+                #[cfg(not(unix))]
+                SocketAddr::V6(_) => {}
+            }
+        }
+        if let Some(ttl) = self.ttl {
+            match remote {
+                SocketAddr::V4(_) => socket.set_ttl_v4(ttl)?,
+                SocketAddr::V6(_) => socket.set_unicast_hops_v6(ttl)?,
+            }
+        }
+        #[cfg(unix)]
+        if self.reuse_port {
+            socket.set_reuse_address(true)?;
+            socket.set_reuse_port(true)?;
+        }
+        if !stream {
+            return Ok(());
+        }
+
+        if self.nodelay {
+            socket.set_tcp_nodelay(true)?;
+        }
+        if let Some(keepalive) = &self.keepalive {
+            let mut params = TcpKeepalive::new().with_time(keepalive.time);
+            if let Some(interval) = keepalive.interval {
+                params = params.with_interval(interval);
+            }
+            #[cfg(unix)]
+            if let Some(retries) = keepalive.retries {
+                params = params.with_retries(retries);
+            }
+            socket.set_tcp_keepalive(&params)?;
+        }
+        if let Some(linger) = self.linger {
+            socket.set_linger(Some(linger))?;
+        }
+        Ok(())
+    }
+}
+
+impl BindOptions {
+    /// Whether a client can reach a destination of the given family.
+    pub fn supports(&self, remote: &SocketAddr) -> bool {
+        self.addrs.is_empty() || self.addrs.iter().any(|r| r.is_ipv6() == remote.is_ipv6())
+    }
+
+    /// Clients that can share a source port, one per address. Only lists of
+    /// a single family are rotated evenly enough for that.
+    pub fn port_slots(&self) -> usize {
+        let v6 = self.addrs.iter().filter(|r| r.is_ipv6()).count();
+        if self.addrs.is_empty() || (v6 != 0 && v6 != self.addrs.len()) {
+            return 1;
+        }
+        let total = self.addrs.iter().map(AddrRange::len).sum::<u128>();
+        total.min(usize::MAX as u128) as usize
+    }
+
+    /// Source address of `client` for `remote`, clients take turns over
+    /// the addresses of the matching family.
+    fn source_ip(&self, client: usize, remote: &SocketAddr) -> io::Result<IpAddr> {
+        if self.addrs.is_empty() {
+            return Ok(match remote {
+                SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+                SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+            });
+        }
+        let ranges = self
+            .addrs
+            .iter()
+            .filter(|r| r.is_ipv6() == remote.is_ipv6());
+        let total = ranges.clone().map(AddrRange::len).sum::<u128>();
+        if total == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::AddrNotAvailable,
+                format!("no bind address of the same family as {remote}"),
+            ));
+        }
+        let mut n = client as u128 % total;
+        for range in ranges {
+            if n < range.len() {
+                return Ok(range.nth(n));
+            }
+            n -= range.len();
+        }
+        unreachable!()
+    }
+}
+
+/// Where the sockets of one client are bound.
+#[derive(Debug, Clone)]
+pub struct LocalEndpoint {
+    options: Arc<BindOptions>,
+    client: usize,
+    port: usize,
+}
+
+impl LocalEndpoint {
+    pub fn new(options: Arc<BindOptions>, client: usize, port: usize) -> Self {
+        Self {
+            options,
+            client,
+            port,
+        }
+    }
+
+    /// Same source address with another port, 0 for an ephemeral one.
+    pub fn with_port(&self, port: usize) -> Self {
+        Self {
+            port,
+            ..self.clone()
+        }
+    }
+
+    pub fn addr(&self, remote: &SocketAddr) -> io::Result<SocketAddr> {
+        let ip = self.options.source_ip(self.client, remote)?;
+        Ok(SocketAddr::new(ip, self.port as u16))
+    }
+
+    /// Bound, non-blocking UDP socket for `remote`.
+    pub fn udp_socket(&self, remote: &SocketAddr) -> io::Result<std::net::UdpSocket> {
+        let socket = self.socket(remote, Type::DGRAM, Protocol::UDP)?;
+        Ok(socket.into())
+    }
+
+    /// Bound TCP socket for `remote`, ready to connect.
+    pub fn tcp_socket(&self, remote: &SocketAddr) -> io::Result<TcpSocket> {
+        let socket = self.socket(remote, Type::STREAM, Protocol::TCP)?;
+        Ok(TcpSocket::from_std_stream(socket.into()))
+    }
+
+    fn socket(&self, remote: &SocketAddr, ty: Type, protocol: Protocol) -> io::Result<Socket> {
+        let socket = Socket::new(Domain::for_address(*remote), ty, Some(protocol))?;
+        socket.set_nonblocking(true)?;
+        self.options
+            .socket
+            .apply(&socket, remote, ty == Type::STREAM)?;
+        if let Some(interface) = &self.options.interface {
+            bind_device(&socket, interface)?;
+        }
+        socket.bind(&self.addr(remote)?.into())?;
+        Ok(socket)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn bind_device(socket: &Socket, interface: &str) -> io::Result<()> {
+    socket.bind_device(Some(interface.as_bytes()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_device(_socket: &Socket, _interface: &str) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "binding to an interface is only supported on Linux",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(s: &str) -> AddrRange {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn single_addresses() {
+        for s in ["10.0.0.1", "10.0.0.1/32", "::1", "::1/128"] {
+            let range = range(s);
+            assert_eq!(range.len(), 1, "{s}");
+            assert_eq!(range.to_string(), s.split('/').next().unwrap());
+        }
+    }
+
+    #[test]
+    fn ipv4_blocks_skip_network_and_broadcast() {
+        assert_eq!(range("10.0.0.77/24").to_string(), "10.0.0.1-10.0.0.254");
+        assert_eq!(range("10.0.0.4/30").to_string(), "10.0.0.5-10.0.0.6");
+        // Point-to-point links use both addresses
+        assert_eq!(range("10.0.0.4/31").to_string(), "10.0.0.4-10.0.0.5");
+        assert_eq!(range("0.0.0.0/0").len(), (1 << 32) - 2);
+    }
+
+    #[test]
+    fn ipv6_blocks_skip_the_router_anycast_address() {
+        assert_eq!(range("fd00::/127").to_string(), "fd00::1");
+        assert_eq!(range("fd00::/126").to_string(), "fd00::1-fd00::3");
+        assert_eq!(range("fd00::/64").len(), u64::MAX as u128 - 1);
+        assert_eq!(range("::/0").len(), u64::MAX as u128 - 1);
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        for s in [
+            "10.0.0.1/33",
+            "::1/129",
+            "10.0.0.1/",
+            "10.0.0.1/-1",
+            "host/24",
+            "",
+        ] {
+            assert!(s.parse::<AddrRange>().is_err(), "{s}");
+        }
+        assert!(parse_bind_list("10.0.0.1, ::1").is_ok());
+        assert!(parse_bind_list("10.0.0.1,").is_err());
+    }
+
+    #[test]
+    fn clients_rotate_over_the_matching_family() {
+        let options = BindOptions {
+            addrs: parse_bind_list("10.0.0.1,fd00::1,10.0.1.0/30").unwrap(),
+            ..Default::default()
+        };
+        let v4: SocketAddr = "192.0.2.1:80".parse().unwrap();
+        let v6: SocketAddr = "[2001:db8::1]:80".parse().unwrap();
+        let sources: Vec<_> = (0..4)
+            .map(|client| options.source_ip(client, &v4).unwrap().to_string())
+            .collect();
+        assert_eq!(sources, ["10.0.0.1", "10.0.1.1", "10.0.1.2", "10.0.0.1"]);
+        assert_eq!(options.source_ip(5, &v6).unwrap().to_string(), "fd00::1");
+        // Mixed families cannot share source ports
+        assert_eq!(options.port_slots(), 1);
+    }
+
+    #[test]
+    fn missing_family_is_an_error() {
+        let options = BindOptions {
+            addrs: parse_bind_list("10.0.0.0/24").unwrap(),
+            ..Default::default()
+        };
+        let v6: SocketAddr = "[2001:db8::1]:80".parse().unwrap();
+        assert!(!options.supports(&v6));
+        assert!(options.source_ip(0, &v6).is_err());
+        assert_eq!(options.port_slots(), 254);
+        assert_eq!(BindOptions::default().port_slots(), 1);
+    }
+}
@@ -0,0 +1,202 @@
+//! Live control of a running test: rate, connections, payloads, pausing and
+//! stopping.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use log::info;
+use tokio::sync::{mpsc, oneshot, watch};
+
+use crate::{
+    payload::{PayloadConfig, PayloadSource},
+    statistics::{RunReport, SnapshotReceiver},
+};
+
+/// Settings every sender picks up at the start of its next round.
+#[derive(Debug, Clone)]
+pub(crate) struct Settings {
+    rate: usize,
+    paused: bool,
+    payloads: Option<PayloadConfig>,
+    /// Bumped every time the payloads are replaced.
+    payloads_version: u64,
+}
+
+/// Changes to the connections, carried out by the manager task.
+#[derive(Debug)]
+pub(crate) enum Command {
+    Add(usize),
+    Remove(usize),
+    Set(usize),
+}
+
+/// Handle steering a running test, cheap to clone. Every change reaches the
+/// senders at the start of their next one second round.
+#[derive(Clone)]
+pub struct Controller {
+    settings: Arc<watch::Sender<Settings>>,
+    commands: mpsc::UnboundedSender<Command>,
+    quit_tx: mpsc::Sender<()>,
+    snapshots: mpsc::Sender<oneshot::Sender<RunReport>>,
+    connections: Arc<AtomicUsize>,
+}
+
+/// Receiving ends of a [`Controller`], consumed by the manager.
+pub(crate) struct ControlReceiver {
+    pub settings: watch::Receiver<Settings>,
+    pub commands: mpsc::UnboundedReceiver<Command>,
+    pub quit_rx: mpsc::Receiver<()>,
+    pub snapshots: SnapshotReceiver,
+}
+
+impl Controller {
+    pub(crate) fn new(rate: usize) -> (Self, ControlReceiver) {
+        let settings = Settings {
+            rate,
+            paused: false,
+            payloads: None,
+            payloads_version: 0,
+        };
+        let (settings_tx, settings_rx) = watch::channel(settings);
+        let (commands_tx, commands_rx) = mpsc::unbounded_channel();
+        let (quit_tx, quit_rx) = mpsc::channel(1);
+        let (snapshots_tx, snapshots_rx) = mpsc::channel(16);
+        let controller = Self {
+            settings: Arc::new(settings_tx),
+            commands: commands_tx,
+            quit_tx,
+            snapshots: snapshots_tx,
+            connections: Arc::new(AtomicUsize::new(0)),
+        };
+        let receiver = ControlReceiver {
+            settings: settings_rx,
+            commands: commands_rx,
+            quit_rx,
+            snapshots: snapshots_rx,
+        };
+        (controller, receiver)
+    }
+
+    /// Payloads sent per second by each connection.
+    pub fn rate(&self) -> usize {
+        self.settings.borrow().rate
+    }
+
+    pub fn set_rate(&self, rate: usize) {
+        self.settings.send_modify(|settings| settings.rate = rate);
+        info!("Rate set to {rate} pkt/s per connection");
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.settings.borrow().paused
+    }
+
+    /// Stops sending until [`Controller::resume`], connections stay open.
+    pub fn pause(&self) {
+        self.settings.send_modify(|settings| settings.paused = true);
+        info!("Sending paused");
+    }
+
+    pub fn resume(&self) {
+        self.settings
+            .send_modify(|settings| settings.paused = false);
+        info!("Sending resumed");
+    }
+
+    /// Replaces the payloads of every connection, picked in the sequential
+    /// or random order the test was started with.
+    pub fn set_payloads(&self, payloads: PayloadConfig) -> Result<(), String> {
+        if payloads.payloads.is_empty() {
+            return Err("payload set is empty".to_owned());
+        }
+        let count = payloads.payloads.len();
+        self.settings.send_modify(|settings| {
+            settings.payloads = Some(payloads);
+            settings.payloads_version += 1;
+        });
+        info!("Payloads replaced, {count} entries");
+        Ok(())
+    }
+
+    /// Connections currently running.
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    /// Opens `count` more connections, paced like the initial ones. Source
+    /// ports beyond the initially allocated ones are ephemeral.
+    pub fn add_connections(&self, count: usize) {
+        let _ = self.commands.send(Command::Add(count));
+    }
+
+    /// Closes the `count` most recently opened connections.
+    pub fn remove_connections(&self, count: usize) {
+        let _ = self.commands.send(Command::Remove(count));
+    }
+
+    /// Adds or removes connections until `count` of them are running.
+    pub fn set_connections(&self, count: usize) {
+        let _ = self.commands.send(Command::Set(count));
+    }
+
+    /// Totals of the test so far, `None` once it is over.
+    pub async fn report(&self) -> Option<RunReport> {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        self.snapshots.send(reply_tx).await.ok()?;
+        reply_rx.await.ok()
+    }
+
+    /// Ends the test, the final statistics are still reported.
+    pub fn stop(&self) {
+        let _ = self.quit_tx.try_send(());
+    }
+
+    pub(crate) fn quit_tx(&self) -> mpsc::Sender<()> {
+        self.quit_tx.clone()
+    }
+
+    pub(crate) fn update_connections(&self, connections: usize) {
+        self.connections.store(connections, Ordering::Relaxed);
+    }
+}
+
+/// A sender's view of the live settings.
+#[derive(Clone)]
+pub(crate) struct ClientControl {
+    settings: watch::Receiver<Settings>,
+    payloads_version: u64,
+}
+
+impl ClientControl {
+    pub fn new(settings: watch::Receiver<Settings>) -> Self {
+        Self {
+            settings,
+            payloads_version: 0,
+        }
+    }
+
+    /// Payloads to send this round, waiting while the test is paused.
+    pub async fn round(&mut self, payloads: &mut PayloadSource) -> usize {
+        // Fails only once the test is over, the last settings still apply
+        let _ = self.settings.wait_for(|settings| !settings.paused).await;
+        self.poll_round(payloads)
+    }
+
+    /// Non-blocking [`ClientControl::round`], 0 while paused. Replaced
+    /// payloads are swapped into `payloads` first.
+    pub fn poll_round(&mut self, payloads: &mut PayloadSource) -> usize {
+        let settings = self.settings.borrow_and_update();
+        if settings.payloads_version != self.payloads_version {
+            self.payloads_version = settings.payloads_version;
+            if let Some(config) = &settings.payloads {
+                payloads.set_config(config.clone());
+            }
+        }
+        match settings.paused {
+            true => 0,
+            false => settings.rate,
+        }
+    }
+}
@@ -0,0 +1,248 @@
+//! HTTP/JSON API driving a running test through its [`Controller`], so that
+//! orchestration scripts can adjust long-running instances.
+//!
+//! | Request                  | Body                     |
+//! |--------------------------|--------------------------|
+//! | `GET /stats`             |                          |
+//! | `POST /rate`             | `{"rate": 500}`          |
+//! | `POST /connections`      | `{"connections": 100}`   |
+//! | `POST /pause`            |                          |
+//! | `POST /resume`           |                          |
+//! | `POST /stop`             |                          |
+
+use std::{net::SocketAddr, time::Duration};
+
+use http::StatusCode;
+use log::{debug, error, info};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    spawn,
+};
+
+use crate::{control::Controller, Histogram, RunReport};
+
+/// Upper bound of the request head and body.
+const MAX_REQUEST: usize = 16384;
+
+struct Request {
+    method: String,
+    path: String,
+    body: Vec<u8>,
+}
+
+/// Serves the API until the task is dropped.
+pub async fn serve(listen: SocketAddr, controller: Controller) {
+    let listener = match TcpListener::bind(listen).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Unable to start the control API on {listen}: {err}");
+            return;
+        }
+    };
+    info!("Control API listening on {listen}");
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                spawn(handle_connection(stream, peer, controller.clone()));
+            }
+            Err(err) => debug!("Failed to accept control connection: {err}"),
+        }
+    }
+}
+
+/// Answers a single request, then closes the connection.
+async fn handle_connection(mut stream: TcpStream, peer: SocketAddr, controller: Controller) {
+    let (status, body) = match read_request(&mut stream).await {
+        Ok(request) => {
+            debug!(
+                "Control request from {peer}: {} {}",
+                request.method, request.path
+            );
+            handle_request(request, &controller).await
+        }
+        Err(err) => (StatusCode::BAD_REQUEST, json!({ "error": err })),
+    };
+    let body = body.to_string();
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let _ = stream.write_all(response.as_bytes()).await;
+    let _ = stream.shutdown().await;
+}
+
+async fn read_request(stream: &mut TcpStream) -> Result<Request, String> {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+    let (head_len, body_len, method, path) = loop {
+        let read = stream
+            .read(&mut chunk)
+            .await
+            .map_err(|err| err.to_string())?;
+        if read == 0 {
+            return Err("connection closed before the end of the request".to_owned());
+        }
+        buf.extend_from_slice(&chunk[..read]);
+
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut request = httparse::Request::new(&mut headers);
+        match request.parse(&buf).map_err(|err| err.to_string())? {
+            httparse::Status::Complete(head_len) => {
+                let body_len = match request
+                    .headers
+                    .iter()
+                    .find(|header| header.name.eq_ignore_ascii_case("content-length"))
+                {
+                    Some(header) => std::str::from_utf8(header.value)
+                        .ok()
+                        .and_then(|value| value.trim().parse().ok())
+                        .ok_or("invalid Content-Length")?,
+                    None => 0,
+                };
+                let method = request.method.unwrap_or_default().to_owned();
+                let path = request.path.unwrap_or_default().to_owned();
+                break (head_len, body_len, method, path);
+            }
+            httparse::Status::Partial if buf.len() > MAX_REQUEST => {
+                return Err("request too large".to_owned())
+            }
+            httparse::Status::Partial => {}
+        }
+    };
+    if body_len > MAX_REQUEST {
+        return Err("request too large".to_owned());
+    }
+    while buf.len() < head_len + body_len {
+        let read = stream
+            .read(&mut chunk)
+            .await
+            .map_err(|err| err.to_string())?;
+        if read == 0 {
+            return Err("connection closed before the end of the request".to_owned());
+        }
+        buf.extend_from_slice(&chunk[..read]);
+    }
+    Ok(Request {
+        method,
+        path,
+        body: buf[head_len..head_len + body_len].to_vec(),
+    })
+}
+
+async fn handle_request(request: Request, controller: &Controller) -> (StatusCode, Value) {
+    match (request.path.as_str(), request.method.as_str()) {
+        ("/stats", "GET") => match controller.report().await {
+            Some(report) => (StatusCode::OK, stats(&report, controller)),
+            None => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                json!({ "error": "the test is over" }),
+            ),
+        },
+        ("/rate", "POST") => match number(&request.body, "rate") {
+            Ok(rate) => {
+                controller.set_rate(rate);
+                (StatusCode::OK, state(controller))
+            }
+            Err(err) => (StatusCode::BAD_REQUEST, json!({ "error": err })),
+        },
+        ("/connections", "POST") => match number(&request.body, "connections") {
+            Ok(connections) => {
+                controller.set_connections(connections);
+                (StatusCode::OK, state(controller))
+            }
+            Err(err) => (StatusCode::BAD_REQUEST, json!({ "error": err })),
+        },
+        ("/pause", "POST") => {
+            controller.pause();
+            (StatusCode::OK, state(controller))
+        }
+        ("/resume", "POST") => {
+            controller.resume();
+            (StatusCode::OK, state(controller))
+        }
+        ("/stop", "POST") => {
+            controller.stop();
+            (StatusCode::OK, state(controller))
+        }
+        ("/stats" | "/rate" | "/connections" | "/pause" | "/resume" | "/stop", method) => (
+            StatusCode::METHOD_NOT_ALLOWED,
+            json!({ "error": format!("{method} not allowed on {}", request.path) }),
+        ),
+        _ => (
+            StatusCode::NOT_FOUND,
+            json!({ "error": format!("no endpoint {}", request.path) }),
+        ),
+    }
+}
+
+/// Reads the non-negative integer `field` of a JSON object.
+fn number(body: &[u8], field: &str) -> Result<usize, String> {
+    serde_json::from_slice::<Value>(body)
+        .ok()
+        .and_then(|body| body.get(field).and_then(Value::as_u64))
+        .map(|value| value as usize)
+        .ok_or_else(|| format!("expected a JSON body like {{\"{field}\": 100}}"))
+}
+
+fn state(controller: &Controller) -> Value {
+    json!({
+        "rate": controller.rate(),
+        "paused": controller.is_paused(),
+        "connections": controller.connections(),
+    })
+}
+
+fn stats(report: &RunReport, controller: &Controller) -> Value {
+    let targets: serde_json::Map<_, _> = report
+        .targets
+        .iter()
+        .map(|(target, report)| {
+            let target_stats = json!({
+                "clients": report.clients,
+                "packets_sent": report.packets_sent,
+                "bytes_sent": report.bytes_sent,
+            });
+            (target.clone(), target_stats)
+        })
+        .collect();
+    let status_codes: serde_json::Map<_, _> = report
+        .status_codes
+        .iter()
+        .map(|(code, count)| (code.to_string(), json!(count)))
+        .collect();
+    json!({
+        "rate": controller.rate(),
+        "paused": controller.is_paused(),
+        "connections": controller.connections(),
+        "elapsed_secs": report.elapsed.as_secs_f64(),
+        "packets_sent": report.packets_sent,
+        "bytes_sent": report.bytes_sent,
+        "packets_received": report.packets_received,
+        "bytes_received": report.bytes_received,
+        "errors": report.errors,
+        "reconnects": report.reconnects,
+        "handshakes": histogram(&report.handshakes),
+        "latency": histogram(&report.latency),
+        "status_codes": status_codes,
+        "streams_opened": report.streams_opened,
+        "max_gap_ms": millis(report.max_gap),
+        "targets": targets,
+    })
+}
+
+fn histogram(histogram: &Histogram) -> Value {
+    json!({
+        "count": histogram.count(),
+        "mean_ms": millis(histogram.mean()),
+        "p50_ms": millis(histogram.percentile(0.5)),
+        "p90_ms": millis(histogram.percentile(0.9)),
+        "p99_ms": millis(histogram.percentile(0.99)),
+        "max_ms": millis(histogram.max()),
+    })
+}
+
+fn millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.
+}
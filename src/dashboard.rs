@@ -0,0 +1,389 @@
+//! Interactive terminal dashboard of a running test, fed with the samples
+//! of the statistics task and steering the test through its [`Controller`].
+
+use std::{
+    collections::VecDeque,
+    io::{stdout, IsTerminal},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use log::{warn, LevelFilter};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout, Rect},
+    style::{Color, Modifier, Style},
+    text::Line,
+    widgets::{Block, Paragraph, Row, Sparkline, Table, TableState},
+    Frame,
+};
+use tokio::{
+    sync::{
+        mpsc::{self, error::TryRecvError},
+        oneshot,
+    },
+    task::{spawn_blocking, JoinHandle},
+};
+
+use crate::{
+    control::Controller,
+    statistics::{bit_rate, ConnectionSample, ConnectionState, Histogram, Sample},
+};
+
+/// Seconds of throughput and errors kept for the sparklines.
+const HISTORY: usize = 300;
+
+/// Redraw and key polling period.
+const TICK: Duration = Duration::from_millis(100);
+
+/// The terminal shows a single dashboard at a time.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Handle to the dashboard, which owns the terminal and silences the log
+/// until closed.
+pub(crate) struct Dashboard {
+    samples: mpsc::Sender<Sample>,
+    close_tx: oneshot::Sender<()>,
+    handle: JoinHandle<()>,
+    log_level: LevelFilter,
+}
+
+impl Dashboard {
+    /// Takes over the terminal, `None` when it is not interactive or another
+    /// test already shows its dashboard.
+    pub fn start(controller: Controller, label: Option<String>) -> Option<Self> {
+        if !stdout().is_terminal() {
+            warn!("Standard output is not a terminal, ignoring --tui");
+            return None;
+        }
+        if ACTIVE.swap(true, Ordering::SeqCst) {
+            warn!("Another test already shows the dashboard, ignoring --tui");
+            return None;
+        }
+        let (samples, samples_rx) = mpsc::channel(16);
+        let (close_tx, close_rx) = oneshot::channel();
+        let log_level = log::max_level();
+        // The log lines would scroll the dashboard away
+        log::set_max_level(LevelFilter::Off);
+        let app = App::new(controller, label);
+        let handle = spawn_blocking(move || app.run(samples_rx, close_rx));
+        Some(Self {
+            samples,
+            close_tx,
+            handle,
+            log_level,
+        })
+    }
+
+    pub fn samples(&self) -> mpsc::Sender<Sample> {
+        self.samples.clone()
+    }
+
+    /// Gives the terminal and the log back.
+    pub async fn close(self) {
+        let _ = self.close_tx.send(());
+        let _ = self.handle.await;
+        log::set_max_level(self.log_level);
+        ACTIVE.store(false, Ordering::SeqCst);
+    }
+}
+
+struct App {
+    controller: Controller,
+    label: Option<String>,
+    throughput: VecDeque<u64>,
+    errors: VecDeque<u64>,
+    latest: Option<Sample>,
+    connections: TableState,
+    /// Outcome of the last key pressed.
+    status: String,
+}
+
+impl App {
+    fn new(controller: Controller, label: Option<String>) -> Self {
+        Self {
+            controller,
+            label,
+            throughput: VecDeque::with_capacity(HISTORY),
+            errors: VecDeque::with_capacity(HISTORY),
+            latest: None,
+            connections: TableState::default(),
+            status: String::new(),
+        }
+    }
+
+    /// Draws until the test is over or `close_rx` fires.
+    fn run(mut self, mut samples: mpsc::Receiver<Sample>, mut close_rx: oneshot::Receiver<()>) {
+        let mut terminal = ratatui::init();
+        'draw: while let Err(oneshot::error::TryRecvError::Empty) = close_rx.try_recv() {
+            loop {
+                match samples.try_recv() {
+                    Ok(sample) => self.push(sample),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => break 'draw,
+                }
+            }
+            if terminal.draw(|frame| self.draw(frame)).is_err() {
+                break;
+            }
+            if event::poll(TICK).unwrap_or(false) {
+                if let Ok(Event::Key(key)) = event::read() {
+                    if key.kind == KeyEventKind::Press {
+                        self.handle_key(key.code, key.modifiers);
+                    }
+                }
+            }
+        }
+        ratatui::restore();
+    }
+
+    fn push(&mut self, sample: Sample) {
+        for (history, value) in [
+            (&mut self.throughput, sample.packets_sent),
+            (&mut self.errors, sample.errors),
+        ] {
+            if history.len() == HISTORY {
+                history.pop_front();
+            }
+            history.push_back(value as u64);
+        }
+        self.latest = Some(sample);
+    }
+
+    fn handle_key(&mut self, code: KeyCode, modifiers: KeyModifiers) {
+        let rate = self.controller.rate();
+        let step = (rate / 10).max(1);
+        match code {
+            KeyCode::Char('+') | KeyCode::Char('=') | KeyCode::Right => {
+                self.controller.set_rate(rate + step);
+                self.status = format!("Rate set to {} pkt/s per connection", rate + step);
+            }
+            KeyCode::Char('-') | KeyCode::Left => {
+                let rate = rate.saturating_sub(step).max(1);
+                self.controller.set_rate(rate);
+                self.status = format!("Rate set to {rate} pkt/s per connection");
+            }
+            KeyCode::Char('p') | KeyCode::Char(' ') => match self.controller.is_paused() {
+                true => {
+                    self.controller.resume();
+                    self.status = "Sending resumed".to_owned();
+                }
+                false => {
+                    self.controller.pause();
+                    self.status = "Sending paused".to_owned();
+                }
+            },
+            KeyCode::Down => self.connections.select_next(),
+            KeyCode::Up => self.connections.select_previous(),
+            KeyCode::Char('q') | KeyCode::Esc => self.stop(),
+            // Raw mode swallows the signal
+            KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL) => self.stop(),
+            _ => {}
+        }
+    }
+
+    fn stop(&mut self) {
+        self.controller.stop();
+        self.status = "Stopping...".to_owned();
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [header, throughput, errors, latency, connections, footer] = Layout::vertical([
+            Constraint::Length(3),
+            Constraint::Length(6),
+            Constraint::Length(5),
+            Constraint::Length(6),
+            Constraint::Min(5),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+
+        self.draw_header(frame, header);
+        self.draw_sparklines(frame, throughput, errors);
+        self.draw_latency(frame, latency);
+        self.draw_connections(frame, connections);
+
+        let keys = "+/- rate  p pause/resume  ↑/↓ scroll  q stop";
+        let footer_text = match self.status.is_empty() {
+            true => keys.to_owned(),
+            false => format!("{keys}  |  {}", self.status),
+        };
+        frame.render_widget(
+            Paragraph::new(footer_text).style(Style::default().fg(Color::DarkGray)),
+            footer,
+        );
+    }
+
+    fn draw_header(&self, frame: &mut Frame, area: Rect) {
+        let title = match &self.label {
+            Some(label) => format!(" crab-net [{label}] "),
+            None => " crab-net ".to_owned(),
+        };
+        let state = match self.controller.is_paused() {
+            true => "PAUSED",
+            false => "running",
+        };
+        let mut text = format!(
+            "{state} --- Rate {} pkt/s x {} connections",
+            self.controller.rate(),
+            self.controller.connections()
+        );
+        if let Some(sample) = &self.latest {
+            let totals = &sample.totals;
+            let elapsed = totals.elapsed.as_secs();
+            text += &format!(
+                " --- Elapsed {:02}:{:02}:{:02} --- Sent {} packets --- Received {} packets --- Errors {}",
+                elapsed / 3600,
+                elapsed / 60 % 60,
+                elapsed % 60,
+                totals.packets_sent,
+                totals.packets_received,
+                totals.errors
+            );
+        }
+        frame.render_widget(
+            Paragraph::new(text).block(Block::bordered().title(title)),
+            area,
+        );
+    }
+
+    fn draw_sparklines(&self, frame: &mut Frame, throughput: Rect, errors: Rect) {
+        let (sent, bandwidth, received, failed) = match &self.latest {
+            Some(sample) => (
+                sample.packets_sent,
+                bit_rate(sample.bytes_sent as f64, 1.),
+                sample.packets_received,
+                sample.errors,
+            ),
+            None => (0, bit_rate(0., 1.), 0, 0),
+        };
+        let mut title = format!(" Throughput: {sent} pkt/s --- {bandwidth}bit/s ");
+        if received > 0 {
+            title += &format!("--- Received {received} pkt/s ");
+        }
+        frame.render_widget(
+            sparkline(&self.throughput, throughput, title, Color::Green),
+            throughput,
+        );
+        let title = format!(" Errors: {failed}/s ");
+        frame.render_widget(sparkline(&self.errors, errors, title, Color::Red), errors);
+    }
+
+    fn draw_latency(&self, frame: &mut Frame, area: Rect) {
+        let [latency, statuses] =
+            Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)])
+                .areas(area);
+
+        let header = Row::new(["", "count", "p50", "p90", "p99", "max"])
+            .style(Style::default().add_modifier(Modifier::BOLD));
+        let rows = match &self.latest {
+            Some(sample) => vec![
+                latency_row("last second", &sample.latency),
+                latency_row("overall", &sample.totals.latency),
+                latency_row("handshakes", &sample.totals.handshakes),
+            ],
+            None => Vec::new(),
+        };
+        let widths = [
+            Constraint::Length(12),
+            Constraint::Length(10),
+            Constraint::Length(10),
+            Constraint::Length(10),
+            Constraint::Length(10),
+            Constraint::Length(10),
+        ];
+        frame.render_widget(
+            Table::new(rows, widths)
+                .header(header)
+                .block(Block::bordered().title(" Latency (ms) ")),
+            latency,
+        );
+
+        let codes: Vec<Line> = self
+            .latest
+            .iter()
+            .flat_map(|sample| &sample.totals.status_codes)
+            .map(|(code, count)| Line::from(format!("{code}: {count}")))
+            .collect();
+        frame.render_widget(
+            Paragraph::new(codes).block(Block::bordered().title(" Status codes ")),
+            statuses,
+        );
+    }
+
+    fn draw_connections(&mut self, frame: &mut Frame, area: Rect) {
+        let connections: &[ConnectionSample] = self
+            .latest
+            .as_ref()
+            .map_or(&[], |sample| &sample.connections);
+        let header = Row::new([
+            "id",
+            "state",
+            "pkt/s",
+            "packets",
+            "bytes",
+            "errors",
+            "reconnects",
+        ])
+        .style(Style::default().add_modifier(Modifier::BOLD));
+        let rows = connections.iter().map(|connection| {
+            let (state, color) = match connection.state {
+                ConnectionState::Active => ("active", Color::Green),
+                ConnectionState::Idle => ("idle", Color::Yellow),
+                ConnectionState::Failing => ("failing", Color::Red),
+                ConnectionState::Closed => ("closed", Color::DarkGray),
+            };
+            Row::new([
+                connection.id.to_string(),
+                state.to_owned(),
+                connection.rate.to_string(),
+                connection.packets_sent.to_string(),
+                connection.bytes_sent.to_string(),
+                connection.errors.to_string(),
+                connection.reconnects.to_string(),
+            ])
+            .style(Style::default().fg(color))
+        });
+        let widths = [
+            Constraint::Length(8),
+            Constraint::Length(10),
+            Constraint::Length(10),
+            Constraint::Length(14),
+            Constraint::Length(16),
+            Constraint::Length(10),
+            Constraint::Length(10),
+        ];
+        let table = Table::new(rows, widths)
+            .header(header)
+            .row_highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+            .block(Block::bordered().title(format!(" Connections ({}) ", connections.len())));
+        frame.render_stateful_widget(table, area, &mut self.connections);
+    }
+}
+
+/// The most recent values fitting in `area`.
+fn sparkline<'a>(
+    history: &VecDeque<u64>,
+    area: Rect,
+    title: String,
+    color: Color,
+) -> Sparkline<'a> {
+    let width = area.width.saturating_sub(2) as usize;
+    let skip = history.len().saturating_sub(width);
+    Sparkline::default()
+        .block(Block::bordered().title(title))
+        .data(history.iter().skip(skip))
+        .style(Style::default().fg(color))
+}
+
+fn latency_row(name: &str, histogram: &Histogram) -> Row<'static> {
+    let ms = |duration: Duration| format!("{:.2}", duration.as_secs_f64() * 1000.);
+    Row::new([
+        name.to_owned(),
+        histogram.count().to_string(),
+        ms(histogram.percentile(0.5)),
+        ms(histogram.percentile(0.9)),
+        ms(histogram.percentile(0.99)),
+        ms(histogram.max()),
+    ])
+}
@@ -0,0 +1,426 @@
+//! Distributed runs: a coordinator pushes a scenario to agents on other
+//! hosts, starts them together and merges their statistics into one report.
+//!
+//! Coordinator and agents exchange newline-delimited JSON [`Message`]s over
+//! TCP. Every agent runs the whole scenario.
+
+use std::{net::SocketAddr, time::Duration};
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    pin, select, spawn,
+    sync::mpsc,
+    task::JoinSet,
+    time::{interval, interval_at, timeout, Instant},
+};
+
+use crate::{shutdown_signal, statistics::bit_rate, Controller, LoadTest, Parameters, RunReport};
+
+/// How often the agents send their totals to the coordinator.
+const AGENT_STATS_PERIOD: Duration = Duration::from_secs(1);
+/// How often the coordinator prints the merged statistics.
+const REPORT_PERIOD: Duration = Duration::from_secs(10);
+/// Upper bound of the connection to an agent.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Upper bound of an agent planning the scenario, e.g. resolving its
+/// destinations.
+const READY_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    /// Scenario to run as YAML, with the position of the agent among all of
+    /// them.
+    Scenario {
+        scenario: String,
+        agent: usize,
+        agents: usize,
+    },
+    /// The agent has planned the run and waits for [`Message::Start`].
+    Ready,
+    Start,
+    /// Ends the run early, the agent still sends its final totals.
+    Stop,
+    /// Totals of the agent so far.
+    Stats {
+        report: RunReport,
+    },
+    Finished {
+        report: RunReport,
+    },
+    Error {
+        message: String,
+    },
+}
+
+struct MessageReader(Lines<BufReader<OwnedReadHalf>>);
+
+impl MessageReader {
+    /// Next message, `None` once the peer has closed the connection.
+    async fn recv(&mut self) -> Result<Option<Message>, String> {
+        match self.0.next_line().await.map_err(|err| err.to_string())? {
+            Some(line) => serde_json::from_str(&line)
+                .map(Some)
+                .map_err(|err| format!("invalid message: {err}")),
+            None => Ok(None),
+        }
+    }
+}
+
+struct MessageWriter(OwnedWriteHalf);
+
+impl MessageWriter {
+    async fn send(&mut self, message: &Message) -> Result<(), String> {
+        let mut line = serde_json::to_vec(message).unwrap();
+        line.push(b'\n');
+        self.0.write_all(&line).await.map_err(|err| err.to_string())
+    }
+}
+
+fn split(stream: TcpStream) -> (MessageReader, MessageWriter) {
+    let (read, write) = stream.into_split();
+    (
+        MessageReader(BufReader::new(read).lines()),
+        MessageWriter(write),
+    )
+}
+
+/// Runs the scenarios pushed by coordinators, one at a time. `plan` turns
+/// a scenario into phases of client groups.
+pub async fn agent<F>(listen: SocketAddr, plan: F)
+where
+    F: Fn(&str) -> Result<Vec<Vec<Parameters>>, String>,
+{
+    let listener = match TcpListener::bind(listen).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("Unable to listen on {listen}: {err}");
+            return;
+        }
+    };
+    info!("Agent listening on {listen}");
+    serve_coordinators(listener, plan).await
+}
+
+/// Like [`agent`], on a listener already bound.
+pub async fn serve_coordinators<F>(listener: TcpListener, plan: F)
+where
+    F: Fn(&str) -> Result<Vec<Vec<Parameters>>, String>,
+{
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!("Failed to accept coordinator: {err}");
+                continue;
+            }
+        };
+        info!("Coordinator {peer} connected");
+        let (mut reader, mut writer) = split(stream);
+        match agent_session(&mut reader, &mut writer, &plan).await {
+            Ok(()) => info!("Session with coordinator {peer} over"),
+            Err(err) => {
+                error!("Session with coordinator {peer} failed: {err}");
+                let _ = writer.send(&Message::Error { message: err }).await;
+            }
+        }
+    }
+}
+
+async fn agent_session<F>(
+    reader: &mut MessageReader,
+    writer: &mut MessageWriter,
+    plan: &F,
+) -> Result<(), String>
+where
+    F: Fn(&str) -> Result<Vec<Vec<Parameters>>, String>,
+{
+    let phases = match reader.recv().await? {
+        Some(Message::Scenario {
+            scenario,
+            agent,
+            agents,
+        }) => {
+            info!("Received scenario, running as agent {}/{agents}", agent + 1);
+            plan(&scenario)?
+        }
+        Some(message) => return Err(format!("expected a scenario, got {message:?}")),
+        None => return Err("connection closed before the scenario".to_owned()),
+    };
+    writer.send(&Message::Ready).await?;
+    match reader.recv().await? {
+        Some(Message::Start) => info!("Starting the run"),
+        Some(Message::Stop) | None => {
+            info!("Run cancelled by the coordinator");
+            return Ok(());
+        }
+        Some(message) => return Err(format!("expected start, got {message:?}")),
+    }
+
+    let started = Instant::now();
+    let count = phases.len();
+    // Totals of the phases already over
+    let mut totals = RunReport::default();
+    let mut ticker = interval(AGENT_STATS_PERIOD);
+    let mut connected = true;
+    let mut stopped = false;
+    for (idx, phase) in phases.into_iter().enumerate() {
+        if stopped {
+            info!("Skipping the remaining phases");
+            break;
+        }
+        if count > 1 {
+            info!("Starting phase {}/{count}", idx + 1);
+        }
+        let mut controllers = Vec::new();
+        let mut groups = JoinSet::new();
+        for params in phase {
+            let handle = LoadTest::from(params).start();
+            controllers.push(handle.controller());
+            groups.spawn(handle.wait());
+        }
+
+        let mut phase_totals = RunReport::default();
+        while !groups.is_empty() {
+            select! {
+                Some(result) = groups.join_next() => match result.unwrap() {
+                    Ok(report) => phase_totals.merge(&report),
+                    Err(err) => error!("{err}"),
+                },
+                _ = ticker.tick(), if connected => {
+                    let mut report = totals.clone();
+                    report.merge(&phase_totals);
+                    for controller in &controllers {
+                        if let Some(current) = controller.report().await {
+                            report.merge(&current);
+                        }
+                    }
+                    report.elapsed = started.elapsed();
+                    if writer.send(&Message::Stats { report }).await.is_err() {
+                        warn!("Lost the coordinator, stopping the run");
+                        connected = false;
+                        stopped = true;
+                        controllers.iter().for_each(Controller::stop);
+                    }
+                }
+                message = reader.recv(), if connected => match message {
+                    Ok(Some(Message::Stop)) => {
+                        info!("Stopping the run on coordinator request");
+                        stopped = true;
+                        controllers.iter().for_each(Controller::stop);
+                    }
+                    Ok(Some(message)) => warn!("Unexpected message from the coordinator: {message:?}"),
+                    Ok(None) | Err(_) => {
+                        warn!("Lost the coordinator, stopping the run");
+                        connected = false;
+                        stopped = true;
+                        controllers.iter().for_each(Controller::stop);
+                    }
+                },
+            }
+        }
+        totals.merge(&phase_totals);
+    }
+
+    totals.elapsed = started.elapsed();
+    info!("Run finished, {} packets sent", totals.packets_sent);
+    writer.send(&Message::Finished { report: totals }).await
+}
+
+/// Pushes each scenario to its agent, starts all the agents together and
+/// returns their merged totals. Ctrl+C stops the agents early.
+pub async fn coordinate(agents: Vec<(SocketAddr, String)>) -> Result<RunReport, String> {
+    let count = agents.len();
+    let mut readers = Vec::new();
+    let mut writers = Vec::new();
+    for (idx, (addr, scenario)) in agents.iter().enumerate() {
+        let stream = timeout(CONNECT_TIMEOUT, TcpStream::connect(addr))
+            .await
+            .map_err(|_| "timed out".to_owned())
+            .and_then(|connected| connected.map_err(|err| err.to_string()))
+            .map_err(|err| format!("cannot reach agent {addr}: {err}"))?;
+        let (reader, mut writer) = split(stream);
+        let scenario = Message::Scenario {
+            scenario: scenario.clone(),
+            agent: idx,
+            agents: count,
+        };
+        writer
+            .send(&scenario)
+            .await
+            .map_err(|err| format!("agent {addr}: {err}"))?;
+        readers.push(reader);
+        writers.push(writer);
+    }
+    // Dropping the connections on error cancels the agents already set up
+    for ((addr, _), reader) in agents.iter().zip(&mut readers) {
+        let ready = timeout(READY_TIMEOUT, reader.recv())
+            .await
+            .map_err(|_| format!("agent {addr} not ready after {READY_TIMEOUT:?}"))?;
+        match ready.map_err(|err| format!("agent {addr}: {err}"))? {
+            Some(Message::Ready) => info!("Agent {addr} ready"),
+            Some(Message::Error { message }) => return Err(format!("agent {addr}: {message}")),
+            Some(message) => return Err(format!("agent {addr}: unexpected {message:?}")),
+            None => return Err(format!("agent {addr} closed the connection")),
+        }
+    }
+    for ((addr, _), writer) in agents.iter().zip(&mut writers) {
+        writer
+            .send(&Message::Start)
+            .await
+            .map_err(|err| format!("agent {addr}: {err}"))?;
+    }
+    info!("Started {count} agents");
+
+    let (messages_tx, mut messages_rx) = mpsc::unbounded_channel();
+    for (idx, mut reader) in readers.into_iter().enumerate() {
+        let messages_tx = messages_tx.clone();
+        spawn(async move {
+            loop {
+                let message = reader.recv().await;
+                let last = !matches!(message, Ok(Some(Message::Stats { .. })));
+                if messages_tx.send((idx, message)).is_err() || last {
+                    break;
+                }
+            }
+        });
+    }
+
+    let mut latest = vec![RunReport::default(); count];
+    let mut finished = vec![false; count];
+    let mut previous = RunReport::default();
+    let mut last_report = Instant::now();
+    let mut timer = interval_at(Instant::now() + REPORT_PERIOD, REPORT_PERIOD);
+    let shutdown = shutdown_signal();
+    pin!(shutdown);
+    let mut interrupted = false;
+    while finished.contains(&false) {
+        select! {
+            Some((idx, message)) = messages_rx.recv() => {
+                let addr = agents[idx].0;
+                // Only statistics keep the agent reader going
+                finished[idx] = !matches!(message, Ok(Some(Message::Stats { .. })));
+                match message {
+                    Ok(Some(Message::Stats { report })) => latest[idx] = report,
+                    Ok(Some(Message::Finished { report })) => {
+                        info!("Agent {addr} finished, {} packets sent", report.packets_sent);
+                        latest[idx] = report;
+                    }
+                    Ok(Some(Message::Error { message })) => error!("Agent {addr} failed: {message}"),
+                    Ok(Some(message)) => warn!("Agent {addr} sent an unexpected {message:?}"),
+                    Ok(None) => warn!("Lost agent {addr}, keeping its last statistics"),
+                    Err(err) => warn!("Lost agent {addr} ({err}), keeping its last statistics"),
+                }
+            }
+            _ = timer.tick() => {
+                let merged = merge_reports(&latest);
+                let running = finished.iter().filter(|done| !**done).count();
+                log_interval(&merged, &previous, last_report.elapsed(), running, count);
+                previous = merged;
+                last_report = Instant::now();
+            }
+            _ = &mut shutdown, if !interrupted => {
+                interrupted = true;
+                for writer in &mut writers {
+                    let _ = writer.send(&Message::Stop).await;
+                }
+            }
+        }
+    }
+
+    let merged = merge_reports(&latest);
+    log_final(&merged, count);
+    Ok(merged)
+}
+
+fn merge_reports(reports: &[RunReport]) -> RunReport {
+    let mut merged = RunReport::default();
+    for report in reports {
+        merged.merge(report);
+    }
+    merged
+}
+
+/// Traffic since the previous report, like the statistics of a single run.
+fn log_interval(
+    merged: &RunReport,
+    previous: &RunReport,
+    elapsed: Duration,
+    running: usize,
+    count: usize,
+) {
+    let seconds = elapsed.as_secs_f64();
+    let packets = merged.packets_sent.saturating_sub(previous.packets_sent);
+    let bytes = merged.bytes_sent.saturating_sub(previous.bytes_sent);
+    let mut report = format!(
+        "[{running}/{count} agents] Sent {packets} packets --- Bandwidth {}bit/s --- Total packets: {}",
+        bit_rate(bytes as f64, seconds),
+        merged.packets_sent
+    );
+    if merged.packets_received > 0 {
+        let packets = merged
+            .packets_received
+            .saturating_sub(previous.packets_received);
+        let bytes = merged
+            .bytes_received
+            .saturating_sub(previous.bytes_received);
+        report += &format!(
+            " --- Received {packets} packets --- Bandwidth {}bit/s --- Total received: {}",
+            bit_rate(bytes as f64, seconds),
+            merged.packets_received
+        );
+    }
+    if merged.latency.count() > 0 {
+        report += &format!(" --- Responses so far: {}", merged.latency);
+    }
+    info!("{report}");
+}
+
+fn log_final(merged: &RunReport, count: usize) {
+    info!(
+        "Final statistics of {count} agents after {:.1?}: {} packets ({} bytes) sent, {} packets ({} bytes) received",
+        merged.elapsed,
+        merged.packets_sent,
+        merged.bytes_sent,
+        merged.packets_received,
+        merged.bytes_received
+    );
+    if merged.handshakes.count() > 0 {
+        info!("Handshakes: {}", merged.handshakes);
+    }
+    if merged.streams_opened > 0 {
+        info!("Streams opened: {}", merged.streams_opened);
+    }
+    if merged.latency.count() > 0 {
+        info!("Responses: {}", merged.latency);
+    }
+    if merged.errors > 0 {
+        info!("Errors: {}", merged.errors);
+    }
+    if merged.reconnects > 0 {
+        info!("Reconnects: {}", merged.reconnects);
+    }
+    if !merged.status_codes.is_empty() {
+        let codes: Vec<_> = merged
+            .status_codes
+            .iter()
+            .map(|(code, count)| format!("{code}: {count}"))
+            .collect();
+        info!("Status codes: {}", codes.join(", "));
+    }
+    for (target, report) in &merged.targets {
+        info!(
+            "{target} ({} clients): {} packets sent",
+            report.clients, report.packets_sent
+        );
+    }
+    for (peer, stats) in &merged.sequence {
+        info!("Sequence statistics for {peer}: {stats}");
+    }
+}
@@ -0,0 +1,161 @@
+//! Hostname destinations resolved to all their A/AAAA records, optionally
+//! re-resolved in the background while the test runs.
+
+use std::{
+    collections::HashMap,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::Duration,
+};
+
+use log::{info, warn};
+use tokio::{
+    net::lookup_host,
+    spawn,
+    task::JoinHandle,
+    time::{interval_at, Instant},
+};
+
+use crate::bind::BindOptions;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DnsOptions {
+    /// Rotate clients across every resolved address instead of the first one.
+    pub spread: bool,
+    /// Resolve the hostnames again at this interval.
+    pub refresh: Option<Duration>,
+}
+
+impl DnsOptions {
+    pub fn enabled(&self) -> bool {
+        self.spread || self.refresh.is_some()
+    }
+}
+
+/// Addresses of one hostname, shared between the clients connecting to it.
+#[derive(Clone)]
+pub struct ResolvedHost {
+    addrs: Arc<RwLock<Vec<SocketAddr>>>,
+    next: Arc<AtomicUsize>,
+    spread: bool,
+}
+
+impl ResolvedHost {
+    /// Address for the next connection.
+    pub fn pick(&self) -> SocketAddr {
+        let addrs = self.addrs.read().unwrap();
+        match self.spread {
+            true => addrs[self.next.fetch_add(1, Ordering::Relaxed) % addrs.len()],
+            false => addrs[0],
+        }
+    }
+}
+
+/// Resolves every hostname once and keeps the refresh tasks running until
+/// it is dropped. Only the addresses the clients can bind for are kept.
+pub struct Resolver {
+    options: DnsOptions,
+    bind: Arc<BindOptions>,
+    hosts: HashMap<String, ResolvedHost>,
+    refreshers: Vec<JoinHandle<()>>,
+}
+
+impl Resolver {
+    pub fn new(options: DnsOptions, bind: Arc<BindOptions>) -> Self {
+        Self {
+            options,
+            bind,
+            hosts: HashMap::new(),
+            refreshers: Vec::new(),
+        }
+    }
+
+    /// Resolves `host` (as `HOST:PORT`) the first time it is asked for.
+    pub async fn lookup(&mut self, host: &str) -> io::Result<ResolvedHost> {
+        if let Some(resolved) = self.hosts.get(host) {
+            return Ok(resolved.clone());
+        }
+        let addrs = resolve(host, &self.bind).await?;
+        info!("{host} resolved to {}", format_addrs(&addrs));
+        let resolved = ResolvedHost {
+            addrs: Arc::new(RwLock::new(addrs)),
+            next: Arc::new(AtomicUsize::new(0)),
+            spread: self.options.spread,
+        };
+        if let Some(period) = self.options.refresh {
+            self.refreshers.push(spawn(refresh(
+                host.to_owned(),
+                self.bind.clone(),
+                resolved.addrs.clone(),
+                period,
+            )));
+        }
+        self.hosts.insert(host.to_owned(), resolved.clone());
+        Ok(resolved)
+    }
+}
+
+impl Drop for Resolver {
+    fn drop(&mut self) {
+        for refresher in &self.refreshers {
+            refresher.abort();
+        }
+    }
+}
+
+async fn refresh(
+    host: String,
+    bind: Arc<BindOptions>,
+    addrs: Arc<RwLock<Vec<SocketAddr>>>,
+    period: Duration,
+) {
+    let mut timer = interval_at(Instant::now() + period, period);
+    loop {
+        timer.tick().await;
+        match resolve(&host, &bind).await {
+            Ok(fresh) => {
+                let mut addrs = addrs.write().unwrap();
+                if *addrs != fresh {
+                    info!("{host} now resolves to {}", format_addrs(&fresh));
+                    *addrs = fresh;
+                }
+            }
+            // Keep using the previous answer, as a caching client would
+            Err(err) => warn!("Failed to re-resolve {host}: {err}"),
+        }
+    }
+}
+
+/// Addresses of `host` of the families `bind` has source addresses for.
+async fn resolve(host: &str, bind: &BindOptions) -> io::Result<Vec<SocketAddr>> {
+    let mut addrs: Vec<_> = lookup_host(host).await?.collect();
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{host} did not resolve"),
+        ));
+    }
+    addrs.retain(|addr| bind.supports(addr));
+    if addrs.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::AddrNotAvailable,
+            format!("{host} has no address of a --bind family"),
+        ));
+    }
+    // The resolver may shuffle records between queries
+    addrs.sort();
+    addrs.dedup();
+    Ok(addrs)
+}
+
+fn format_addrs(addrs: &[SocketAddr]) -> String {
+    addrs
+        .iter()
+        .map(SocketAddr::to_string)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
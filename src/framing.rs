@@ -0,0 +1,321 @@
+//! Message framing for payloads sent over stream transports, and the
+//! matching splitting on the receiving side.
+
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LengthPrefix {
+    U16,
+    U32,
+    /// Unsigned LEB128, as used by protobuf length-delimited streams.
+    Varint,
+}
+
+/// How payloads are marked for the receiving side.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessageOptions {
+    /// Payload boundaries on TCP, TLS and Unix stream connections.
+    pub framing: Framing,
+    /// Stamp UDP/DTLS datagrams with a sequence header.
+    pub sequence: bool,
+}
+
+/// How payload boundaries are marked on a byte stream.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// Payload bytes are written back to back.
+    #[default]
+    None,
+    /// Payload length written before the payload.
+    Length {
+        prefix: LengthPrefix,
+        little_endian: bool,
+    },
+    /// Delimiter written after every payload.
+    Delimiter(Vec<u8>),
+    /// Payloads padded with zeros, or truncated, to a fixed size.
+    Pad(usize),
+}
+
+impl Framing {
+    /// Frames a single payload. Payloads too long for a fixed-width
+    /// prefix are truncated to the largest length it can carry.
+    pub fn apply(&self, mut payload: Vec<u8>) -> Vec<u8> {
+        match self {
+            Framing::None => payload,
+            Framing::Length {
+                prefix,
+                little_endian,
+            } => {
+                let mut framed = Vec::with_capacity(payload.len() + 10);
+                match prefix {
+                    LengthPrefix::U16 => {
+                        payload.truncate(u16::MAX as usize);
+                        let len = payload.len() as u16;
+                        framed.extend_from_slice(&match little_endian {
+                            true => len.to_le_bytes(),
+                            false => len.to_be_bytes(),
+                        });
+                    }
+                    LengthPrefix::U32 => {
+                        payload.truncate(u32::MAX as usize);
+                        let len = payload.len() as u32;
+                        framed.extend_from_slice(&match little_endian {
+                            true => len.to_le_bytes(),
+                            false => len.to_be_bytes(),
+                        });
+                    }
+                    LengthPrefix::Varint => {
+                        let mut len = payload.len() as u64;
+                        while len >= 0x80 {
+                            framed.push(len as u8 | 0x80);
+                            len >>= 7;
+                        }
+                        framed.push(len as u8);
+                    }
+                }
+                framed.extend_from_slice(&payload);
+                framed
+            }
+            Framing::Delimiter(delimiter) => {
+                payload.extend_from_slice(delimiter);
+                payload
+            }
+            Framing::Pad(size) => {
+                payload.resize(*size, 0);
+                payload
+            }
+        }
+    }
+
+    /// Counts and removes the complete frames at the start of `buf`, leaving
+    /// a trailing partial frame in place. Without framing every non-empty
+    /// read counts as one frame.
+    pub fn split(&self, buf: &mut Vec<u8>) -> usize {
+        let mut frames = 0;
+        let mut offset = 0;
+        loop {
+            let rest = &buf[offset..];
+            let len = match self {
+                Framing::None => (!rest.is_empty()).then_some(rest.len()),
+                Framing::Length {
+                    prefix,
+                    little_endian,
+                } => decode_length(rest, *prefix, *little_endian)
+                    .and_then(|(header, len)| header.checked_add(len))
+                    .filter(|&total| total <= rest.len()),
+                Framing::Delimiter(delimiter) => rest
+                    .windows(delimiter.len())
+                    .position(|w| w == delimiter.as_slice())
+                    .map(|pos| pos + delimiter.len()),
+                Framing::Pad(size) => (rest.len() >= *size).then_some(*size),
+            };
+            match len {
+                Some(len) => {
+                    offset += len;
+                    frames += 1;
+                }
+                None => break,
+            }
+        }
+        buf.drain(..offset);
+        frames
+    }
+}
+
+/// Decodes a length prefix into its encoded size and the payload length.
+fn decode_length(buf: &[u8], prefix: LengthPrefix, little_endian: bool) -> Option<(usize, usize)> {
+    match prefix {
+        LengthPrefix::U16 => {
+            let bytes = buf.get(..2)?.try_into().ok()?;
+            let len = match little_endian {
+                true => u16::from_le_bytes(bytes),
+                false => u16::from_be_bytes(bytes),
+            };
+            Some((2, len as usize))
+        }
+        LengthPrefix::U32 => {
+            let bytes = buf.get(..4)?.try_into().ok()?;
+            let len = match little_endian {
+                true => u32::from_le_bytes(bytes),
+                false => u32::from_be_bytes(bytes),
+            };
+            Some((4, len as usize))
+        }
+        LengthPrefix::Varint => {
+            let mut len = 0u64;
+            for (i, byte) in buf.iter().take(10).enumerate() {
+                len |= ((byte & 0x7F) as u64) << (7 * i);
+                if byte & 0x80 == 0 {
+                    return Some((i + 1, len as usize));
+                }
+            }
+            None
+        }
+    }
+}
+
+impl FromStr for Framing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let length = |prefix, little_endian| {
+            Ok(Self::Length {
+                prefix,
+                little_endian,
+            })
+        };
+        match s {
+            "none" => Ok(Self::None),
+            "u16" | "u16be" => length(LengthPrefix::U16, false),
+            "u16le" => length(LengthPrefix::U16, true),
+            "u32" | "u32be" => length(LengthPrefix::U32, false),
+            "u32le" => length(LengthPrefix::U32, true),
+            "varint" => length(LengthPrefix::Varint, false),
+            "newline" => Ok(Self::Delimiter(b"\n".to_vec())),
+            "crlf" => Ok(Self::Delimiter(b"\r\n".to_vec())),
+            _ => {
+                if let Some(delimiter) = s.strip_prefix("delim:") {
+                    let delimiter = unescape(delimiter)?;
+                    if delimiter.is_empty() {
+                        return Err("empty framing delimiter".to_owned());
+                    }
+                    Ok(Self::Delimiter(delimiter))
+                } else if let Some(size) = s.strip_prefix("pad:") {
+                    match size.parse() {
+                        Ok(size) if size > 0 => Ok(Self::Pad(size)),
+                        _ => Err(format!("invalid padding size: {size}")),
+                    }
+                } else {
+                    Err(format!("unknown framing: {s}"))
+                }
+            }
+        }
+    }
+}
+
+/// Resolves `\n`, `\r`, `\t`, `\0`, `\\` and `\xHH` escapes.
+fn unescape(s: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('n') => bytes.push(b'\n'),
+            Some('r') => bytes.push(b'\r'),
+            Some('t') => bytes.push(b'\t'),
+            Some('0') => bytes.push(0),
+            Some('\\') => bytes.push(b'\\'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                let byte = u8::from_str_radix(&hex, 16)
+                    .map_err(|_| format!("invalid escape in delimiter: \\x{hex}"))?;
+                bytes.push(byte);
+            }
+            other => {
+                return Err(format!(
+                    "invalid escape in delimiter: \\{}",
+                    other.map(String::from).unwrap_or_default()
+                ))
+            }
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn framing(s: &str) -> Framing {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn length_prefixes_round_trip() {
+        for name in ["u16", "u16le", "u32", "u32le", "varint"] {
+            let framing = framing(name);
+            let mut buf = framing.apply(b"hello".to_vec());
+            buf.extend(framing.apply(Vec::new()));
+            assert_eq!(framing.split(&mut buf), 2, "{name}");
+            assert!(buf.is_empty(), "{name}");
+        }
+    }
+
+    #[test]
+    fn length_prefix_split_across_reads() {
+        let framing = framing("u32");
+        let framed = framing.apply(b"hello".to_vec());
+        let mut buf = framed[..2].to_vec();
+        assert_eq!(framing.split(&mut buf), 0);
+        buf.extend_from_slice(&framed[2..6]);
+        assert_eq!(framing.split(&mut buf), 0);
+        buf.extend_from_slice(&framed[6..]);
+        assert_eq!(framing.split(&mut buf), 1);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn varint_boundaries() {
+        let framing = framing("varint");
+        for (len, header) in [(0, 1), (127, 1), (128, 2), (16383, 2), (16384, 3)] {
+            let framed = framing.apply(vec![0; len]);
+            assert_eq!(framed.len(), header + len, "{len}");
+            // Only the last byte of the prefix is missing
+            let mut buf = framed[..header - 1].to_vec();
+            assert_eq!(framing.split(&mut buf), 0, "{len}");
+            buf.extend_from_slice(&framed[header - 1..]);
+            assert_eq!(framing.split(&mut buf), 1, "{len}");
+        }
+    }
+
+    #[test]
+    fn u16_prefix_truncates_long_payloads() {
+        let framed = framing("u16").apply(vec![1; u16::MAX as usize + 10]);
+        assert_eq!(framed[..2], [0xFF, 0xFF]);
+        assert_eq!(framed.len(), 2 + u16::MAX as usize);
+    }
+
+    #[test]
+    fn delimiter_split_across_reads() {
+        let framing = framing("crlf");
+        let mut buf = b"one\r\ntwo\r".to_vec();
+        assert_eq!(framing.split(&mut buf), 1);
+        assert_eq!(buf, b"two\r");
+        buf.extend_from_slice(b"\n");
+        assert_eq!(framing.split(&mut buf), 1);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn pad_fills_and_truncates() {
+        let framing = framing("pad:4");
+        assert_eq!(framing.apply(b"ab".to_vec()), b"ab\0\0");
+        assert_eq!(framing.apply(b"abcdef".to_vec()), b"abcd");
+        let mut buf = vec![0; 10];
+        assert_eq!(framing.split(&mut buf), 2);
+        assert_eq!(buf.len(), 2);
+    }
+
+    #[test]
+    fn unframed_reads_count_once() {
+        let mut buf = b"abc".to_vec();
+        assert_eq!(Framing::None.split(&mut buf), 1);
+        assert_eq!(Framing::None.split(&mut buf), 0);
+    }
+
+    #[test]
+    fn parses_delimiter_escapes() {
+        assert_eq!(
+            framing("delim:\\x00|\\n"),
+            Framing::Delimiter(vec![0, b'|', b'\n'])
+        );
+        assert!("delim:".parse::<Framing>().is_err());
+        assert!("delim:\\xZZ".parse::<Framing>().is_err());
+        assert!("pad:0".parse::<Framing>().is_err());
+    }
+}
@@ -0,0 +1,389 @@
+//! HTTP/1.1 and HTTP/2 request building, HTTP/1.1 response parsing.
+
+use std::{io, str::FromStr, time::Duration};
+
+use bytes::Bytes;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::payload::PayloadEntry;
+
+/// Upper bound on response heads, anything larger is treated as a protocol error.
+const MAX_HEADERS: usize = 64;
+/// Room made in the buffer before each read.
+const READ_CHUNK: usize = 16384;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpVersion {
+    Http1,
+    /// Negotiated through ALPN over TLS, prior knowledge (h2c) over plain TCP.
+    Http2,
+}
+
+impl FromStr for HttpVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1.1" => Ok(Self::Http1),
+            "2" => Ok(Self::Http2),
+            _ => Err(format!("unknown HTTP version: {s}")),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct HttpOptions {
+    pub version: HttpVersion,
+    pub method: String,
+    pub path: String,
+    /// Extra headers as `Name: value` lines, sent with every request.
+    pub headers: Vec<String>,
+    /// Requests written back to back before waiting for the responses.
+    pub pipeline: usize,
+    pub keep_alive: bool,
+    /// HTTP/2 streams in flight per connection.
+    pub max_concurrent_streams: usize,
+    /// HTTP/2 responses still incomplete after this long count as errors.
+    pub response_timeout: Duration,
+}
+
+impl HttpOptions {
+    /// Serializes the request for `entry`, whose data is used as the body of
+    /// methods that carry one. Per-entry method, path and headers take
+    /// precedence over the command line ones.
+    pub fn build_request(&self, entry: &PayloadEntry, host: &str) -> (Vec<u8>, bool) {
+        let (method, path, headers, body) = self.request_parts(entry);
+        let has_header = |name: &str| headers.iter().any(|(n, _)| n.eq_ignore_ascii_case(name));
+
+        let mut request = format!("{method} {path} HTTP/1.1\r\n");
+        if !has_header("host") {
+            request += &format!("Host: {host}\r\n");
+        }
+        if !has_header("connection") && !self.keep_alive {
+            request += "Connection: close\r\n";
+        }
+        for (name, value) in &headers {
+            request += &format!("{name}: {value}\r\n");
+        }
+        if !body.is_empty() && !has_header("content-length") {
+            request += &format!("Content-Length: {}\r\n", body.len());
+        }
+        request += "\r\n";
+        request += body;
+
+        (request.into_bytes(), method == "HEAD")
+    }
+
+    /// HTTP/2 counterpart of [`HttpOptions::build_request`]. Connection-specific
+    /// headers are dropped as HTTP/2 forbids them, `Host` becomes the authority.
+    pub fn build_h2_request(
+        &self,
+        entry: &PayloadEntry,
+        scheme: &str,
+        host: &str,
+    ) -> Result<(http::Request<()>, Bytes), http::Error> {
+        let (method, path, headers, body) = self.request_parts(entry);
+        let authority = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("host"))
+            .map_or(host, |(_, value)| value);
+
+        let mut request = http::Request::builder()
+            .method(method)
+            .uri(format!("{scheme}://{authority}{path}"));
+        for (name, value) in &headers {
+            let name = name.to_ascii_lowercase();
+            if !matches!(
+                name.as_str(),
+                "host" | "connection" | "keep-alive" | "transfer-encoding" | "upgrade"
+            ) {
+                request = request.header(name, *value);
+            }
+        }
+        Ok((request.body(())?, Bytes::copy_from_slice(body.as_bytes())))
+    }
+
+    fn request_parts<'a>(
+        &'a self,
+        entry: &'a PayloadEntry,
+    ) -> (&'a str, &'a str, Vec<(&'a str, &'a str)>, &'a str) {
+        let method = entry.method.as_deref().unwrap_or(&self.method);
+        let path = entry.path.as_deref().unwrap_or(&self.path);
+        let headers = self
+            .headers
+            .iter()
+            .chain(&entry.headers)
+            .filter_map(|h| h.split_once(':'))
+            .map(|(name, value)| (name.trim(), value.trim()))
+            .collect();
+        let body = match method {
+            "GET" | "HEAD" => "",
+            _ => entry.data.as_str(),
+        };
+        (method, path, headers, body)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Response {
+    pub status: u16,
+    /// The server will close the connection after this response.
+    pub close: bool,
+}
+
+enum BodyLength {
+    Empty,
+    Fixed(usize),
+    Chunked,
+    UntilClose,
+}
+
+/// Incremental HTTP/1.1 response reader keeping leftover bytes of pipelined
+/// responses between calls. Bodies are discarded as they arrive, only heads
+/// and chunk size lines are buffered whole.
+#[derive(Debug, Default)]
+pub struct ResponseReader {
+    buf: Vec<u8>,
+}
+
+impl ResponseReader {
+    pub fn clear(&mut self) {
+        self.buf.clear();
+    }
+
+    /// Reads one complete response, skipping interim `1xx` responses.
+    pub async fn read_response<S: AsyncRead + Unpin + ?Sized>(
+        &mut self,
+        stream: &mut S,
+        head_request: bool,
+    ) -> io::Result<Response> {
+        loop {
+            let (head_len, status, length, close) = loop {
+                let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+                let mut response = httparse::Response::new(&mut headers);
+                match response.parse(&self.buf).map_err(io::Error::other)? {
+                    httparse::Status::Complete(head_len) => {
+                        let status = response.code.unwrap_or_default();
+                        let (length, close) = body_length(&response, head_request);
+                        break (head_len, status, length, close);
+                    }
+                    httparse::Status::Partial => self.fill(stream).await?,
+                }
+            };
+            self.buf.drain(..head_len);
+
+            match length {
+                BodyLength::Empty => {}
+                BodyLength::Fixed(len) => self.consume(stream, len).await?,
+                BodyLength::Chunked => self.consume_chunked(stream).await?,
+                BodyLength::UntilClose => {
+                    while stream.read_buf(&mut self.buf).await? > 0 {
+                        self.buf.clear();
+                    }
+                }
+            }
+
+            if !(100..200).contains(&status) {
+                let close = close || matches!(length, BodyLength::UntilClose);
+                return Ok(Response { status, close });
+            }
+        }
+    }
+
+    async fn fill<S: AsyncRead + Unpin + ?Sized>(&mut self, stream: &mut S) -> io::Result<()> {
+        self.buf.reserve(READ_CHUNK);
+        if stream.read_buf(&mut self.buf).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+
+    /// Skips `len` bytes, dropping them as soon as they are read.
+    async fn consume<S: AsyncRead + Unpin + ?Sized>(
+        &mut self,
+        stream: &mut S,
+        mut len: usize,
+    ) -> io::Result<()> {
+        loop {
+            let buffered = len.min(self.buf.len());
+            self.buf.drain(..buffered);
+            len -= buffered;
+            if len == 0 {
+                return Ok(());
+            }
+            self.fill(stream).await?;
+        }
+    }
+
+    async fn read_line<S: AsyncRead + Unpin + ?Sized>(
+        &mut self,
+        stream: &mut S,
+    ) -> io::Result<String> {
+        loop {
+            if let Some(end) = self.buf.windows(2).position(|w| w == b"\r\n") {
+                let line = String::from_utf8_lossy(&self.buf[..end]).into_owned();
+                self.buf.drain(..end + 2);
+                return Ok(line);
+            }
+            self.fill(stream).await?;
+        }
+    }
+
+    async fn consume_chunked<S: AsyncRead + Unpin + ?Sized>(
+        &mut self,
+        stream: &mut S,
+    ) -> io::Result<()> {
+        loop {
+            let line = self.read_line(stream).await?;
+            let size = line.split(';').next().unwrap_or_default().trim();
+            let size = usize::from_str_radix(size, 16)
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "bad chunk size"))?;
+            if size == 0 {
+                // Trailer section ends with an empty line
+                while !self.read_line(stream).await?.is_empty() {}
+                return Ok(());
+            }
+            self.consume(stream, size + 2).await?;
+        }
+    }
+}
+
+fn body_length(response: &httparse::Response, head_request: bool) -> (BodyLength, bool) {
+    let mut content_length = None;
+    let mut chunked = false;
+    let mut close = response.version == Some(0);
+    for header in response.headers.iter() {
+        let value = String::from_utf8_lossy(header.value);
+        if header.name.eq_ignore_ascii_case("content-length") {
+            content_length = value.trim().parse().ok();
+        } else if header.name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.to_ascii_lowercase().contains("chunked");
+        } else if header.name.eq_ignore_ascii_case("connection") {
+            if value.eq_ignore_ascii_case("close") {
+                close = true;
+            } else if value.eq_ignore_ascii_case("keep-alive") {
+                close = false;
+            }
+        }
+    }
+
+    let status = response.code.unwrap_or_default();
+    let length = if head_request || (100..200).contains(&status) || status == 204 || status == 304 {
+        BodyLength::Empty
+    } else if chunked {
+        BodyLength::Chunked
+    } else if let Some(len) = content_length {
+        BodyLength::Fixed(len)
+    } else {
+        BodyLength::UntilClose
+    };
+    (length, close)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read_all(input: &[u8], head_requests: &[bool]) -> Vec<u16> {
+        let mut stream = input;
+        let mut reader = ResponseReader::default();
+        let mut statuses = Vec::new();
+        for head_request in head_requests {
+            let response = reader
+                .read_response(&mut stream, *head_request)
+                .await
+                .unwrap();
+            statuses.push(response.status);
+        }
+        assert!(stream.is_empty() && reader.buf.is_empty());
+        statuses
+    }
+
+    #[tokio::test]
+    async fn content_length_body() {
+        let input = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello";
+        assert_eq!(read_all(input, &[false]).await, [200]);
+    }
+
+    #[tokio::test]
+    async fn large_body_is_not_buffered() {
+        let body = vec![b'x'; 1 << 20];
+        let mut input =
+            format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body.len()).into_bytes();
+        input.extend_from_slice(&body);
+        let mut stream = input.as_slice();
+        let mut reader = ResponseReader::default();
+        reader.read_response(&mut stream, false).await.unwrap();
+        assert!(stream.is_empty());
+        assert!(reader.buf.capacity() < 4 * READ_CHUNK);
+    }
+
+    #[tokio::test]
+    async fn chunked_body_with_trailers() {
+        let input = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n\
+            5;ext=1\r\nhello\r\n6\r\n world\r\n0\r\nX-Checksum: 1\r\nX-Other: 2\r\n\r\n";
+        assert_eq!(read_all(input, &[false]).await, [200]);
+    }
+
+    #[tokio::test]
+    async fn bad_chunk_size() {
+        let mut input = &b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\nzz\r\n"[..];
+        let err = ResponseReader::default()
+            .read_response(&mut input, false)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn pipelined_responses_keep_leftovers() {
+        let input = b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nabc\
+            HTTP/1.1 404 Not Found\r\nTransfer-Encoding: chunked\r\n\r\n1\r\nx\r\n0\r\n\r\n\
+            HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n";
+        assert_eq!(
+            read_all(input, &[false, false, false]).await,
+            [200, 404, 201]
+        );
+    }
+
+    #[tokio::test]
+    async fn interim_responses_are_skipped() {
+        let input = b"HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 103 Early Hints\r\nLink: </a>\r\n\r\n\
+            HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok";
+        assert_eq!(read_all(input, &[false]).await, [200]);
+    }
+
+    #[tokio::test]
+    async fn responses_without_body() {
+        // Content-Length of a HEAD response describes the body a GET would get
+        let input = b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n\
+            HTTP/1.1 204 No Content\r\n\r\n\
+            HTTP/1.1 304 Not Modified\r\nContent-Length: 10\r\n\r\n";
+        assert_eq!(
+            read_all(input, &[true, false, false]).await,
+            [200, 204, 304]
+        );
+    }
+
+    #[tokio::test]
+    async fn body_until_close() {
+        let mut input = &b"HTTP/1.0 200 OK\r\n\r\nuntil the end"[..];
+        let response = ResponseReader::default()
+            .read_response(&mut input, false)
+            .await
+            .unwrap();
+        assert!(response.close);
+        assert!(input.is_empty());
+    }
+
+    #[tokio::test]
+    async fn truncated_body() {
+        let mut input = &b"HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\nshort"[..];
+        let err = ResponseReader::default()
+            .read_response(&mut input, false)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
@@ -1,157 +1,824 @@
-use std::{fs, io::Error, net::SocketAddr, path::Path, time::Duration};
-use crate::payload::PayloadConfig;
+
+use std::{
+    fmt,
+    fs,
+    io::{self, Error},
+    net::{SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+    future::Future,
+    pin::Pin,
+    str::FromStr,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+use crate::{
+    batch::{BatchOptions, UdpBatcher},
+    bind::{LocalEndpoint, SourceOptions},
+    control::{ClientControl, Command, ControlReceiver},
+    dashboard::Dashboard,
+    dns::{DnsOptions, ResolvedHost, Resolver},
+    framing::{Framing, MessageOptions},
+    http::{HttpOptions, HttpVersion},
+    payload::{PayloadConfig, PayloadSource},
+    ports::PortAllocator,
+    quic::QuicOptions,
+    statistics::{StatPacket, StatsContext, StatsSender},
+    targets::{Assignment, Target, TargetSelector},
+    uring::{UringBackend, UringSocket},
+    websocket::WsOptions,
+};
 
 use derive_new::new;
-use log::{error, info};
+use log::{debug, error, info, warn};
 use openssl::ssl::{SslContext, SslMethod};
-use sender::{sender_task_dtls, sender_task_tcp, sender_task_udp};
+use quinn::{Endpoint, EndpointConfig, TokioRuntime};
+#[cfg(unix)]
+use sender::sender_task_unix_datagram;
+use sender::{
+    sender_task_dtls, sender_task_http, sender_task_http2, sender_task_quic, sender_task_tcp,
+    sender_task_udp, sender_task_websocket,
+};
 use statistics::stats_task;
 use tokio::{
-    io::AsyncWrite,
-    net::{TcpSocket, TcpStream, UdpSocket},
-    task::JoinSet,
-    time::sleep,
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpStream, UdpSocket},
+    spawn,
+    sync::{OwnedSemaphorePermit, Semaphore},
+    task::{AbortHandle, JoinSet},
+    time::{interval, sleep, MissedTickBehavior},
 };
+#[cfg(unix)]
+use tokio::net::{UnixDatagram, UnixStream};
 use tokio_dtls_stream_sink::{Client, Session};
-use tokio_native_tls::native_tls::{Certificate, TlsConnector};
+use tokio_native_tls::{
+    native_tls::{Certificate, TlsConnector},
+    TlsStream,
+};
 
+mod dashboard;
 mod sender;
 mod statistics;
+mod uring;
+pub mod batch;
+pub mod bind;
+pub mod control;
+pub mod control_api;
+pub mod distributed;
+pub mod dns;
+pub mod framing;
+pub mod http;
+pub mod load_test;
 pub mod payload;
+pub mod ports;
+pub mod quic;
+pub mod scenario;
+pub mod sequence;
+pub mod server;
+pub mod targets;
+pub mod websocket;
+
+pub use control::Controller;
+pub use load_test::{LoadTest, LoadTestBuilder, RunHandle, Transport};
+pub use statistics::{Histogram, RunReport, StatsOptions, TargetReport};
 
+/// Runs the test until it completes or Ctrl+C is pressed and returns the
+/// total packets sent.
 pub async fn manager(params: Parameters) -> usize {
+    let (controller, control) = Controller::new(params.rate);
+    let ctrl_c = controller.clone();
+    spawn(async move {
+        shutdown_signal().await;
+        ctrl_c.stop();
+    });
+    match run_test(params, controller, control).await {
+        Ok(report) => report.packets_sent,
+        Err(err) => {
+            error!("{err}");
+            0
+        }
+    }
+}
+
+/// Runs the test until it completes or `controller` stops it.
+async fn run_test(
+    params: Parameters,
+    controller: Controller,
+    control: ControlReceiver,
+) -> Result<RunReport, String> {
+    let started = Instant::now();
     let (udp, (use_tls, ca_file)) = params.connection_type;
-    if use_tls && ca_file.is_none() {
-        error!("DTLS requires CA file to verify server credentials");
-        return 0;
+    let secure = use_tls || params.quic.is_some();
+    if secure && ca_file.is_none() {
+        return Err("TLS/DTLS/QUIC require CA file to verify server credentials".to_owned());
+    }
+    let all_inet = params
+        .targets
+        .iter()
+        .all(|target| matches!(target.destination, Destination::Inet(_)));
+    for target in &params.targets {
+        if let Destination::Inet(addr) = &target.destination {
+            if !params.source.bind.supports(addr) {
+                return Err(format!("No --bind address of the same family as {}", target.label()));
+            }
+        }
+    }
+    if secure && !all_inet {
+        return Err("TLS/DTLS/QUIC are not supported over Unix sockets".to_owned());
+    }
+    if params.http.is_some() && (udp || params.quic.is_some()) {
+        return Err("HTTP mode requires a TCP or TLS transport".to_owned());
+    }
+    if params.websocket.is_some() && (udp || params.quic.is_some() || params.http.is_some()) {
+        return Err("WebSocket mode requires a TCP or TLS transport and excludes HTTP mode".to_owned());
     }
+    let http2 = params
+        .http
+        .as_ref()
+        .is_some_and(|http| http.version == HttpVersion::Http2);
+    let alpn = match http2 {
+        true if params.alpn.is_empty() => vec!["h2".to_owned()],
+        _ => params.alpn.clone(),
+    };
+    let byte_stream = !udp && params.quic.is_none() && params.http.is_none() && params.websocket.is_none();
+    if params.messages.framing != Framing::None && !byte_stream {
+        warn!("Payload framing only applies to TCP, TLS and Unix stream connections, ignoring it");
+    }
+    let framing = match byte_stream {
+        true => params.messages.framing.clone(),
+        false => Framing::None,
+    };
+    let sequence = params.messages.sequence && udp && params.quic.is_none();
+    if params.messages.sequence && !sequence {
+        warn!("Sequence headers only apply to UDP and DTLS datagrams, ignoring them");
+    }
+    let tls_connector = (use_tls && !udp)
+        .then(|| setup_tls_connector(ca_file.as_ref().unwrap(), &alpn))
+        .transpose()?;
+    let quic_config = params
+        .quic
+        .as_ref()
+        .map(|_| quic::client_config(ca_file.as_ref().unwrap(), &params.alpn))
+        .transpose()?;
 
-    // Setup quit channel for auto-termination
-    let (quit_tx, mut quit_rx) = tokio::sync::mpsc::channel::<()>(1);
+    let control_api = params
+        .control_listen
+        .map(|listen| spawn(control_api::serve(listen, controller.clone())));
+
+    if let Some(duration) = params.duration {
+        let duration_tx = controller.quit_tx();
+        spawn(async move {
+            sleep(duration).await;
+            info!("Test duration of {duration:?} reached");
+            let _ = duration_tx.send(()).await;
+        });
+    }
     
+    let source = params.source;
+    let ports = PortAllocator::new(&source.ports, params.connections, source.bind.port_slots())?;
+    let dashboard = match params.stats.tui {
+        true => Dashboard::start(controller.clone(), params.stats.label.clone()),
+        false => None,
+    };
+
     // Initialize stats tracker with max packets if specified
-    let stats_tracker = stats_task(params.connections, params.max_packets, Some(quit_tx));
+    let stats_tracker = stats_task(
+        params.connections,
+        StatsContext {
+            max_packets: params.max_packets,
+            quit_tx: Some(controller.quit_tx()),
+            snapshots: Some(control.snapshots),
+            samples: dashboard.as_ref().map(Dashboard::samples),
+            options: params.stats,
+        },
+    );
     
+    let uring = match params.backend {
+        Backend::IoUring
+            if secure
+                || sequence
+                || params.http.is_some()
+                || params.websocket.is_some()
+                || !all_inet => {
+            warn!("io-uring backend only supports plain UDP/TCP, using tokio");
+            None
+        }
+        Backend::IoUring => {
+            let threads = thread::available_parallelism().map_or(1, |n| n.get());
+            match UringBackend::new(threads) {
+                Ok(backend) => Some(backend),
+                Err(err) => {
+                    warn!("io-uring backend unavailable ({err}), using tokio");
+                    None
+                }
+            }
+        }
+        Backend::Tokio => None,
+    };
+
+    let per_target_stats = params.targets.len() > 1;
+    let mut selector = TargetSelector::new(params.targets, params.assignment);
+    let bind = Arc::new(source.bind);
+    let mut resolver = params
+        .dns
+        .enabled()
+        .then(|| Resolver::new(params.dns, bind.clone()));
+    let shared = Arc::new(ClientShared {
+        udp,
+        use_tls,
+        ca_file,
+        tls_connector,
+        quic: params.quic.zip(quic_config),
+        http: params.http,
+        http2,
+        websocket: params.websocket,
+        batch: params.batch,
+        sequence,
+        uring,
+    });
+
+    // Connection setups in flight, a client holds its slot until connected
+    let connect_slots = Arc::new(Semaphore::new(params.connect_parallel.max(1)));
+    let sequential = params.connect_parallel <= 1 && params.connect_rate.is_none();
+    let mut pacing = params.connect_rate.map(|rate| {
+        let mut pacing = interval(Duration::from_secs_f64(1. / rate as f64));
+        pacing.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        pacing
+    });
+
     let mut tasks = JoinSet::new();
-    let mut start_port = params.start_port;
-
-    for id in 0..params.connections {
-        start_port += id;
-        let fallback_payload = params.payload.as_bytes().to_vec();
-        let payload_config = params.payload_config.clone();
-        let stats_tx_cloned = stats_tracker.tx.clone();
-        let ca_file = ca_file.clone();
-        let sequential_payload = params.sequential_payload;
-        let random_payload = params.random_payload;
-        
-        if use_tls {
-            if udp {
-                let session =
-                    setup_dtls_session(start_port, params.server_addr, ca_file.unwrap()).await;
-                tasks.spawn(async move {
-                    sender_task_dtls(id, session, fallback_payload, params.rate, stats_tx_cloned).await
-                });
-            } else {
-                let stream =
-                    setup_tls_stream(start_port, params.server_addr, ca_file.unwrap()).await;
-                tasks.spawn(async move {
-                    sender_task_tcp(id, stream, payload_config, fallback_payload, params.rate, stats_tx_cloned, 
-                                   sequential_payload, random_payload).await
-                });
+    // Ids of the clients still running, most recent last
+    let mut clients: Vec<(usize, AbortHandle)> = Vec::new();
+    let mut next_id = 0;
+    let mut wanted = params.connections;
+    let mut removed = 0;
+    let mut commands = control.commands;
+    let mut quit_rx = control.quit_rx;
+
+    loop {
+        let spawning = next_id < wanted;
+        // Clients removed on purpose leave the test running until stopped
+        if !spawning && tasks.is_empty() && wanted > removed {
+            info!("All tasks completed");
+            break;
+        }
+        let id = next_id;
+        let ready = async {
+            let permit = connect_slots.clone().acquire_owned().await.unwrap();
+            match &mut pacing {
+                Some(pacing) => {
+                    pacing.tick().await;
+                }
+                // One client after the other, pausing once each is connected
+                None if sequential && id > 0 => sleep(Duration::from_millis(params.sleep)).await,
+                None => {}
+            }
+            permit
+        };
+
+        tokio::select! {
+            permit = ready, if spawning => {
+                let start_port = ports.port(id);
+                let payloads = PayloadSource::new(
+                    params.payload_config.clone(),
+                    params.payload.as_bytes().to_vec(),
+                    params.sequential_payload,
+                    params.random_payload,
+                    framing.clone(),
+                    sequence.then_some(id as u32),
+                );
+                let stats_tx = stats_tracker.tx.for_client(id);
+                let target = selector.select(id).clone();
+                let resolved = match (&target.host, &mut resolver) {
+                    (Some(host), Some(resolver)) => match resolver.lookup(host).await {
+                        Ok(resolved) => Some(resolved),
+                        Err(err) => {
+                            warn!("Failed to resolve {host} ({err}), using {}", target.destination);
+                            None
+                        }
+                    },
+                    _ => None,
+                };
+                let local = LocalEndpoint::new(bind.clone(), id, start_port);
+                let destination = match &resolved {
+                    Some(resolved) => Destination::Inet(resolved.pick()),
+                    None => target.destination.clone(),
+                };
+                if per_target_stats {
+                    let _ = stats_tx.send(StatPacket::Target(target.label())).await;
+                }
+
+                let client = ClientSetup {
+                    id,
+                    destination,
+                    resolved,
+                    local,
+                    start_port,
+                    payloads,
+                    control: ClientControl::new(control.settings.clone()),
+                    stats_tx,
+                };
+                clients.push((id, tasks.spawn(run_client(shared.clone(), client, permit))));
+                next_id += 1;
+            }
+            Some(command) = commands.recv() => {
+                // Pending and live clients
+                let running = wanted - next_id + clients.len();
+                let (add, remove) = match command {
+                    Command::Add(count) => (count, 0),
+                    Command::Remove(count) => (0, count),
+                    Command::Set(count) => {
+                        (count.saturating_sub(running), running.saturating_sub(count))
+                    }
+                };
+                if add > 0 {
+                    wanted += add;
+                    info!("Adding {add} connections");
+                }
+                if remove > 0 {
+                    // Drop the clients not spawned yet first
+                    let pending = (wanted - next_id).min(remove);
+                    wanted -= pending;
+                    let mut left = remove - pending;
+                    while left > 0 {
+                        let Some((id, client)) = clients.pop() else { break };
+                        if !client.is_finished() {
+                            client.abort();
+                            let _ = stats_tracker.tx.for_client(id).send(StatPacket::Closed).await;
+                            removed += 1;
+                            left -= 1;
+                        }
+                    }
+                    info!("Removed {} connections", remove - left);
+                }
+            }
+            Some(joined) = tasks.join_next_with_id(), if !tasks.is_empty() => {
+                let (task, panicked) = match &joined {
+                    Ok((task, ())) => (*task, false),
+                    Err(err) => (err.id(), err.is_panic()),
+                };
+                if let Some(idx) = clients.iter().position(|(_, client)| client.id() == task) {
+                    let (id, _) = clients.remove(idx);
+                    // Clients report their own end unless they panicked
+                    if panicked {
+                        error!("client {id} panicked");
+                        let _ = stats_tracker.tx.for_client(id).send(StatPacket::Closed).await;
+                    }
+                }
+            }
+            _ = quit_rx.recv() => {
+                info!("Received quit signal, shutting down...");
+                tasks.abort_all();
+                break;
             }
-        } else if udp {
-            let socket = setup_udp_socket(params.server_addr, start_port).await;
-            tasks.spawn(async move {
-                sender_task_udp(id, socket, payload_config, fallback_payload, params.rate, stats_tx_cloned,
-                               sequential_payload, random_payload).await
-            });
-        } else {
-            let stream = setup_tcp_stream(params.server_addr, start_port).await;
-            tasks.spawn(async move {
-                sender_task_tcp(id, stream, payload_config, fallback_payload, params.rate, stats_tx_cloned,
-                               sequential_payload, random_payload).await
-            });
         }
-        sleep(Duration::from_millis(params.sleep)).await;
+        controller.update_connections(clients.len());
+    }
+    controller.update_connections(0);
+    drop(shared);
+    drop(resolver);
+    if let Some(control_api) = control_api {
+        control_api.abort();
+    }
+    if let Some(dashboard) = dashboard {
+        dashboard.close().await;
     }
     
-    // Wait for either quit signal or all tasks to complete
-    tokio::select! {
-        _ = quit_rx.recv() => {
-            info!("Received quit signal, shutting down...");
-            tasks.abort_all();
+    // Return the totals once the final report is out
+    let mut report = stats_tracker.finish().await;
+    report.elapsed = started.elapsed();
+    Ok(report)
+}
+
+/// Settings shared by all the clients of a run.
+struct ClientShared {
+    udp: bool,
+    use_tls: bool,
+    ca_file: Option<String>,
+    tls_connector: Option<tokio_native_tls::TlsConnector>,
+    quic: Option<(QuicOptions, quinn::ClientConfig)>,
+    http: Option<HttpOptions>,
+    http2: bool,
+    websocket: Option<WsOptions>,
+    batch: BatchOptions,
+    sequence: bool,
+    uring: Option<UringBackend>,
+}
+
+/// What sets one client apart from the others.
+struct ClientSetup {
+    id: usize,
+    destination: Destination,
+    resolved: Option<ResolvedHost>,
+    local: LocalEndpoint,
+    start_port: usize,
+    payloads: PayloadSource,
+    control: ClientControl,
+    stats_tx: StatsSender,
+}
+
+/// Sender of a connected client, running until the end of the test.
+type Sender = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Connects the client, gives back its connection slot and then runs its
+/// sender until the end of the test.
+async fn run_client(shared: Arc<ClientShared>, client: ClientSetup, permit: OwnedSemaphorePermit) {
+    let (id, stats_tx) = (client.id, client.stats_tx.clone());
+    let sender = connect_client(&shared, client).await;
+    drop(permit);
+    match sender {
+        Ok(sender) => {
+            let _ = stats_tx.send(StatPacket::Connected).await;
+            sender.await;
         }
-        _ = async { while (tasks.join_next().await).is_some() {} } => {
-            info!("All tasks completed");
+        Err(err) => {
+            debug!("client {id} failed to connect: {err}");
+            let _ = stats_tx.send(StatPacket::Errors(1)).await;
         }
     }
-    
-    // Return the total number of packets sent
-    stats_tracker.get_total_packets()
+    let _ = stats_tx.send(StatPacket::Closed).await;
 }
 
-async fn setup_udp_socket(addr: SocketAddr, port: usize) -> UdpSocket {
-    let socket = UdpSocket::bind("0.0.0.0:".to_owned() + &port.to_string())
-        .await
-        .unwrap();
-    socket.connect(addr).await.unwrap();
-    socket
+async fn connect_client(shared: &ClientShared, client: ClientSetup) -> io::Result<Sender> {
+    let ClientSetup {
+        id,
+        destination,
+        resolved,
+        local,
+        start_port,
+        payloads,
+        control,
+        stats_tx,
+    } = client;
+
+    let sender: Sender = if let Some(options) = &shared.http {
+        let target = StreamTarget {
+            destination,
+            resolved,
+            local,
+            tls: shared.tls_connector.clone(),
+            alpn: shared.http2.then_some("h2"),
+        };
+        let stream = target.connect(start_port).await?;
+        let options = options.clone();
+        match options.version {
+            HttpVersion::Http1 => Box::pin(sender_task_http(
+                id, stream, target, options, payloads, control, stats_tx,
+            )),
+            HttpVersion::Http2 => Box::pin(sender_task_http2(
+                id, stream, target, options, payloads, control, stats_tx,
+            )),
+        }
+    } else if let Some(options) = &shared.websocket {
+        let target = StreamTarget {
+            destination,
+            resolved,
+            local,
+            tls: shared.tls_connector.clone(),
+            alpn: None,
+        };
+        let stream = target.connect(start_port).await?;
+        let options = options.clone();
+        Box::pin(sender_task_websocket(
+            id, stream, target, options, payloads, control, stats_tx,
+        ))
+    } else {
+        match &destination {
+            #[cfg(unix)]
+            Destination::Unix(path) => {
+                if shared.udp {
+                    let socket = setup_unix_datagram(path).await?;
+                    Box::pin(sender_task_unix_datagram(id, socket, payloads, control, stats_tx))
+                } else {
+                    let stream = setup_unix_stream(path).await?;
+                    Box::pin(sender_task_tcp(id, stream, payloads, control, stats_tx))
+                }
+            }
+            Destination::Inet(server_addr) => {
+                connect_inet(shared, id, *server_addr, &local, payloads, control, stats_tx).await?
+            }
+        }
+    };
+    Ok(sender)
 }
 
-async fn setup_tcp_stream(addr: SocketAddr, port: usize) -> Box<TcpStream> {
-    let local_addr = ("0.0.0.0:".to_owned() + &port.to_string()).parse().unwrap();
-    let socket = TcpSocket::new_v4().unwrap();
-    socket.bind(local_addr).unwrap();
-    Box::new(socket.connect(addr).await.unwrap())
+async fn connect_inet(
+    shared: &ClientShared,
+    id: usize,
+    server_addr: SocketAddr,
+    local: &LocalEndpoint,
+    payloads: PayloadSource,
+    control: ClientControl,
+    stats_tx: StatsSender,
+) -> io::Result<Sender> {
+    let sender: Sender = if let Some((options, config)) = &shared.quic {
+        let (connection, handshake) = setup_quic_connection(local, server_addr, config.clone()).await?;
+        let _ = stats_tx.send(StatPacket::Handshake(handshake)).await;
+        Box::pin(sender_task_quic(
+            id,
+            connection,
+            options.clone(),
+            payloads,
+            control,
+            stats_tx,
+        ))
+    } else if shared.use_tls {
+        if shared.udp {
+            let session =
+                setup_dtls_session(local, server_addr, shared.ca_file.clone().unwrap()).await?;
+            Box::pin(sender_task_dtls(id, session, payloads, control, stats_tx))
+        } else {
+            let stream =
+                setup_tls_stream(local, server_addr, shared.tls_connector.as_ref().unwrap())
+                    .await
+                    .map(Box::new)?;
+            Box::pin(sender_task_tcp(id, stream, payloads, control, stats_tx))
+        }
+    } else if shared.udp {
+        let socket = setup_udp_socket(server_addr, local).await?;
+        if let Some(uring) = &shared.uring {
+            let socket = UringSocket::Udp(socket.into_std()?);
+            let done = uring.register(id, socket, payloads, control, stats_tx)?;
+            Box::pin(async move {
+                let _ = done.await;
+            })
+        } else {
+            let batcher = UdpBatcher::new(shared.batch.mode, shared.batch.size);
+            Box::pin(sender_task_udp(
+                id,
+                socket,
+                payloads,
+                batcher,
+                control,
+                stats_tx,
+                shared.sequence,
+            ))
+        }
+    } else {
+        let stream = setup_tcp_stream(server_addr, local).await?;
+        if let Some(uring) = &shared.uring {
+            let stream = UringSocket::Tcp(stream.into_std()?);
+            let done = uring.register(id, stream, payloads, control, stats_tx)?;
+            Box::pin(async move {
+                let _ = done.await;
+            })
+        } else {
+            Box::pin(sender_task_tcp(id, stream, payloads, control, stats_tx))
+        }
+    };
+    Ok(sender)
 }
 
-async fn setup_dtls_session(port: usize, addr: SocketAddr, ca_file: String) -> DtlsSession {
-    let mut ctx = SslContext::builder(SslMethod::dtls()).unwrap();
-    ctx.set_ca_file(ca_file).unwrap();
-    let socket = UdpSocket::bind("0.0.0.0:".to_owned() + &port.to_string())
-        .await
-        .unwrap();
-    let client = Client::new(socket);
-    let session = client.connect(addr, Some(ctx.build())).await.unwrap();
-    DtlsSession::new(client, session)
+/// Resolves on the first Ctrl+C, a second one terminates the process.
+async fn shutdown_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+    info!("Received Ctrl+C, shutting down gracefully...");
+    spawn(async {
+        let _ = tokio::signal::ctrl_c().await;
+        std::process::exit(130);
+    });
 }
 
-async fn setup_tls_stream(
-    port: usize,
+async fn setup_udp_socket(addr: SocketAddr, local: &LocalEndpoint) -> io::Result<UdpSocket> {
+    let socket = UdpSocket::from_std(local.udp_socket(&addr)?)?;
+    socket.connect(addr).await?;
+    Ok(socket)
+}
+
+async fn setup_tcp_stream(addr: SocketAddr, local: &LocalEndpoint) -> io::Result<Box<TcpStream>> {
+    let socket = local.tcp_socket(&addr)?;
+    Ok(Box::new(socket.connect(addr).await?))
+}
+
+#[cfg(unix)]
+async fn setup_unix_stream(path: &Path) -> io::Result<Box<UnixStream>> {
+    Ok(Box::new(UnixStream::connect(path).await?))
+}
+
+#[cfg(unix)]
+async fn setup_unix_datagram(path: &Path) -> io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(path)?;
+    Ok(socket)
+}
+
+async fn setup_dtls_session(
+    local: &LocalEndpoint,
     addr: SocketAddr,
     ca_file: String,
-) -> Box<dyn AsyncWrite + Unpin + Send> {
-    let pem = fs::read(Path::new(&ca_file)).unwrap();
-    let cert = Certificate::from_pem(&pem).unwrap();
+) -> io::Result<DtlsSession> {
+    let mut ctx = SslContext::builder(SslMethod::dtls())?;
+    ctx.set_ca_file(ca_file)?;
+    let socket = UdpSocket::from_std(local.udp_socket(&addr)?)?;
+    let client = Client::new(socket);
+    let session = client.connect(addr, Some(ctx.build())).await?;
+    Ok(DtlsSession::new(client, session))
+}
+
+async fn setup_quic_connection(
+    local: &LocalEndpoint,
+    addr: SocketAddr,
+    config: quinn::ClientConfig,
+) -> io::Result<(quinn::Connection, Duration)> {
+    let endpoint = Endpoint::new(
+        EndpointConfig::default(),
+        None,
+        local.udp_socket(&addr)?,
+        Arc::new(TokioRuntime),
+    )?;
+    let start_time = Instant::now();
+    let connection = endpoint
+        .connect_with(config, addr, &addr.ip().to_string())
+        .map_err(io::Error::other)?
+        .await
+        .map_err(io::Error::other)?;
+    Ok((connection, start_time.elapsed()))
+}
+
+/// Builds a connector trusting the certificate in `ca_file`.
+pub(crate) fn setup_tls_connector(
+    ca_file: &str,
+    alpn: &[String],
+) -> Result<tokio_native_tls::TlsConnector, String> {
+    let ca_err = |err: &dyn fmt::Display| format!("Failed to load CA file {ca_file}: {err}");
+    let pem = fs::read(Path::new(ca_file)).map_err(|err| ca_err(&err))?;
+    let cert = Certificate::from_pem(&pem).map_err(|err| ca_err(&err))?;
+    let alpn: Vec<&str> = alpn.iter().map(String::as_str).collect();
     let connector = TlsConnector::builder()
         .add_root_certificate(cert)
         .danger_accept_invalid_hostnames(true)
+        .request_alpns(&alpn)
         .build()
-        .unwrap();
-    let connector = tokio_native_tls::TlsConnector::from(connector);
-    let tcp_stream = setup_tcp_stream(addr, port).await;
-    Box::new(
-        connector
-            .connect(addr.ip().to_string().as_str(), tcp_stream)
-            .await
-            .unwrap(),
-    )
+        .map_err(|err| format!("Failed to set up TLS: {err}"))?;
+    Ok(tokio_native_tls::TlsConnector::from(connector))
+}
+
+async fn setup_tls_stream(
+    local: &LocalEndpoint,
+    addr: SocketAddr,
+    connector: &tokio_native_tls::TlsConnector,
+) -> io::Result<TlsStream<Box<TcpStream>>> {
+    let tcp_stream = setup_tcp_stream(addr, local).await?;
+    connector
+        .connect(addr.ip().to_string().as_str(), tcp_stream)
+        .await
+        .map_err(io::Error::other)
 }
 
+/// Byte stream the stream-oriented senders write to and, for request/response
+/// protocols, read from.
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for T {}
 
+/// Everything needed to open a new stream towards the destination, used by
+/// senders that have to reconnect when the server closes the connection.
+#[derive(Clone)]
+pub struct StreamTarget {
+    destination: Destination,
+    local: LocalEndpoint,
+    /// Hostname followed across DNS changes, new connections pick from its
+    /// current addresses.
+    resolved: Option<ResolvedHost>,
+    tls: Option<tokio_native_tls::TlsConnector>,
+    /// ALPN protocol the server must select, if any.
+    alpn: Option<&'static str>,
+}
+
+impl StreamTarget {
+    pub async fn connect(&self, port: usize) -> io::Result<Box<dyn AsyncStream>> {
+        let destination = match &self.resolved {
+            Some(resolved) => &Destination::Inet(resolved.pick()),
+            None => &self.destination,
+        };
+        let local = self.local.with_port(port);
+        match (destination, &self.tls) {
+            (Destination::Inet(addr), Some(connector)) => {
+                let stream = setup_tls_stream(&local, *addr, connector).await?;
+                if let Some(alpn) = self.alpn {
+                    let negotiated = stream.get_ref().negotiated_alpn().map_err(io::Error::other)?;
+                    if negotiated.as_deref() != Some(alpn.as_bytes()) {
+                        return Err(io::Error::other(format!("server did not negotiate {alpn}")));
+                    }
+                }
+                Ok(Box::new(stream))
+            }
+            (Destination::Inet(addr), None) => Ok(setup_tcp_stream(*addr, &local).await?),
+            #[cfg(unix)]
+            (Destination::Unix(path), _) => Ok(Box::new(UnixStream::connect(path).await?)),
+        }
+    }
+
+    pub fn scheme(&self) -> &'static str {
+        if self.tls.is_some() {
+            "https"
+        } else {
+            "http"
+        }
+    }
+
+    /// Value for the HTTP `Host` header.
+    pub fn host(&self) -> String {
+        match &self.destination {
+            Destination::Inet(addr) => addr.to_string(),
+            #[cfg(unix)]
+            Destination::Unix(_) => "localhost".to_owned(),
+        }
+    }
+}
+
+// Built once from the command line or by the `LoadTestBuilder`
+#[allow(clippy::too_many_arguments)]
 #[derive(new)]
 pub struct Parameters {
-    server_addr: SocketAddr,
+    targets: Vec<Target>,       // Destinations the clients are spread across
+    assignment: Assignment,     // How clients are assigned to the targets
     rate: usize,
     connections: usize,
     payload_config: Option<PayloadConfig>,
     payload: String, // fallback when not using payload_config
-    start_port: usize,
+    source: SourceOptions,      // Source addresses, ports and socket options
     sleep: u64,
     connection_type: (bool, (bool, Option<String>)),
     max_packets: Option<usize>, // Maximum number of packets to send before quitting
     sequential_payload: bool,   // Use sequential payloads from file
     random_payload: bool,       // Use random payloads from file
+    batch: BatchOptions,        // Syscall batching of the UDP senders
+    backend: Backend,           // I/O backend driving the plain UDP/TCP senders
+    quic: Option<QuicOptions>,  // Send over QUIC instead of TCP/UDP
+    alpn: Vec<String>,          // ALPN protocols offered during the handshake
+    http: Option<HttpOptions>,  // Send HTTP/1.1 or HTTP/2 requests over TCP/TLS
+    websocket: Option<WsOptions>, // Send WebSocket frames over TCP/TLS
+    messages: MessageOptions,   // Stream framing and datagram sequence headers
+    dns: DnsOptions,            // Spread over and re-resolve hostname addresses
+    connect_parallel: usize,    // Connection setups in flight, 1 connects clients one by one
+    connect_rate: Option<u32>,  // Connections opened per second
+    duration: Option<Duration>, // Stop the run after this long
+    control_listen: Option<SocketAddr>, // Address of the HTTP control API
+    stats: StatsOptions,        // Period, label and detail of the statistics
+}
+
+/// Where the simulated clients connect to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination {
+    Inet(SocketAddr),
+    /// Unix domain socket path, used as `SOCK_DGRAM` with `--udp` and `SOCK_STREAM` otherwise.
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FromStr for Destination {
+    type Err = String;
+
+    /// Parses `IP:PORT`, `HOST:PORT` or `unix:PATH`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            #[cfg(unix)]
+            Some(path) => Ok(Destination::Unix(path.into())),
+            #[cfg(not(unix))]
+            Some(_) => Err("Unix sockets are not supported on this platform".to_owned()),
+            None => s
+                .to_socket_addrs()
+                .map_err(|err| format!("invalid destination {s}: {err}"))?
+                .next()
+                .map(Destination::Inet)
+                .ok_or_else(|| format!("destination {s} did not resolve")),
+        }
+    }
+}
+
+impl From<SocketAddr> for Destination {
+    fn from(addr: SocketAddr) -> Self {
+        Destination::Inet(addr)
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Destination::Inet(addr) => write!(f, "{addr}"),
+            #[cfg(unix)]
+            Destination::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Tokio,
+    IoUring,
+}
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tokio" => Ok(Self::Tokio),
+            "io-uring" => Ok(Self::IoUring),
+            _ => Err(format!("unknown backend: {s}")),
+        }
+    }
 }
 
 #[derive(new)]
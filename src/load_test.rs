@@ -0,0 +1,409 @@
+//! Typed entry point for running load tests from Rust code, such as
+//! integration tests, without going through the command line.
+
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::{net::SocketAddr, time::Duration};
+
+use tokio::{spawn, task::JoinHandle};
+
+use crate::{
+    batch::{BatchMode, BatchOptions},
+    bind::{BindOptions, SourceOptions},
+    control::Controller,
+    dns::DnsOptions,
+    framing::{Framing, MessageOptions},
+    http::HttpOptions,
+    payload::PayloadConfig,
+    ports::{PortOptions, PortStrategy},
+    quic::{self, QuicOptions},
+    run_test, setup_tls_connector,
+    targets::{Assignment, Target},
+    websocket::WsOptions,
+    Backend, Destination, Parameters, RunReport, StatsOptions,
+};
+
+/// How the clients talk to the targets.
+#[derive(Debug, Clone, Default)]
+pub enum Transport {
+    #[default]
+    Tcp,
+    Udp,
+    Tls,
+    Dtls,
+    Quic(QuicOptions),
+}
+
+/// A configured test, ready to run.
+pub struct LoadTest {
+    params: Parameters,
+}
+
+impl LoadTest {
+    pub fn builder() -> LoadTestBuilder {
+        LoadTestBuilder::default()
+    }
+
+    /// Starts the test in the background.
+    pub fn start(self) -> RunHandle {
+        let (controller, control) = Controller::new(self.params.rate);
+        let task = spawn(run_test(self.params, controller.clone(), control));
+        RunHandle { controller, task }
+    }
+
+    /// Runs the test until it completes on its own, after its duration or
+    /// maximum packets.
+    pub async fn run(self) -> Result<RunReport, String> {
+        self.start().wait().await
+    }
+}
+
+impl From<Parameters> for LoadTest {
+    fn from(params: Parameters) -> Self {
+        Self { params }
+    }
+}
+
+impl From<LoadTest> for Parameters {
+    fn from(test: LoadTest) -> Self {
+        test.params
+    }
+}
+
+/// A test running in the background.
+pub struct RunHandle {
+    controller: Controller,
+    task: JoinHandle<Result<RunReport, String>>,
+}
+
+impl RunHandle {
+    /// Asks the clients to stop, [`RunHandle::wait`] then returns the report.
+    pub fn stop(&self) {
+        self.controller.stop();
+    }
+
+    /// Handle changing the rate, connections or payloads while running.
+    pub fn controller(&self) -> Controller {
+        self.controller.clone()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.task.is_finished()
+    }
+
+    /// Waits for the end of the test.
+    pub async fn wait(self) -> Result<RunReport, String> {
+        self.task
+            .await
+            .map_err(|err| format!("load test task failed: {err}"))?
+    }
+}
+
+/// Builds a [`LoadTest`], unset options take the command line defaults
+/// except for the source ports, which are ephemeral.
+pub struct LoadTestBuilder {
+    params: Parameters,
+    transport: Transport,
+}
+
+impl Default for LoadTestBuilder {
+    fn default() -> Self {
+        let ports = PortOptions {
+            strategy: PortStrategy::Ephemeral,
+            start: 8000,
+            end: u16::MAX,
+            list: Vec::new(),
+        };
+        let params = Parameters::new(
+            Vec::new(),
+            Assignment::default(),
+            1,
+            1,
+            None,
+            "test".to_owned(),
+            SourceOptions {
+                bind: BindOptions::default(),
+                ports,
+            },
+            50,
+            (false, (false, None)),
+            None,
+            false,
+            false,
+            BatchOptions::default(),
+            Backend::Tokio,
+            None,
+            Vec::new(),
+            None,
+            None,
+            MessageOptions::default(),
+            DnsOptions::default(),
+            1,
+            None,
+            None,
+            None,
+            StatsOptions::default(),
+        );
+        Self {
+            params,
+            transport: Transport::default(),
+        }
+    }
+}
+
+impl LoadTestBuilder {
+    /// Adds a destination, may be called several times.
+    pub fn target(self, destination: impl Into<Destination>) -> Self {
+        self.weighted_target(destination, 1)
+    }
+
+    /// Adds a destination receiving `weight` shares of the clients with the
+    /// weighted and hash assignments.
+    pub fn weighted_target(mut self, destination: impl Into<Destination>, weight: usize) -> Self {
+        self.params.targets.push(Target {
+            destination: destination.into(),
+            weight,
+            host: None,
+        });
+        self
+    }
+
+    pub fn tcp(self, destination: impl Into<Destination>) -> Self {
+        self.transport(Transport::Tcp).target(destination)
+    }
+
+    pub fn udp(self, destination: impl Into<Destination>) -> Self {
+        self.transport(Transport::Udp).target(destination)
+    }
+
+    pub fn tls(self, destination: impl Into<Destination>) -> Self {
+        self.transport(Transport::Tls).target(destination)
+    }
+
+    pub fn dtls(self, destination: impl Into<Destination>) -> Self {
+        self.transport(Transport::Dtls).target(destination)
+    }
+
+    /// QUIC with a single bidirectional stream per connection.
+    pub fn quic(self, destination: impl Into<Destination>) -> Self {
+        self.transport(Transport::Quic(QuicOptions::default()))
+            .target(destination)
+    }
+
+    /// Unix stream socket, or datagram socket with [`Transport::Udp`].
+    #[cfg(unix)]
+    pub fn unix(self, path: impl Into<PathBuf>) -> Self {
+        self.target(Destination::Unix(path.into()))
+    }
+
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = transport;
+        self
+    }
+
+    pub fn assignment(mut self, assignment: Assignment) -> Self {
+        self.params.assignment = assignment;
+        self
+    }
+
+    /// PEM file validating the server certificate of the secure transports.
+    pub fn ca(mut self, path: impl Into<String>) -> Self {
+        self.params.connection_type.1 .1 = Some(path.into());
+        self
+    }
+
+    /// Adds an ALPN protocol to offer.
+    pub fn alpn(mut self, protocol: impl Into<String>) -> Self {
+        self.params.alpn.push(protocol.into());
+        self
+    }
+
+    /// Payloads sent per second by each client.
+    pub fn rate(mut self, rate: usize) -> Self {
+        self.params.rate = rate;
+        self
+    }
+
+    pub fn connections(mut self, connections: usize) -> Self {
+        self.params.connections = connections;
+        self
+    }
+
+    pub fn payload(mut self, payload: impl Into<String>) -> Self {
+        self.params.payload = payload.into();
+        self
+    }
+
+    /// Payloads to pick from instead of the single payload.
+    pub fn payloads(mut self, config: PayloadConfig) -> Self {
+        self.params.payload_config = Some(config);
+        self
+    }
+
+    /// Cycle through the payloads in order.
+    pub fn sequential_payloads(mut self) -> Self {
+        self.params.sequential_payload = true;
+        self
+    }
+
+    /// Pick a random payload every time.
+    pub fn random_payloads(mut self) -> Self {
+        self.params.random_payload = true;
+        self
+    }
+
+    pub fn framing(mut self, framing: Framing) -> Self {
+        self.params.messages.framing = framing;
+        self
+    }
+
+    /// Stamp UDP/DTLS datagrams with a sequence header.
+    pub fn sequence(mut self) -> Self {
+        self.params.messages.sequence = true;
+        self
+    }
+
+    pub fn http(mut self, options: HttpOptions) -> Self {
+        self.params.http = Some(options);
+        self
+    }
+
+    pub fn websocket(mut self, options: WsOptions) -> Self {
+        self.params.websocket = Some(options);
+        self
+    }
+
+    pub fn ports(mut self, ports: PortOptions) -> Self {
+        self.params.source.ports = ports;
+        self
+    }
+
+    /// Pause between consecutive connections when connecting one by one.
+    pub fn connect_delay(mut self, delay: Duration) -> Self {
+        self.params.sleep = delay.as_millis() as u64;
+        self
+    }
+
+    /// Connection setups in flight at once.
+    pub fn connect_parallel(mut self, parallel: usize) -> Self {
+        self.params.connect_parallel = parallel;
+        self
+    }
+
+    /// New connections opened per second.
+    pub fn connect_rate(mut self, rate: u32) -> Self {
+        self.params.connect_rate = Some(rate);
+        self
+    }
+
+    pub fn max_packets(mut self, max_packets: usize) -> Self {
+        self.params.max_packets = Some(max_packets);
+        self
+    }
+
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.params.duration = Some(duration);
+        self
+    }
+
+    pub fn udp_batch(mut self, mode: BatchMode, batch_size: usize) -> Self {
+        self.params.batch = BatchOptions {
+            mode,
+            size: batch_size,
+        };
+        self
+    }
+
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.params.backend = backend;
+        self
+    }
+
+    pub fn dns(mut self, dns: DnsOptions) -> Self {
+        self.params.dns = dns;
+        self
+    }
+
+    pub fn bind(mut self, bind: BindOptions) -> Self {
+        self.params.source.bind = bind;
+        self
+    }
+
+    /// Prefix of the statistics lines of this test.
+    pub fn label(mut self, label: impl Into<String>) -> Self {
+        self.params.stats.label = Some(label.into());
+        self
+    }
+
+    /// Serves the HTTP control API on `listen` while the test runs.
+    pub fn control_listen(mut self, listen: SocketAddr) -> Self {
+        self.params.control_listen = Some(listen);
+        self
+    }
+
+    /// Period of the statistics lines, 10 seconds by default.
+    pub fn stats_interval(mut self, interval: Duration) -> Self {
+        self.params.stats.interval = interval;
+        self
+    }
+
+    /// Logs the statistics of every connection with each statistics line.
+    /// They are in the final report either way.
+    pub fn connection_stats(mut self) -> Self {
+        self.params.stats.per_connection = true;
+        self
+    }
+
+    /// Shows the interactive terminal dashboard instead of the statistics
+    /// lines, the log is silenced until the test ends.
+    pub fn tui(mut self) -> Self {
+        self.params.stats.tui = true;
+        self
+    }
+
+    pub fn build(mut self) -> Result<LoadTest, String> {
+        if self.params.targets.is_empty() {
+            return Err("no target to connect to".to_owned());
+        }
+        if self.params.targets.iter().any(|target| target.weight == 0) {
+            return Err("target weights must be > 0".to_owned());
+        }
+        if self.params.connections == 0 || self.params.rate == 0 {
+            return Err("connections and rate must be > 0".to_owned());
+        }
+        if self.params.stats.interval.is_zero() {
+            return Err("stats interval must be > 0".to_owned());
+        }
+        let (udp, tls) = match &self.transport {
+            Transport::Tcp | Transport::Quic(_) => (false, false),
+            Transport::Udp => (true, false),
+            Transport::Tls => (false, true),
+            Transport::Dtls => (true, true),
+        };
+        let secure = tls || matches!(self.transport, Transport::Quic(_));
+        if secure && self.params.connection_type.1 .1.is_none() {
+            return Err("TLS, DTLS and QUIC require a CA file".to_owned());
+        }
+        // A bad CA file fails here rather than once the test runs
+        if let Some(ca_file) = &self.params.connection_type.1 .1 {
+            match &self.transport {
+                Transport::Tls | Transport::Dtls => {
+                    setup_tls_connector(ca_file, &[])?;
+                }
+                Transport::Quic(_) => {
+                    quic::client_config(ca_file, &[])?;
+                }
+                Transport::Tcp | Transport::Udp => {}
+            }
+        }
+        self.params.connection_type.0 = udp;
+        self.params.connection_type.1 .0 = tls;
+        self.params.quic = match self.transport {
+            Transport::Quic(options) => Some(options),
+            _ => None,
+        };
+        Ok(LoadTest {
+            params: self.params,
+        })
+    }
+}
@@ -1,58 +1,608 @@
-use std::net::ToSocketAddrs;
+use std::{
+    ffi::OsString,
+    net::{SocketAddr, ToSocketAddrs},
+    process,
+    time::{Duration, Instant},
+};
 
 use byte_unit::Byte;
-use clap::{Arg, ArgMatches, Command};
-use crab_net::{manager, Parameters, payload::PayloadConfig};
-use log::{info, warn, LevelFilter};
+use clap::{error::ErrorKind, parser::ValueSource, Arg, ArgMatches, Command};
+use crab_net::{
+    batch::{BatchMode, BatchOptions},
+    bind::{parse_bind_list, BindOptions, Keepalive, SocketOptions, SourceOptions},
+    distributed::{agent, coordinate},
+    dns::DnsOptions,
+    framing::{Framing, MessageOptions},
+    http::{HttpOptions, HttpVersion},
+    manager,
+    payload::PayloadConfig,
+    ports::{parse_port_list, PortOptions, PortStrategy},
+    quic::{QuicMode, QuicOptions},
+    scenario::{Scenario, ScenarioFormat, ScenarioValue},
+    server::{serve, CertFiles, ServeParameters},
+    targets::{load_targets, Assignment, Target},
+    websocket::WsOptions,
+    Backend, Parameters, StatsOptions,
+};
+use log::{error, info, warn, LevelFilter};
 use mimalloc::MiMalloc;
 use simple_logger::SimpleLogger;
-use tokio::runtime::{Builder, Runtime};
-use ctrlc;
+use tokio::{
+    runtime::{Builder, Runtime},
+    spawn,
+};
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
+// Options that cannot be set from a scenario file
+const NOT_IN_SCENARIO: [&str; 4] = ["scenario", "dump-config", "help", "version"];
+
 fn main() {
     SimpleLogger::new()
         .with_level(LevelFilter::Info)
         .init()
         .unwrap();
 
-    let cli = build_cli();
-    let rt = build_runtime(&cli);
-    
-    // Create a channel for graceful shutdown
-    let (shutdown_tx, _shutdown_rx) = std::sync::mpsc::channel();
+    let phases = build_cli();
+    let cli = &phases[0].groups[0].matches;
+    let rt = build_runtime(cli);
     
-    // Setup Ctrl+C handler to terminate the process
-    ctrlc::set_handler(move || {
-        info!("Received Ctrl+C, shutting down gracefully...");
-        let _ = shutdown_tx.send(());
-        // Give a short time for the message to be logged
-        std::thread::sleep(std::time::Duration::from_millis(100));
-        std::process::exit(0);
-    }).expect("Error setting Ctrl+C handler");
+    if let Some(("serve", matches)) = cli.subcommand() {
+        let params = extract_serve_parameters(matches);
+        rt.block_on(serve(params));
+        return;
+    }
+    if let Some(("agent", matches)) = cli.subcommand() {
+        let listen = *matches.get_one::<SocketAddr>("listen").unwrap();
+        rt.block_on(agent(listen, agent_phases));
+        return;
+    }
+    if let Some(("coordinator", matches)) = cli.subcommand() {
+        let agents = coordinator_scenarios(matches);
+        match rt.block_on(coordinate(agents)) {
+            Ok(report) => info!("Total packets sent: {}", report.packets_sent),
+            Err(err) => {
+                error!("{err}");
+                process::exit(1);
+            }
+        }
+        return;
+    }
     
-    // Run the manager and get total packets sent
-    let total_packets = rt.block_on(async {
-        let params = extract_parameters(cli);
-        manager(params).await
-    });
+    // Run the managers and get total packets sent
+    let total_packets = rt.block_on(run_phases(phases));
     
     // Print final statistics
     info!("Total packets sent: {}", total_packets);
 }
 
-fn build_cli() -> ArgMatches {
+/// Client groups started together.
+struct Phase {
+    name: Option<String>,
+    groups: Vec<Group>,
+}
+
+struct Group {
+    name: Option<String>,
+    matches: ArgMatches,
+}
+
+/// Runs the phases one after the other and the groups of a phase side by
+/// side, returning the total packets sent.
+async fn run_phases(phases: Vec<Phase>) -> usize {
+    // The managers stop on Ctrl+C by themselves, this skips the phases left
+    let interrupted = spawn(tokio::signal::ctrl_c());
+    let count = phases.len();
+    let mut total_packets = 0;
+    for (idx, phase) in phases.into_iter().enumerate() {
+        if idx > 0 && interrupted.is_finished() {
+            info!("Interrupted, skipping the remaining phases");
+            break;
+        }
+        if let Some(name) = &phase.name {
+            info!("Starting phase {name} ({}/{count})", idx + 1);
+        }
+        let started = Instant::now();
+        let groups: Vec<_> = phase
+            .groups
+            .into_iter()
+            .map(|group| {
+                let label = group_label(phase.name.as_deref(), group.name.as_deref());
+                if let Some(label) = &label {
+                    info!("Group {label}:");
+                }
+                let params = extract_parameters(group.matches, label.clone());
+                (label, spawn(manager(params)))
+            })
+            .collect();
+        let mut summary = Vec::new();
+        for (label, handle) in groups {
+            let packets = handle.await.unwrap();
+            total_packets += packets;
+            if let Some(label) = label {
+                summary.push(format!("{label}: {packets} packets"));
+            }
+        }
+        let elapsed = started.elapsed();
+        match &phase.name {
+            Some(name) => info!("Phase {name} finished after {elapsed:.1?}: {}", summary.join(", ")),
+            None if !summary.is_empty() => info!("Groups finished after {elapsed:.1?}: {}", summary.join(", ")),
+            None => {}
+        }
+    }
+    total_packets
+}
+
+/// Phase and group names joined, prefixed to the statistics of the group.
+fn group_label(phase: Option<&str>, group: Option<&str>) -> Option<String> {
+    let label = [phase, group]
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>()
+        .join("/");
+    (!label.is_empty()).then_some(label)
+}
+
+/// Parses the command line, filling in the options of `--scenario` that are
+/// not given on the command line itself. Returns one phase with a single
+/// group unless the scenario defines phases or groups.
+fn build_cli() -> Vec<Phase> {
+    let mut command = build_command();
+    command.build();
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let cli = command
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(&args)
+        .unwrap_or_default();
+
+    // --help and --version leave the pre-parse without any argument
+    let phases = match cli.try_get_one::<String>("scenario").ok().flatten() {
+        Some(path) if cli.subcommand().is_none() => {
+            match Scenario::from_file(path)
+                .and_then(|scenario| scenario_phases(&command, &cli, &args, &scenario, path))
+            {
+                Ok(phases) => phases,
+                Err(err) => command.error(ErrorKind::InvalidValue, err).exit(),
+            }
+        }
+        _ => vec![Phase {
+            name: None,
+            groups: vec![Group {
+                name: None,
+                matches: command.clone().get_matches_from(&args),
+            }],
+        }],
+    };
+
+    if let Some(format) = phases[0].groups[0].matches.get_one::<String>("dump-config") {
+        let format: ScenarioFormat = format.parse().unwrap();
+        let group_dump = |group: &Group| Scenario {
+            name: group.name.clone(),
+            ..dump_config(&command, &group.matches)
+        };
+        let phase_dump = |phase: &Phase| match &phase.groups[..] {
+            [group] if group.name.is_none() => Scenario {
+                name: phase.name.clone(),
+                ..group_dump(group)
+            },
+            groups => Scenario {
+                name: phase.name.clone(),
+                groups: groups.iter().map(group_dump).collect(),
+                ..Default::default()
+            },
+        };
+        let dump = match &phases[..] {
+            [phase] if phase.name.is_none() => phase_dump(phase),
+            phases => Scenario {
+                phases: phases.iter().map(phase_dump).collect(),
+                ..Default::default()
+            },
+        };
+        match dump.to_string(format) {
+            Ok(dump) => print!("{dump}"),
+            Err(err) => command.error(ErrorKind::Io, err).exit(),
+        }
+        process::exit(0);
+    }
+    phases
+}
+
+/// Phases and groups of the scenario read from `path`, the options of every
+/// group parsed as if they were given on the command line.
+fn scenario_phases(
+    command: &Command,
+    cli: &ArgMatches,
+    args: &[OsString],
+    scenario: &Scenario,
+    path: &str,
+) -> Result<Vec<Phase>, String> {
+    let staged = scenario.is_staged();
+    let phases = match (&scenario.phases[..], &scenario.groups[..]) {
+        ([], []) => vec![Scenario::default()],
+        ([], groups) => vec![Scenario {
+            groups: groups.to_vec(),
+            ..Default::default()
+        }],
+        (phases, _) => phases
+            .iter()
+            .enumerate()
+            .map(|(idx, phase)| Scenario {
+                name: Some(phase.name.clone().unwrap_or_else(|| format!("phase-{}", idx + 1))),
+                ..phase.clone()
+            })
+            .collect(),
+    };
+
+    let mut next_port = None;
+    let mut planned = Vec::new();
+    for phase in phases {
+        let phase_context = match &phase.name {
+            Some(name) => format!("scenario {path}, phase {name}"),
+            None => format!("scenario {path}"),
+        };
+        let phase_options = overlay(command, &scenario.options, &phase.options)
+            .map_err(|err| format!("{err} in {phase_context}"))?;
+        let groups = match phase.groups.is_empty() {
+            true => vec![Scenario::default()],
+            false => phase
+                .groups
+                .iter()
+                .enumerate()
+                .map(|(idx, group)| Scenario {
+                    name: Some(group.name.clone().unwrap_or_else(|| format!("group-{}", idx + 1))),
+                    ..group.clone()
+                })
+                .collect(),
+        };
+        let mut planned_groups = Vec::new();
+        for group in groups {
+            let context = match &group.name {
+                Some(name) => format!("{phase_context}, group {name}"),
+                None => phase_context.clone(),
+            };
+            let local = phase.options.iter().chain(&group.options);
+            if let Some((name, _)) = local
+                .clone()
+                .find(|(name, _)| ["workers", "control-listen"].contains(&name.as_str()))
+            {
+                return Err(format!("{name} applies to the whole run and is only allowed at the top level of {context}"));
+            }
+            let options = overlay(command, &phase_options, &group.options)
+                .map_err(|err| format!("{err} in {context}"))?;
+            let parse = |options: &[(String, ScenarioValue)]| {
+                scenario_args(command, cli, options)
+                    .map(|file_args| parse_scenario(command, args, &file_args, &context))
+                    .map_err(|err| format!("{err} in {context}"))
+            };
+            let mut matches = parse(&options)?;
+
+            // Groups not given ports of their own get consecutive ranges:
+            // concurrent groups cannot bind the same ports, and those of the
+            // previous phases may still be in TIME_WAIT
+            let own_port = cli.value_source("port") == Some(ValueSource::CommandLine)
+                || local.clone().any(|(name, _)| name == "port");
+            if staged && !own_port && matches.get_one::<String>("port-strategy").unwrap() == "range" {
+                let port = *matches.get_one::<u16>("port").unwrap() as usize;
+                let start = *next_port.get_or_insert(port);
+                if start > u16::MAX as usize {
+                    return Err(format!("no source ports left past 65535 for the range of {context}, lower --port or use --port-strategy ephemeral"));
+                }
+                if start != port {
+                    let port = [("port".to_owned(), ScenarioValue::Int(start as i64))];
+                    matches = parse(&overlay(command, &options, &port)?)?;
+                }
+                next_port = Some(start + *matches.get_one::<usize>("clients").unwrap());
+            }
+            planned_groups.push(Group {
+                name: group.name,
+                matches,
+            });
+        }
+        // Concurrent groups would all bind the same address
+        let control = |group: &Group| group.matches.get_one::<SocketAddr>("control-listen").is_some();
+        if planned_groups.len() > 1 && planned_groups.iter().any(control) {
+            return Err(format!("control-listen steers a single client group and cannot be used with the concurrent groups of {phase_context}"));
+        }
+        planned.push(Phase {
+            name: phase.name,
+            groups: planned_groups,
+        });
+    }
+    Ok(planned)
+}
+
+/// Parses the command line with the scenario arguments in front of it,
+/// exiting on invalid options.
+fn parse_scenario(command: &Command, args: &[OsString], file_args: &[String], context: &str) -> ArgMatches {
+    let (program, args) = args.split_first().unwrap();
+    let merged = std::iter::once(program.clone())
+        .chain(file_args.iter().map(OsString::from))
+        .chain(args.iter().cloned());
+    command.clone().try_get_matches_from(merged).unwrap_or_else(|err| {
+        if err.use_stderr() {
+            eprintln!("Invalid configuration in {context}:");
+        }
+        err.exit()
+    })
+}
+
+fn scenario_option<'a>(command: &'a Command, name: &str) -> Result<&'a Arg, String> {
+    command
+        .get_arguments()
+        .find(|arg| arg.get_long() == Some(name) && !NOT_IN_SCENARIO.contains(&name))
+        .ok_or_else(|| format!("unknown option '{name}', options are named as their --long flag"))
+}
+
+/// `base` with `overlay` on top, replacing the same and conflicting options.
+fn overlay(
+    command: &Command,
+    base: &[(String, ScenarioValue)],
+    overlay: &[(String, ScenarioValue)],
+) -> Result<Vec<(String, ScenarioValue)>, String> {
+    let mut merged = Vec::new();
+    for (name, value) in base {
+        let conflicts = command.get_arg_conflicts_with(scenario_option(command, name)?);
+        let replaced = overlay.iter().any(|(other, _)| {
+            other == name || conflicts.iter().any(|arg| arg.get_long() == Some(other))
+        });
+        if !replaced {
+            merged.push((name.clone(), value.clone()));
+        }
+    }
+    merged.extend(overlay.iter().cloned());
+    Ok(merged)
+}
+
+/// Arguments for scenario options, leaving out those given, or conflicting
+/// with one given, on the command line.
+fn scenario_args(
+    command: &Command,
+    cli: &ArgMatches,
+    options: &[(String, ScenarioValue)],
+) -> Result<Vec<String>, String> {
+    let given = |arg: &Arg| cli.value_source(arg.get_id().as_str()) == Some(ValueSource::CommandLine);
+    let mut args = Vec::new();
+    for (name, value) in options {
+        let arg = scenario_option(command, name)?;
+        if given(arg) || command.get_arg_conflicts_with(arg).into_iter().any(given) {
+            continue;
+        }
+        args.extend(value.to_args(name));
+    }
+    Ok(args)
+}
+
+/// Effective options of this invocation, defaults included, as a scenario.
+fn dump_config(command: &Command, matches: &ArgMatches) -> Scenario {
+    let options = command
+        .get_arguments()
+        .filter(|arg| !NOT_IN_SCENARIO.contains(&arg.get_id().as_str()))
+        .filter_map(|arg| {
+            let long = arg.get_long()?;
+            let raw: Vec<String> = matches
+                .get_raw(arg.get_id().as_str())?
+                .map(|value| value.to_string_lossy().into_owned())
+                .collect();
+            let value = if arg.get_num_args().is_some_and(|n| n.max_values() == 0) {
+                ScenarioValue::Flag(raw[0] == "true")
+            } else if let clap::ArgAction::Append = arg.get_action() {
+                ScenarioValue::List(raw.into_iter().map(ScenarioValue::Text).collect())
+            } else {
+                match raw[0].parse() {
+                    Ok(int) => ScenarioValue::Int(int),
+                    Err(_) => ScenarioValue::Text(raw[0].clone()),
+                }
+            };
+            Some((long.to_owned(), value))
+        })
+        .collect();
+    Scenario {
+        options,
+        ..Default::default()
+    }
+}
+
+fn build_command() -> Command {
     Command::new("crab-net")
         .version(option_env!("CARGO_PKG_VERSION").unwrap_or(""))
         .about("Simple stress test for servers")
+        .subcommand_negates_reqs(true)
+        .subcommand(
+            Command::new("serve")
+                .about("Run a sink/echo server reporting the received traffic")
+                .arg(
+                    Arg::new("listen")
+                        .long("listen")
+                        .help("Address to listen on as IP:PORT")
+                        .value_parser(parse_socket_addr)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("udp")
+                        .long("udp")
+                        .help("Receive UDP datagrams instead of TCP streams")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("tls")
+                        .long("tls")
+                        .help("Serve TLS, or DTLS together with --udp")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("echo")
+                        .long("echo")
+                        .help("Send received data back to the client")
+                        .action(clap::ArgAction::SetTrue),
+                )
+                .arg(
+                    Arg::new("cert")
+                        .long("cert")
+                        .help("PEM certificate chain for TLS/DTLS")
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("key")
+                        .long("key")
+                        .help("PEM (PKCS#8) private key for TLS/DTLS")
+                        .value_parser(clap::value_parser!(String)),
+                )
+                .arg(
+                    Arg::new("framing")
+                        .long("framing")
+                        .help("Count TCP messages using the client framing instead of reads")
+                        .default_value("none")
+                        .value_parser(|s: &str| s.parse::<Framing>().map(|_| s.to_owned())),
+                )
+                .arg(stats_interval_arg()),
+        )
+        .subcommand(
+            Command::new("agent")
+                .about("Wait for a coordinator and run the scenarios it pushes")
+                .arg(
+                    Arg::new("listen")
+                        .long("listen")
+                        .help("Address to listen on for the coordinator as IP:PORT")
+                        .value_parser(parse_socket_addr)
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            Command::new("coordinator")
+                .about("Run a scenario on several agents at once and merge their statistics")
+                .arg(
+                    Arg::new("agent")
+                        .long("agent")
+                        .help("Agent address as IP:PORT, may be repeated")
+                        .value_parser(parse_socket_addr)
+                        .action(clap::ArgAction::Append)
+                        .required(true),
+                )
+                .arg(
+                    Arg::new("scenario")
+                        .long("scenario")
+                        .help("YAML or TOML scenario file run by every agent")
+                        .required(true),
+                ),
+        )
         .arg(
             Arg::new("addr")
                 .short('d')
                 .long("destination")
-                .help("Server address as IP:PORT")
-                .required(true),
+                .help("Server address as IP:PORT or unix:PATH, optionally followed by @WEIGHT, may be repeated")
+                .value_parser(|s: &str| s.parse::<Target>())
+                .action(clap::ArgAction::Append)
+                .required_unless_present("targets-file"),
+        )
+        .arg(
+            Arg::new("targets-file")
+                .long("targets-file")
+                .help("YAML file listing the destinations and their weights")
+                .value_parser(|s: &str| load_targets(s).map_err(|err| format!("failed to load {s}: {err}")))
+                .required(false),
+        )
+        .arg(
+            Arg::new("bind")
+                .long("bind")
+                .help("Source addresses or CIDR blocks to spread clients across, comma separated, may be repeated")
+                .value_parser(|s: &str| parse_bind_list(s).map(|_| s.to_owned()))
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("interface")
+                .long("interface")
+                .help("Bind client sockets to a network interface (Linux only)")
+                .value_parser(clap::value_parser!(String)),
+        )
+        .arg(
+            Arg::new("nodelay")
+                .long("nodelay")
+                .help("Set TCP_NODELAY on TCP/TLS connections")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("sndbuf")
+                .long("sndbuf")
+                .help("Socket send buffer size (SO_SNDBUF) in bytes")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("rcvbuf")
+                .long("rcvbuf")
+                .help("Socket receive buffer size (SO_RCVBUF) in bytes")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("tos")
+                .long("tos")
+                .help("IP TOS / IPv6 traffic class byte")
+                .value_parser(clap::value_parser!(u32).range(0..=255))
+                .conflicts_with("dscp"),
+        )
+        .arg(
+            Arg::new("dscp")
+                .long("dscp")
+                .help("DSCP code point, written to the upper six bits of the TOS byte")
+                .value_parser(clap::value_parser!(u32).range(0..=63)),
+        )
+        .arg(
+            Arg::new("ttl")
+                .long("ttl")
+                .help("IP TTL / IPv6 hop limit")
+                .value_parser(clap::value_parser!(u32).range(1..=255)),
+        )
+        .arg(
+            Arg::new("keepalive")
+                .long("keepalive")
+                .help("Enable TCP keepalive, probing after N idle seconds")
+                .value_parser(clap::value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("keepalive-interval")
+                .long("keepalive-interval")
+                .help("Seconds between TCP keepalive probes")
+                .value_parser(clap::value_parser!(u64).range(1..))
+                .requires("keepalive"),
+        )
+        .arg(
+            Arg::new("keepalive-retries")
+                .long("keepalive-retries")
+                .help("Unanswered TCP keepalive probes before the connection is dropped")
+                .value_parser(clap::value_parser!(u32).range(1..))
+                .requires("keepalive"),
+        )
+        .arg(
+            Arg::new("linger")
+                .long("linger")
+                .help("SO_LINGER timeout in seconds, 0 resets TCP connections on close")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("reuseport")
+                .long("reuseport")
+                .help("Set SO_REUSEADDR and SO_REUSEPORT on client sockets")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dns-spread")
+                .long("dns-spread")
+                .help("Spread clients across every address a hostname destination resolves to")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("dns-refresh")
+                .long("dns-refresh")
+                .help("Re-resolve hostname destinations every N seconds for new and reconnecting clients")
+                .value_parser(clap::value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("target-assignment")
+                .long("target-assignment")
+                .help("How clients are spread across destinations")
+                .value_parser(["round-robin", "weighted", "hash"])
+                .default_value("round-robin"),
         )
         .arg(
             Arg::new("clients")
@@ -71,6 +621,32 @@ fn build_cli() -> ArgMatches {
                 .value_parser(clap::value_parser!(usize))
                 .required(false),
         )
+        .arg(
+            Arg::new("duration")
+                .long("duration")
+                .help("Stop the test after N seconds")
+                .value_parser(clap::value_parser!(u64).range(1..)),
+        )
+        .arg(
+            Arg::new("control-listen")
+                .long("control-listen")
+                .value_name("addr")
+                .help("Serve the HTTP control API on IP:PORT")
+                .value_parser(parse_socket_addr),
+        )
+        .arg(stats_interval_arg())
+        .arg(
+            Arg::new("connection-stats")
+                .long("connection-stats")
+                .help("Print the statistics of every connection with each statistics line")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("tui")
+                .long("tui")
+                .help("Show an interactive dashboard instead of the statistics lines")
+                .action(clap::ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("sequential-payload")
                 .long("sequential-payload")
@@ -118,13 +694,35 @@ fn build_cli() -> ArgMatches {
                 .long("port")
                 .help("Starting source port for clients")
                 .default_value("8000")
-                .value_parser(clap::value_parser!(usize)),
+                .value_parser(clap::value_parser!(u16).range(1..)),
+        )
+        .arg(
+            Arg::new("port-end")
+                .long("port-end")
+                .help("Last source port of the range and random strategies")
+                .default_value("65535")
+                .value_parser(clap::value_parser!(u16).range(1..)),
+        )
+        .arg(
+            Arg::new("port-strategy")
+                .long("port-strategy")
+                .help("How source ports are assigned to clients")
+                .value_parser(["ephemeral", "range", "random", "list"])
+                .default_value("range"),
+        )
+        .arg(
+            Arg::new("ports")
+                .long("ports")
+                .help("Source ports and FIRST-LAST ranges for the list strategy, comma separated")
+                .value_parser(|s: &str| parse_port_list(s).map(|_| s.to_owned()))
+                .required_if_eq("port-strategy", "list"),
         )
         .arg(
             Arg::new("workers")
                 .short('w')
                 .long("workers")
                 .help("Number of worker threads for the Tokio runtime [default: #CPU core]")
+                .global(true)
                 .value_parser(clap::value_parser!(usize)),
         )
         .arg(
@@ -135,6 +733,19 @@ fn build_cli() -> ArgMatches {
                 .default_value("50")
                 .value_parser(clap::value_parser!(u64)),
         )
+        .arg(
+            Arg::new("connect-parallel")
+                .long("connect-parallel")
+                .help("Connection setups (handshakes) in flight at once, 1 connects clients one by one with the -s delay")
+                .default_value("1")
+                .value_parser(clap::value_parser!(u32).range(1..)),
+        )
+        .arg(
+            Arg::new("connect-rate")
+                .long("connect-rate")
+                .help("New connections opened per second, replaces the -s delay")
+                .value_parser(clap::value_parser!(u32).range(1..)),
+        )
         .arg(
             Arg::new("udp")
                 .long("udp")
@@ -144,6 +755,40 @@ fn build_cli() -> ArgMatches {
                 .default_value("false")
                 .value_parser(clap::value_parser!(bool)),
         )
+        .arg(
+            Arg::new("framing")
+                .long("framing")
+                .help("Frame TCP payloads: none, u16[be|le], u32[be|le], varint, newline, crlf, delim:<bytes> or pad:<size>")
+                .default_value("none")
+                .value_parser(|s: &str| s.parse::<Framing>().map(|_| s.to_owned())),
+        )
+        .arg(
+            Arg::new("sequence")
+                .long("sequence")
+                .help("Stamp UDP/DTLS datagrams with a sequence header to measure loss, reordering and jitter")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("udp-batch")
+                .long("udp-batch")
+                .help("Batch UDP sends per syscall (Linux only)")
+                .value_parser(["off", "sendmmsg", "gso"])
+                .default_value("off"),
+        )
+        .arg(
+            Arg::new("batch-size")
+                .long("batch-size")
+                .help("Datagrams handed to the kernel per batched send")
+                .default_value("32")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("backend")
+                .long("backend")
+                .help("I/O backend for plain UDP/TCP senders (io-uring needs the io-uring feature)")
+                .value_parser(["tokio", "io-uring"])
+                .default_value("tokio"),
+        )
         .arg(
             Arg::new("tls")
                 .long("tls")
@@ -153,13 +798,136 @@ fn build_cli() -> ArgMatches {
                 .default_value("false")
                 .value_parser(clap::value_parser!(bool)),
         )
+        .arg(
+            Arg::new("quic")
+                .long("quic")
+                .help("Send data over QUIC (requires --ca)")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("quic-mode")
+                .long("quic-mode")
+                .help("Carry payloads on bidirectional streams, unidirectional streams or datagrams")
+                .value_parser(["bidi", "uni", "datagram"])
+                .default_value("bidi"),
+        )
+        .arg(
+            Arg::new("quic-streams")
+                .long("quic-streams")
+                .help("Concurrent streams per QUIC connection")
+                .default_value("1")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("quic-stream-per-payload")
+                .long("quic-stream-per-payload")
+                .help("Open a new QUIC stream for every payload")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("http")
+                .long("http")
+                .help("Send HTTP/1.1 requests over TCP/TLS, payloads are used as request bodies")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("http-version")
+                .long("http-version")
+                .help("HTTP version: 2 uses ALPN over TLS and prior knowledge (h2c) over TCP")
+                .value_parser(["1.1", "2"])
+                .default_value("1.1"),
+        )
+        .arg(
+            Arg::new("http2-max-streams")
+                .long("http2-max-streams")
+                .help("Concurrent HTTP/2 streams per connection")
+                .default_value("100")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("http2-timeout")
+                .long("http2-timeout")
+                .value_name("secs")
+                .help("Seconds an HTTP/2 response may take before it counts as an error")
+                .default_value("10")
+                .value_parser(clap::value_parser!(u64)),
+        )
+        .arg(
+            Arg::new("http-method")
+                .long("http-method")
+                .help("HTTP request method")
+                .default_value("GET"),
+        )
+        .arg(
+            Arg::new("http-path")
+                .long("http-path")
+                .help("HTTP request target, also used for the WebSocket upgrade")
+                .default_value("/"),
+        )
+        .arg(
+            Arg::new("header")
+                .short('H')
+                .long("header")
+                .help("Extra HTTP or WebSocket upgrade header as 'Name: value', may be repeated")
+                .action(clap::ArgAction::Append),
+        )
+        .arg(
+            Arg::new("http-pipeline")
+                .long("http-pipeline")
+                .help("HTTP requests sent back to back before reading the responses")
+                .default_value("1")
+                .value_parser(clap::value_parser!(usize)),
+        )
+        .arg(
+            Arg::new("http-no-keepalive")
+                .long("http-no-keepalive")
+                .help("Open a new connection for every HTTP request batch")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("websocket")
+                .long("websocket")
+                .help("Upgrade TCP/TLS connections to WebSocket and send payloads as frames")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("ws-binary")
+                .long("ws-binary")
+                .help("Send binary WebSocket frames instead of text frames")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("ws-echo")
+                .long("ws-echo")
+                .help("Measure frame latency assuming the server echoes every message")
+                .action(clap::ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("alpn")
+                .long("alpn")
+                .help("ALPN protocol to offer, may be repeated")
+                .action(clap::ArgAction::Append),
+        )
         .arg(
             Arg::new("ca")
                 .long("ca")
                 .help("PEM File to validate server credentials")
                 .value_parser(clap::value_parser!(String)),
         )
-        .get_matches()
+        .arg(
+            Arg::new("scenario")
+                .long("scenario")
+                .help("YAML or TOML file of options keyed by their long flag name, flags on the command line take precedence")
+                .value_parser(clap::value_parser!(String)),
+        )
+        .arg(
+            Arg::new("dump-config")
+                .long("dump-config")
+                .help("Print the effective options as a scenario file and exit")
+                .num_args(0..=1)
+                .default_missing_value("yaml")
+                .value_parser(["yaml", "toml"]),
+        )
 }
 
 fn build_runtime(cli: &ArgMatches) -> Runtime {
@@ -176,13 +944,18 @@ fn build_runtime(cli: &ArgMatches) -> Runtime {
     rt_builder.enable_all().build().unwrap()
 }
 
-fn extract_parameters(matches: ArgMatches) -> Parameters {
-    let server_addr = matches
-        .get_one::<String>("addr")
-        .unwrap()
-        .to_socket_addrs()
+fn extract_parameters(matches: ArgMatches, label: Option<String>) -> Parameters {
+    let mut targets: Vec<Target> = matches
+        .get_many::<Target>("addr")
+        .map(|values| values.cloned().collect())
+        .unwrap_or_default();
+    if let Some(loaded) = matches.get_one::<Vec<Target>>("targets-file") {
+        targets.extend(loaded.iter().cloned());
+    }
+    let assignment: Assignment = matches
+        .get_one::<String>("target-assignment")
         .unwrap()
-        .next()
+        .parse()
         .unwrap();
     let rate = *matches.get_one("rate").unwrap();
     let connections = *matches.get_one("clients").unwrap();
@@ -196,11 +969,7 @@ fn extract_parameters(matches: ArgMatches) -> Parameters {
         warn!("Both random-payload and sequential-payload specified. Using sequential mode.");
     }
     
-    let payload_config = if let Some(file) = payload_file {
-        Some(PayloadConfig::from_file(file).unwrap())
-    } else {
-        None
-    };
+    let payload_config = payload_file.map(|file| PayloadConfig::from_file(file).unwrap());
 
     let fallback_payload = matches.get_one::<String>("payload").unwrap().to_string();
     let len = if let Some(config) = &payload_config {
@@ -219,8 +988,22 @@ fn extract_parameters(matches: ArgMatches) -> Parameters {
     } else {
         fallback_payload.len()
     };
-    let start_port = *matches.get_one("port").unwrap();
+    let ports = PortOptions {
+        strategy: matches
+            .get_one::<String>("port-strategy")
+            .unwrap()
+            .parse()
+            .unwrap(),
+        start: *matches.get_one("port").unwrap(),
+        end: *matches.get_one("port-end").unwrap(),
+        list: matches
+            .get_one::<String>("ports")
+            .map(|s| parse_port_list(s).unwrap())
+            .unwrap_or_default(),
+    };
     let sleep = *matches.get_one("timeout").unwrap();
+    let connect_parallel = *matches.get_one::<u32>("connect-parallel").unwrap() as usize;
+    let connect_rate = matches.get_one::<u32>("connect-rate").copied();
 
     let bandwidth = Byte::from_u128((connections * rate * len * 8) as u128)
         .unwrap_or_default()
@@ -232,25 +1015,336 @@ fn extract_parameters(matches: ArgMatches) -> Parameters {
     let use_tls = *matches.get_one("tls").unwrap();
     let ca_file = matches.get_one("ca").cloned();
     let max_packets = matches.get_one::<usize>("max-packets").copied();
+    let duration = matches
+        .get_one::<u64>("duration")
+        .map(|secs| Duration::from_secs(*secs));
+    let framing: Framing = matches
+        .get_one::<String>("framing")
+        .unwrap()
+        .parse()
+        .unwrap();
+    let sequence = matches.get_flag("sequence");
+    let control_listen = matches.get_one::<SocketAddr>("control-listen").copied();
+    let tui = matches.get_flag("tui");
+    let stats_interval = stats_interval(&matches);
+    let connection_stats = matches.get_flag("connection-stats");
+    let bind = BindOptions {
+        addrs: matches
+            .get_many::<String>("bind")
+            .map(|values| values.flat_map(|s| parse_bind_list(s).unwrap()).collect())
+            .unwrap_or_default(),
+        interface: matches.get_one::<String>("interface").cloned(),
+        socket: extract_socket_options(&matches),
+    };
+    let dns = DnsOptions {
+        spread: matches.get_flag("dns-spread"),
+        refresh: matches.get_one::<u64>("dns-refresh").map(|secs| Duration::from_secs(*secs)),
+    };
+    let udp_batch: BatchMode = matches
+        .get_one::<String>("udp-batch")
+        .unwrap()
+        .parse()
+        .unwrap();
+    let batch_size = *matches.get_one("batch-size").unwrap();
+    let quic = matches.get_flag("quic").then(|| QuicOptions {
+        mode: matches
+            .get_one::<String>("quic-mode")
+            .unwrap()
+            .parse::<QuicMode>()
+            .unwrap(),
+        streams: *matches.get_one("quic-streams").unwrap(),
+        stream_per_payload: matches.get_flag("quic-stream-per-payload"),
+    });
+    let alpn = matches
+        .get_many::<String>("alpn")
+        .map(|values| values.cloned().collect())
+        .unwrap_or_default();
+    let http = matches.get_flag("http").then(|| HttpOptions {
+        version: matches
+            .get_one::<String>("http-version")
+            .unwrap()
+            .parse::<HttpVersion>()
+            .unwrap(),
+        method: matches.get_one::<String>("http-method").unwrap().to_uppercase(),
+        path: matches.get_one::<String>("http-path").unwrap().clone(),
+        headers: matches
+            .get_many::<String>("header")
+            .map(|values| values.cloned().collect())
+            .unwrap_or_default(),
+        pipeline: *matches.get_one("http-pipeline").unwrap(),
+        keep_alive: !matches.get_flag("http-no-keepalive"),
+        max_concurrent_streams: *matches.get_one("http2-max-streams").unwrap(),
+        response_timeout: Duration::from_secs(*matches.get_one("http2-timeout").unwrap()),
+    });
+    let websocket = matches.get_flag("websocket").then(|| WsOptions {
+        path: matches.get_one::<String>("http-path").unwrap().clone(),
+        headers: matches
+            .get_many::<String>("header")
+            .map(|values| values.cloned().collect())
+            .unwrap_or_default(),
+        binary: matches.get_flag("ws-binary"),
+        echo: matches.get_flag("ws-echo"),
+    });
+    let backend: Backend = matches
+        .get_one::<String>("backend")
+        .unwrap()
+        .parse()
+        .unwrap();
 
-    info!("Server address: {server_addr}, clients: {connections}, payload size: {len}, rate: {rate} pkt/s, sleep timeout:{sleep} ms, udp: {use_udp}, tls: {use_tls}");
+    let destination = targets
+        .iter()
+        .map(|target| match target.weight {
+            1 => target.label(),
+            weight => format!("{}@{weight}", target.label()),
+        })
+        .collect::<Vec<_>>()
+        .join(", ");
+    info!("Destination: {destination}, clients: {connections}, payload size: {len}, rate: {rate} pkt/s, sleep timeout:{sleep} ms, udp: {use_udp}, tls: {use_tls}");
     if let Some(max) = max_packets {
         info!("Will quit after sending {max} packets");
     }
+    if let Some(duration) = duration {
+        info!("Will quit after {duration:?}");
+    }
+    if let Some(quic) = &quic {
+        info!("QUIC mode: {:?}, streams per connection: {}", quic.mode, quic.streams);
+    }
+    if let Some(http) = &http {
+        match http.version {
+            HttpVersion::Http1 => info!("HTTP/1.1 mode: {} {}, pipeline: {}, keep-alive: {}", http.method, http.path, http.pipeline, http.keep_alive),
+            HttpVersion::Http2 => info!("HTTP/2 mode: {} {}, max concurrent streams: {}", http.method, http.path, http.max_concurrent_streams),
+        }
+    }
+    if let Some(ws) = &websocket {
+        info!("WebSocket mode: {}, binary frames: {}, echo latency: {}", ws.path, ws.binary, ws.echo);
+    }
+    if backend != Backend::Tokio {
+        info!("I/O backend: {backend:?}");
+    }
+    if framing != Framing::None {
+        info!("Payload framing: {framing:?}");
+    }
+    if use_udp && sequence {
+        info!("Sequence headers enabled, echoed datagrams are tracked for loss and reordering");
+    }
+    if targets.len() > 1 {
+        info!("Target assignment: {assignment:?}");
+    }
+    if connect_parallel > 1 || connect_rate.is_some() {
+        info!(
+            "Concurrent connection setup: {connect_parallel} in flight, rate: {}",
+            connect_rate.map_or("unlimited".to_owned(), |rate| format!("{rate} conn/s"))
+        );
+    }
+    match ports.strategy {
+        PortStrategy::Ephemeral => info!("Source ports: ephemeral"),
+        PortStrategy::List => info!("Source ports: {} listed", ports.list.len()),
+        strategy => info!("Source ports: {strategy:?} {}-{}", ports.start, ports.end),
+    }
+    if !bind.addrs.is_empty() || bind.interface.is_some() {
+        let addrs: Vec<_> = bind.addrs.iter().map(|range| format!("{range}")).collect();
+        info!("Source addresses: {}, interface: {}", match addrs.is_empty() {
+            true => "any".to_owned(),
+            false => addrs.join(", "),
+        }, bind.interface.as_deref().unwrap_or("any"));
+    }
+    if bind.socket != SocketOptions::default() {
+        info!("Socket options: {:?}", bind.socket);
+    }
+    if dns.enabled() {
+        if targets.iter().all(|target| target.host.is_none()) {
+            warn!("DNS options only apply to hostname destinations, ignoring them");
+        } else {
+            info!("DNS spread: {}, refresh: {:?}", dns.spread, dns.refresh);
+        }
+    }
+    if use_udp && udp_batch != BatchMode::Off {
+        info!("UDP batching: {udp_batch:?}, batch size: {batch_size}");
+    }
     info!("Theoretical Packets rate: {} pkt/sec", connections * rate);
     info!("Theoretical Bandwidth: {bandwidth} bit/s");
 
     Parameters::new(
-        server_addr,
+        targets,
+        assignment,
         rate,
         connections,
         payload_config,
         fallback_payload,
-        start_port,
+        SourceOptions { bind, ports },
         sleep,
         (use_udp, (use_tls, ca_file)),
         max_packets,
         sequential_payload,
         random_payload,
+        BatchOptions {
+            mode: udp_batch,
+            size: batch_size,
+        },
+        backend,
+        quic.clone(),
+        alpn,
+        http.clone(),
+        websocket,
+        MessageOptions { framing, sequence },
+        dns,
+        connect_parallel,
+        connect_rate,
+        duration,
+        control_listen,
+        StatsOptions {
+            label,
+            interval: stats_interval,
+            per_connection: connection_stats,
+            tui,
+        },
     )
 }
+
+fn extract_socket_options(matches: &ArgMatches) -> SocketOptions {
+    let secs = |id: &str| matches.get_one::<u64>(id).map(|secs| Duration::from_secs(*secs));
+    SocketOptions {
+        nodelay: matches.get_flag("nodelay"),
+        send_buffer: matches.get_one("sndbuf").copied(),
+        recv_buffer: matches.get_one("rcvbuf").copied(),
+        tos: matches
+            .get_one::<u32>("tos")
+            .copied()
+            .or_else(|| matches.get_one::<u32>("dscp").map(|dscp| dscp << 2)),
+        ttl: matches.get_one("ttl").copied(),
+        keepalive: secs("keepalive").map(|time| Keepalive {
+            time,
+            interval: secs("keepalive-interval"),
+            retries: matches.get_one("keepalive-retries").copied(),
+        }),
+        linger: secs("linger"),
+        reuse_port: matches.get_flag("reuseport"),
+    }
+}
+
+fn extract_serve_parameters(matches: &ArgMatches) -> ServeParameters {
+    let listen = *matches.get_one::<SocketAddr>("listen").unwrap();
+    let framing: Framing = matches
+        .get_one::<String>("framing")
+        .unwrap()
+        .parse()
+        .unwrap();
+
+    ServeParameters::new(
+        listen,
+        matches.get_flag("udp"),
+        matches.get_flag("tls"),
+        matches.get_flag("echo"),
+        CertFiles {
+            cert: matches.get_one::<String>("cert").cloned(),
+            key: matches.get_one::<String>("key").cloned(),
+        },
+        framing,
+        stats_interval(matches),
+    )
+}
+
+fn stats_interval_arg() -> Arg {
+    Arg::new("stats-interval")
+        .long("stats-interval")
+        .value_name("secs")
+        .help("Seconds between statistics lines, fractions allowed")
+        .default_value("10")
+        .value_parser(|s: &str| parse_interval(s).map(|_| s.to_owned()))
+}
+
+fn stats_interval(matches: &ArgMatches) -> Duration {
+    parse_interval(matches.get_one::<String>("stats-interval").unwrap()).unwrap()
+}
+
+fn parse_interval(s: &str) -> Result<Duration, String> {
+    match s.parse::<f64>() {
+        Ok(secs) if secs > 0. => Duration::try_from_secs_f64(secs).map_err(|err| err.to_string()),
+        _ => Err(format!("invalid interval {s}, expected a number of seconds > 0")),
+    }
+}
+
+fn parse_socket_addr(s: &str) -> Result<SocketAddr, String> {
+    s.to_socket_addrs()
+        .map_err(|err| format!("invalid address {s}: {err}"))?
+        .next()
+        .ok_or_else(|| format!("address {s} did not resolve"))
+}
+
+/// Plans a scenario without a command line of its own, as the agents and
+/// the coordinator do.
+fn plan_scenario(scenario: &Scenario, source: &str) -> Result<Vec<Phase>, String> {
+    let mut command = build_command();
+    command.build();
+    let args = [OsString::from("crab-net")];
+    let cli = command
+        .clone()
+        .ignore_errors(true)
+        .try_get_matches_from(&args)
+        .unwrap_or_default();
+    scenario_phases(&command, &cli, &args, scenario, source)
+}
+
+/// Client groups of the scenario pushed by a coordinator, phase by phase.
+fn agent_phases(scenario: &str) -> Result<Vec<Vec<Parameters>>, String> {
+    let scenario = Scenario::parse(scenario, ScenarioFormat::Yaml)?;
+    let phases = plan_scenario(&scenario, "from the coordinator")?;
+    let phases = phases
+        .into_iter()
+        .map(|phase| {
+            phase
+                .groups
+                .into_iter()
+                .map(|group| {
+                    let label = group_label(phase.name.as_deref(), group.name.as_deref());
+                    extract_parameters(group.matches, label)
+                })
+                .collect()
+        })
+        .collect();
+    Ok(phases)
+}
+
+/// The scenario of every agent. Each agent gets its own range of source
+/// ports, so that agents sharing a host do not collide.
+fn coordinator_scenarios(matches: &ArgMatches) -> Vec<(SocketAddr, String)> {
+    let agents: Vec<SocketAddr> = matches
+        .get_many::<SocketAddr>("agent")
+        .unwrap()
+        .copied()
+        .collect();
+    let path = matches.get_one::<String>("scenario").unwrap();
+    let mut command = build_command();
+    command.build();
+    // Invalid options are reported here, before any agent is involved
+    let scenario = Scenario::from_file(path)
+        .unwrap_or_else(|err| command.clone().error(ErrorKind::InvalidValue, err).exit());
+    let phases = plan_scenario(&scenario, path)
+        .unwrap_or_else(|err| command.clone().error(ErrorKind::InvalidValue, err).exit());
+
+    let first = &phases[0].groups[0].matches;
+    let range = first.get_one::<String>("port-strategy").unwrap() == "range";
+    let start = *first.get_one::<u16>("port").unwrap() as usize;
+    // Groups without ports of their own use consecutive ranges
+    let span: usize = phases
+        .iter()
+        .flat_map(|phase| &phase.groups)
+        .map(|group| *group.matches.get_one::<usize>("clients").unwrap())
+        .sum();
+    let spread = range && start + agents.len() * span <= u16::MAX as usize + 1;
+    if range && !spread {
+        warn!("Not enough source ports for a range per agent, agents sharing a host may collide");
+    }
+    agents
+        .into_iter()
+        .enumerate()
+        .map(|(idx, addr)| {
+            let mut scenario = scenario.clone();
+            if spread && idx > 0 {
+                let port = [("port".to_owned(), ScenarioValue::Int((start + idx * span) as i64))];
+                scenario.options = overlay(&command, &scenario.options, &port).unwrap();
+            }
+            (addr, scenario.to_string(ScenarioFormat::Yaml).unwrap())
+        })
+        .collect()
+}
@@ -3,10 +3,33 @@ use std::fs::File;
 use std::io::Read;
 use std::path::Path;
 
+use crate::{framing::Framing, sequence::SequenceHeader};
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct PayloadEntry {
     pub index: usize,
     pub data: String,
+    /// HTTP method overriding `--http-method` for this entry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<String>,
+    /// HTTP request target overriding `--http-path` for this entry.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// Extra HTTP headers as `Name: value` lines.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub headers: Vec<String>,
+}
+
+impl PayloadEntry {
+    pub fn from_data(data: String) -> Self {
+        Self {
+            index: 0,
+            data,
+            method: None,
+            path: None,
+            headers: Vec::new(),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -27,16 +50,20 @@ impl PayloadConfig {
     }
 
     pub fn get_payload(&self, index: Option<usize>, random: bool, sequential: bool) -> Option<String> {
+        self.get_entry(index, random, sequential).map(|p| p.data.clone())
+    }
+
+    pub fn get_entry(&self, index: Option<usize>, random: bool, sequential: bool) -> Option<&PayloadEntry> {
         if random {
             let random_idx = fastrand::usize(..self.payloads.len());
-            Some(self.payloads[random_idx].data.clone())
+            Some(&self.payloads[random_idx])
         } else if sequential {
             // For sequential mode, we ignore the index parameter
-            Some(self.payloads[self.current_index].data.clone())
+            Some(&self.payloads[self.current_index])
         } else {
             match index {
-                Some(idx) => self.payloads.iter().find(|p| p.index == idx).map(|p| p.data.clone()),
-                None => Some(self.payloads[0].data.clone()) // Default to first payload if no index specified
+                Some(idx) => self.payloads.iter().find(|p| p.index == idx),
+                None => self.payloads.first() // Default to first payload if no index specified
             }
         }
     }
@@ -45,3 +72,90 @@ impl PayloadConfig {
         self.current_index = (self.current_index + 1) % self.payloads.len();
     }
 }
+
+/// Per-client payload generator combining the optional payload file with the
+/// fallback payload and the selection mode requested on the command line.
+#[derive(Debug, Clone)]
+pub struct PayloadSource {
+    config: Option<PayloadConfig>,
+    fallback: Vec<u8>,
+    sequential: bool,
+    random: bool,
+    framing: Framing,
+    /// Client id to stamp a sequence header with, and the next number.
+    sequence: Option<(u32, u64)>,
+}
+
+impl PayloadSource {
+    pub fn new(
+        config: Option<PayloadConfig>,
+        fallback: Vec<u8>,
+        sequential: bool,
+        random: bool,
+        framing: Framing,
+        sequence: Option<u32>,
+    ) -> Self {
+        Self {
+            config,
+            fallback,
+            sequential,
+            random,
+            framing,
+            sequence: sequence.map(|client| (client, 0)),
+        }
+    }
+
+    /// Framing applied to every payload.
+    pub fn framing(&self) -> &Framing {
+        &self.framing
+    }
+
+    /// Replaces the payloads picked from, e.g. while the test is running.
+    pub fn set_config(&mut self, config: PayloadConfig) {
+        self.config = Some(config);
+    }
+
+    /// Next payload, framed and stamped for the wire.
+    pub fn next_payload(&mut self) -> Vec<u8> {
+        let payload = match &mut self.config {
+            Some(config) => {
+                let payload = config
+                    .get_payload(None, self.random, self.sequential)
+                    .unwrap()
+                    .into_bytes();
+                if self.sequential {
+                    config.next_sequential_index();
+                }
+                payload
+            }
+            None => self.fallback.clone(),
+        };
+        let payload = self.framing.apply(payload);
+        match &mut self.sequence {
+            Some((client, seq)) => {
+                let header = SequenceHeader::new(*client, *seq);
+                *seq += 1;
+                header.prepend(&payload)
+            }
+            None => payload,
+        }
+    }
+
+    /// Like [`PayloadSource::next_payload`] but unframed and keeping the
+    /// per-entry protocol metadata.
+    pub fn next_entry(&mut self) -> PayloadEntry {
+        match &mut self.config {
+            Some(config) => {
+                let entry = config
+                    .get_entry(None, self.random, self.sequential)
+                    .unwrap()
+                    .clone();
+                if self.sequential {
+                    config.next_sequential_index();
+                }
+                entry
+            }
+            None => PayloadEntry::from_data(String::from_utf8_lossy(&self.fallback).into_owned()),
+        }
+    }
+}
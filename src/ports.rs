@@ -0,0 +1,189 @@
+//! Source port allocation for the client sockets.
+
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PortStrategy {
+    /// Let the OS pick an ephemeral port.
+    Ephemeral,
+    /// Consecutive ports from the start port.
+    #[default]
+    Range,
+    /// Distinct ports picked at random between the start and end port.
+    Random,
+    /// Ports taken in order from an explicit list.
+    List,
+}
+
+impl FromStr for PortStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ephemeral" => Ok(Self::Ephemeral),
+            "range" => Ok(Self::Range),
+            "random" => Ok(Self::Random),
+            "list" => Ok(Self::List),
+            _ => Err(format!("unknown port strategy: {s}")),
+        }
+    }
+}
+
+/// Parses a comma separated list of ports and `FIRST-LAST` ranges.
+pub fn parse_port_list(s: &str) -> Result<Vec<u16>, String> {
+    let mut ports = Vec::new();
+    for item in s.split(',').map(str::trim) {
+        let (first, last) = item.split_once('-').unwrap_or((item, item));
+        let parse = |port: &str| match port.trim().parse::<u16>() {
+            Ok(port) if port > 0 => Ok(port),
+            _ => Err(format!("invalid port: {item}")),
+        };
+        let (first, last) = (parse(first)?, parse(last)?);
+        if first > last {
+            return Err(format!("invalid port range: {item}"));
+        }
+        ports.extend(first..=last);
+    }
+    Ok(ports)
+}
+
+#[derive(Debug, Clone)]
+pub struct PortOptions {
+    pub strategy: PortStrategy,
+    pub start: u16,
+    pub end: u16,
+    pub list: Vec<u16>,
+}
+
+/// Source ports assigned to the clients, validated up front so a run never
+/// stops half way through the connections for lack of ports.
+pub struct PortAllocator {
+    ports: Vec<u16>,
+    // Clients sharing a port, each on a different source address
+    slots: usize,
+}
+
+impl PortAllocator {
+    /// `slots` is the number of source addresses the clients rotate over,
+    /// the same port can be reused once per address.
+    pub fn new(options: &PortOptions, clients: usize, slots: usize) -> Result<Self, String> {
+        let slots = slots.max(1);
+        let needed = clients.div_ceil(slots);
+        let available = match options.strategy {
+            PortStrategy::Ephemeral => {
+                return Ok(Self {
+                    ports: Vec::new(),
+                    slots,
+                })
+            }
+            PortStrategy::Range | PortStrategy::Random => {
+                if options.start == 0 || options.start > options.end {
+                    return Err(format!(
+                        "invalid source port range {}-{}",
+                        options.start, options.end
+                    ));
+                }
+                (options.start..=options.end).collect()
+            }
+            PortStrategy::List => options.list.clone(),
+        };
+        if available.len() < needed {
+            return Err(format!(
+                "{clients} clients need {needed} source ports but only {} are available, \
+                 widen the port range, add --bind addresses or use ephemeral ports",
+                available.len()
+            ));
+        }
+        let mut ports = available;
+        if options.strategy == PortStrategy::Random {
+            fastrand::shuffle(&mut ports);
+        }
+        ports.truncate(needed);
+        Ok(Self { ports, slots })
+    }
+
+    /// Source port of `client`, 0 for an ephemeral one.
+    pub fn port(&self, client: usize) -> usize {
+        self.ports
+            .get(client / self.slots)
+            .map_or(0, |port| *port as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(strategy: PortStrategy, start: u16, end: u16) -> PortOptions {
+        PortOptions {
+            strategy,
+            start,
+            end,
+            list: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn parses_port_lists() {
+        assert_eq!(
+            parse_port_list("80, 8000-8002").unwrap(),
+            [80, 8000, 8001, 8002]
+        );
+        assert_eq!(parse_port_list("65535-65535").unwrap(), [65535]);
+        for s in ["0", "8002-8000", "80,", "65536", "a-b"] {
+            assert!(parse_port_list(s).is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn range_up_to_the_last_port() {
+        let options = options(PortStrategy::Range, 65534, 65535);
+        let ports = PortAllocator::new(&options, 2, 1).unwrap();
+        assert_eq!((ports.port(0), ports.port(1)), (65534, 65535));
+        // Clients added later fall back to ephemeral ports
+        assert_eq!(ports.port(2), 0);
+        assert!(PortAllocator::new(&options, 3, 1).is_err());
+    }
+
+    #[test]
+    fn source_addresses_share_ports() {
+        let options = options(PortStrategy::Range, 9000, 9001);
+        let ports = PortAllocator::new(&options, 6, 3).unwrap();
+        let assigned: Vec<_> = (0..6).map(|client| ports.port(client)).collect();
+        assert_eq!(assigned, [9000, 9000, 9000, 9001, 9001, 9001]);
+        assert!(PortAllocator::new(&options, 7, 3).is_err());
+    }
+
+    #[test]
+    fn random_ports_are_distinct_and_in_range() {
+        let options = options(PortStrategy::Random, 9000, 9099);
+        let ports = PortAllocator::new(&options, 100, 1).unwrap();
+        let mut assigned: Vec<_> = (0..100).map(|client| ports.port(client)).collect();
+        assigned.sort_unstable();
+        assert_eq!(assigned, (9000..=9099).collect::<Vec<_>>());
+        assert!(PortAllocator::new(&options, 101, 1).is_err());
+    }
+
+    #[test]
+    fn list_ports_in_order() {
+        let options = PortOptions {
+            list: vec![7000, 6000],
+            ..options(PortStrategy::List, 0, 0)
+        };
+        let ports = PortAllocator::new(&options, 2, 1).unwrap();
+        assert_eq!((ports.port(0), ports.port(1)), (7000, 6000));
+        assert!(PortAllocator::new(&options, 3, 1).is_err());
+    }
+
+    #[test]
+    fn invalid_ranges_and_ephemeral_ports() {
+        for (start, end) in [(0, 10), (9001, 9000)] {
+            let options = options(PortStrategy::Range, start, end);
+            assert!(PortAllocator::new(&options, 1, 1).is_err(), "{start}-{end}");
+        }
+        // Ephemeral ports ignore the range and never run out
+        let options = options(PortStrategy::Ephemeral, 0, 0);
+        let ports = PortAllocator::new(&options, 100_000, 0).unwrap();
+        assert_eq!(ports.port(99_999), 0);
+    }
+}
@@ -0,0 +1,199 @@
+//! Scenario files: a whole run described in YAML or TOML, keyed by the
+//! long names of the command line options. A run is either a single set of
+//! options or `phases` run one after the other, each made of client
+//! `groups` running side by side.
+
+use std::{fmt, fs, path::Path, str::FromStr};
+
+use serde::{
+    de::{MapAccess, Visitor},
+    ser::SerializeMap,
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+/// Value of one option: a flag, a single value or a repeated option.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ScenarioValue {
+    Flag(bool),
+    Int(i64),
+    Float(f64),
+    Text(String),
+    List(Vec<ScenarioValue>),
+}
+
+impl ScenarioValue {
+    /// Command line arguments setting option `name` to this value. A false
+    /// flag leaves the option unset.
+    pub fn to_args(&self, name: &str) -> Vec<String> {
+        match self {
+            ScenarioValue::Flag(true) => vec![format!("--{name}")],
+            ScenarioValue::Flag(false) => Vec::new(),
+            ScenarioValue::List(values) => values.iter().flat_map(|v| v.to_args(name)).collect(),
+            value => vec![format!("--{name}={value}")],
+        }
+    }
+}
+
+impl fmt::Display for ScenarioValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScenarioValue::Flag(flag) => write!(f, "{flag}"),
+            ScenarioValue::Int(value) => write!(f, "{value}"),
+            ScenarioValue::Float(value) => write!(f, "{value}"),
+            ScenarioValue::Text(value) => write!(f, "{value}"),
+            ScenarioValue::List(values) => {
+                let values: Vec<_> = values.iter().map(ToString::to_string).collect();
+                write!(f, "{}", values.join(","))
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScenarioFormat {
+    Yaml,
+    Toml,
+}
+
+impl ScenarioFormat {
+    /// TOML for `.toml` files, YAML otherwise.
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Self {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Self::Toml,
+            _ => Self::Yaml,
+        }
+    }
+}
+
+impl FromStr for ScenarioFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "yaml" => Ok(Self::Yaml),
+            "toml" => Ok(Self::Toml),
+            _ => Err(format!("unknown scenario format: {s}")),
+        }
+    }
+}
+
+/// Options of a run, phase or group in file order. Phases and groups
+/// inherit the options of their parent, overriding them as needed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Scenario {
+    /// Name of a phase or group, used in the statistics.
+    pub name: Option<String>,
+    pub options: Vec<(String, ScenarioValue)>,
+    /// Phases run one after the other, top level only.
+    pub phases: Vec<Scenario>,
+    /// Client groups running side by side, at the top level or in a phase.
+    pub groups: Vec<Scenario>,
+}
+
+impl Scenario {
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)
+            .map_err(|err| format!("cannot read scenario {}: {err}", path.display()))?;
+        Self::parse(&contents, ScenarioFormat::from_path(path))
+            .map_err(|err| format!("invalid scenario {}: {err}", path.display()))
+    }
+
+    pub fn parse(contents: &str, format: ScenarioFormat) -> Result<Self, String> {
+        let scenario: Self = match format {
+            ScenarioFormat::Yaml => {
+                serde_yaml::from_str(contents).map_err(|err| err.to_string())?
+            }
+            ScenarioFormat::Toml => toml::from_str(contents).map_err(|err| err.to_string())?,
+        };
+        scenario.validate()?;
+        Ok(scenario)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.name.is_some() {
+            return Err("name is only allowed in phases and groups".to_owned());
+        }
+        if !self.phases.is_empty() && !self.groups.is_empty() {
+            return Err("groups belong inside the phases when there are phases".to_owned());
+        }
+        let groups = self
+            .phases
+            .iter()
+            .flat_map(|phase| &phase.groups)
+            .chain(&self.groups);
+        if self.phases.iter().any(|phase| !phase.phases.is_empty()) {
+            return Err("phases cannot contain phases".to_owned());
+        }
+        for group in groups {
+            if !group.phases.is_empty() || !group.groups.is_empty() {
+                return Err(match &group.name {
+                    Some(name) => format!("group {name} cannot contain phases or groups"),
+                    None => "groups cannot contain phases or groups".to_owned(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the scenario runs more than one set of clients.
+    pub fn is_staged(&self) -> bool {
+        !self.phases.is_empty() || !self.groups.is_empty()
+    }
+
+    pub fn to_string(&self, format: ScenarioFormat) -> Result<String, String> {
+        match format {
+            ScenarioFormat::Yaml => serde_yaml::to_string(self).map_err(|err| err.to_string()),
+            ScenarioFormat::Toml => toml::to_string(self).map_err(|err| err.to_string()),
+        }
+    }
+}
+
+impl Serialize for Scenario {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        if let Some(name) = &self.name {
+            map.serialize_entry("name", name)?;
+        }
+        for (name, value) in &self.options {
+            map.serialize_entry(name, value)?;
+        }
+        if !self.groups.is_empty() {
+            map.serialize_entry("groups", &self.groups)?;
+        }
+        if !self.phases.is_empty() {
+            map.serialize_entry("phases", &self.phases)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Scenario {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct ScenarioVisitor;
+
+        impl<'de> Visitor<'de> for ScenarioVisitor {
+            type Value = Scenario;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a map of option names to values")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Scenario, A::Error> {
+                let mut scenario = Scenario::default();
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "name" => scenario.name = Some(map.next_value()?),
+                        "phases" => scenario.phases = map.next_value()?,
+                        "groups" => scenario.groups = map.next_value()?,
+                        _ => scenario.options.push((key, map.next_value()?)),
+                    }
+                }
+                Ok(scenario)
+            }
+        }
+
+        deserializer.deserialize_map(ScenarioVisitor)
+    }
+}
@@ -1,52 +1,135 @@
+use std::{
+    collections::BTreeMap,
+    io,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
 use coarsetime::{Duration, Instant};
-use kanal::AsyncSender;
 use log::debug;
-use crate::payload::PayloadConfig;
+use quinn::{Connection, SendStream};
 use tokio::{
-    io::{AsyncWrite, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
+    join,
     net::UdpSocket,
-    time::sleep,
+    select, spawn,
+    sync::Semaphore,
+    time::{interval_at, sleep, timeout},
 };
 
-use crate::{statistics::StatPacket, DtlsSession};
+#[cfg(unix)]
+use tokio::net::UnixDatagram;
+
+use crate::{
+    batch::UdpBatcher,
+    control::ClientControl,
+    framing::Framing,
+    http::{HttpOptions, ResponseReader},
+    payload::PayloadSource,
+    quic::{QuicMode, QuicOptions},
+    sequence::{SequenceHeader, SequenceTracker},
+    statistics::{Histogram, StatPacket, StatsSender},
+    websocket::{WsOptions, WsRound, WsSession},
+    AsyncStream, DtlsSession, StreamTarget,
+};
 
 pub async fn sender_task_udp(
     id: usize,
     socket: UdpSocket,
-    mut payload_config: Option<PayloadConfig>,
-    fallback_payload: Vec<u8>,
-    rate: usize,
-    stats_tx: AsyncSender<StatPacket>,
-    sequential_payload: bool,
-    random_payload: bool,
+    mut payloads: PayloadSource,
+    mut batcher: UdpBatcher,
+    mut control: ClientControl,
+    stats_tx: StatsSender,
+    track_echoes: bool,
+) {
+    debug!("client {id} spawned");
+    let one_sec = Duration::new(1, 0);
+    let mut batch = Vec::with_capacity(batcher.batch_size());
+
+    let sender = async {
+        loop {
+            let start_time = Instant::now();
+            let mut packets_sent = 0;
+            let mut bytes_sent = 0;
+            let mut errors = 0;
+            let mut remaining = control.round(&mut payloads).await;
+
+            while remaining > 0 {
+                let batch_len = remaining.min(batcher.batch_size());
+                batch.clear();
+                batch.extend((0..batch_len).map(|_| payloads.next_payload()));
+
+                let (packets, bytes) = batcher.send(&socket, &batch).await;
+                packets_sent += packets;
+                bytes_sent += bytes;
+                errors += batch_len - packets;
+                remaining -= batch_len;
+            }
+
+            send_stats(bytes_sent, packets_sent, errors, &stats_tx).await;
+            maybe_sleep(start_time, one_sec).await;
+        }
+    };
+
+    if track_echoes {
+        join!(sender, receive_echoes(id, &socket, &stats_tx));
+    } else {
+        sender.await;
+    }
+}
+
+/// Tracks the sequence headers of the datagrams the server echoes back.
+async fn receive_echoes(id: usize, socket: &UdpSocket, stats_tx: &StatsSender) {
+    let one_sec = std::time::Duration::from_secs(1);
+    let mut timer = interval_at(tokio::time::Instant::now() + one_sec, one_sec);
+    let mut buf = vec![0; 65536];
+    let mut tracker = SequenceTracker::default();
+
+    loop {
+        select! {
+            _ = timer.tick() => {
+                let stats = tracker.take_interval();
+                let peer = format!("client {id}");
+                let _ = stats_tx.send(StatPacket::Sequence { peer, stats }).await;
+            }
+            received = socket.recv(&mut buf) => {
+                if let Some(header) = received.ok().and_then(|len| SequenceHeader::parse(&buf[..len])) {
+                    tracker.record(&header);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(unix)]
+pub async fn sender_task_unix_datagram(
+    id: usize,
+    socket: UnixDatagram,
+    mut payloads: PayloadSource,
+    mut control: ClientControl,
+    stats_tx: StatsSender,
 ) {
     debug!("client {id} spawned");
     let one_sec = Duration::new(1, 0);
 
     loop {
         let start_time = Instant::now();
-        let mut packets_error = 0;
+        let mut packets_sent = 0;
         let mut bytes_sent = 0;
+        let mut errors = 0;
 
+        let rate = control.round(&mut payloads).await;
         for _ in 0..rate {
-            let payload = if let Some(config) = &mut payload_config {
-                let payload = config.get_payload(None, random_payload, sequential_payload).unwrap().into_bytes();
-                if sequential_payload {
-                    config.next_sequential_index();
-                }
-                payload
-            } else {
-                fallback_payload.clone()
-            };
-            
-            if socket.send(&payload).await.is_err() {
-                packets_error += 1;
-            } else {
+            let payload = payloads.next_payload();
+            if socket.send(&payload).await.is_ok() {
+                packets_sent += 1;
                 bytes_sent += payload.len();
+            } else {
+                errors += 1;
             }
         }
 
-        send_stats(rate, bytes_sent, packets_error, &stats_tx).await;
+        send_stats(bytes_sent, packets_sent, errors, &stats_tx).await;
         maybe_sleep(start_time, one_sec).await;
     }
 }
@@ -54,81 +137,529 @@ pub async fn sender_task_udp(
 pub async fn sender_task_dtls(
     id: usize,
     mut session: DtlsSession,
-    payload: Vec<u8>,
-    rate: usize,
-    stats_tx: AsyncSender<StatPacket>,
+    mut payloads: PayloadSource,
+    mut control: ClientControl,
+    stats_tx: StatsSender,
 ) {
     debug!("client {id} spawned");
     let one_sec = Duration::new(1, 0);
 
     loop {
         let start_time = Instant::now();
-        let mut packets_error = 0;
+        let mut packets_sent = 0;
+        let mut bytes_sent = 0;
+        let mut errors = 0;
 
+        let rate = control.round(&mut payloads).await;
         for _ in 0..rate {
-            if session.write(&payload).await.is_err() {
-                packets_error += 1;
+            let payload = payloads.next_payload();
+            if session.write(&payload).await.is_ok() {
+                packets_sent += 1;
+                bytes_sent += payload.len();
+            } else {
+                errors += 1;
             }
         }
 
-        send_stats(rate, payload.len(), packets_error, &stats_tx).await;
+        send_stats(bytes_sent, packets_sent, errors, &stats_tx).await;
         maybe_sleep(start_time, one_sec).await;
     }
 }
 
 pub async fn sender_task_tcp(
     id: usize,
-    mut stream: Box<dyn AsyncWrite + Unpin + Send>,
-    mut payload_config: Option<PayloadConfig>,
-    fallback_payload: Vec<u8>,
-    rate: usize,
-    stats_tx: AsyncSender<StatPacket>,
-    sequential_payload: bool,
-    random_payload: bool,
+    stream: Box<dyn AsyncStream>,
+    mut payloads: PayloadSource,
+    mut control: ClientControl,
+    stats_tx: StatsSender,
 ) {
     debug!("client {id} spawned");
     let one_sec = Duration::new(1, 0);
+    let framing = payloads.framing().clone();
+    let (reader, mut writer) = tokio::io::split(stream);
+
+    let sender = async {
+        loop {
+            let start_time = Instant::now();
+            let mut packets_sent = 0;
+            let mut bytes_sent = 0;
+            let mut errors = 0;
+
+            let rate = control.round(&mut payloads).await;
+            for _ in 0..rate {
+                let payload = payloads.next_payload();
+                if writer.write_all(&payload).await.is_ok() {
+                    packets_sent += 1;
+                    bytes_sent += payload.len();
+                } else {
+                    errors += 1;
+                }
+            }
+
+            send_stats(bytes_sent, packets_sent, errors, &stats_tx).await;
+            maybe_sleep(start_time, one_sec).await;
+        }
+    };
+
+    join!(sender, drain_stream(reader, framing, &stats_tx));
+}
+
+/// Reads what the server sends back, e.g. the data of an echo server, so
+/// that it never stops reading because the client does not.
+async fn drain_stream<R: AsyncRead + Unpin>(
+    mut reader: R,
+    framing: Framing,
+    stats_tx: &StatsSender,
+) {
+    let one_sec = std::time::Duration::from_secs(1);
+    let mut timer = interval_at(tokio::time::Instant::now() + one_sec, one_sec);
+    let mut buf = vec![0; 65536];
+    let mut pending = Vec::new();
+    let (mut bytes, mut packets) = (0, 0);
+
+    loop {
+        select! {
+            _ = timer.tick(), if bytes > 0 => {
+                let _ = stats_tx.send(StatPacket::Received { bytes, packets }).await;
+                (bytes, packets) = (0, 0);
+            }
+            read = reader.read(&mut buf) => {
+                let len = match read {
+                    Ok(0) | Err(_) => break,
+                    Ok(len) => len,
+                };
+                pending.extend_from_slice(&buf[..len]);
+                packets += framing.split(&mut pending);
+                bytes += len;
+            }
+        }
+    }
+    if bytes > 0 {
+        let _ = stats_tx.send(StatPacket::Received { bytes, packets }).await;
+    }
+}
+
+pub async fn sender_task_quic(
+    id: usize,
+    connection: Connection,
+    options: QuicOptions,
+    mut payloads: PayloadSource,
+    mut control: ClientControl,
+    stats_tx: StatsSender,
+) {
+    debug!("client {id} spawned");
+    let one_sec = Duration::new(1, 0);
+
+    let mut streams = Vec::new();
+    if options.mode != QuicMode::Datagram && !options.stream_per_payload {
+        for _ in 0..options.streams.max(1) {
+            if let Some(stream) = open_quic_stream(&connection, options.mode).await {
+                streams.push(stream);
+            }
+        }
+        let _ = stats_tx.send(StatPacket::Streams(streams.len())).await;
+    }
+    let mut next_stream = 0;
 
     loop {
         let start_time = Instant::now();
-        let mut packets_error = 0;
+        let mut packets_sent = 0;
         let mut bytes_sent = 0;
+        let mut errors = 0;
+        let mut streams_opened = 0;
 
+        let rate = control.round(&mut payloads).await;
         for _ in 0..rate {
-            let payload = if let Some(config) = &mut payload_config {
-                let payload = config.get_payload(None, random_payload, sequential_payload).unwrap().into_bytes();
-                if sequential_payload {
-                    config.next_sequential_index();
+            let payload = payloads.next_payload();
+            let len = payload.len();
+            let sent = match options.mode {
+                QuicMode::Datagram => connection.send_datagram(payload.into()).is_ok(),
+                _ if options.stream_per_payload => {
+                    match open_quic_stream(&connection, options.mode).await {
+                        Some(mut stream) => {
+                            streams_opened += 1;
+                            stream.write_all(&payload).await.is_ok() && stream.finish().is_ok()
+                        }
+                        None => false,
+                    }
+                }
+                _ if streams.is_empty() => false,
+                _ => {
+                    next_stream = (next_stream + 1) % streams.len();
+                    let stream = &mut streams[next_stream];
+                    stream.write_all(&payload).await.is_ok()
                 }
-                payload
-            } else {
-                fallback_payload.clone()
             };
-            
-            if stream.write_all(&payload).await.is_err() {
-                packets_error += 1;
+            if sent {
+                packets_sent += 1;
+                bytes_sent += len;
             } else {
-                bytes_sent += payload.len();
+                errors += 1;
+            }
+        }
+
+        if streams_opened > 0 {
+            let _ = stats_tx.send(StatPacket::Streams(streams_opened)).await;
+        }
+        send_stats(bytes_sent, packets_sent, errors, &stats_tx).await;
+        maybe_sleep(start_time, one_sec).await;
+    }
+}
+
+/// Opens a stream in the requested mode. The receive half of bidirectional
+/// streams is drained in the background so server replies never stall the flow control.
+async fn open_quic_stream(connection: &Connection, mode: QuicMode) -> Option<SendStream> {
+    match mode {
+        QuicMode::Uni => connection.open_uni().await.ok(),
+        _ => {
+            let (send, mut recv) = connection.open_bi().await.ok()?;
+            spawn(async move {
+                let mut buf = [0u8; 4096];
+                while let Ok(Some(_)) = recv.read(&mut buf).await {}
+            });
+            Some(send)
+        }
+    }
+}
+
+pub async fn sender_task_http(
+    id: usize,
+    mut stream: Box<dyn AsyncStream>,
+    target: StreamTarget,
+    options: HttpOptions,
+    mut payloads: PayloadSource,
+    mut control: ClientControl,
+    stats_tx: StatsSender,
+) {
+    debug!("client {id} spawned");
+    let one_sec = Duration::new(1, 0);
+    let host = target.host();
+    let mut reader = ResponseReader::default();
+    let mut connected = true;
+
+    loop {
+        let start_time = Instant::now();
+        let mut packets_sent = 0;
+        let mut bytes_sent = 0;
+        let mut errors = 0;
+        let mut reconnects = 0;
+        let mut latency = Histogram::default();
+        let mut statuses = BTreeMap::new();
+        let mut remaining = control.round(&mut payloads).await;
+
+        while remaining > 0 {
+            let depth = remaining.min(options.pipeline.max(1));
+            remaining -= depth;
+
+            if !connected {
+                match reconnect_ephemeral(id, &target).await {
+                    Ok(new_stream) => {
+                        stream = new_stream;
+                        reader.clear();
+                        connected = true;
+                        reconnects += 1;
+                    }
+                    Err(_) => {
+                        // The rest of the round is lost
+                        errors += depth + remaining;
+                        break;
+                    }
+                }
+            }
+
+            let mut buf = Vec::new();
+            let mut head_requests = Vec::with_capacity(depth);
+            for _ in 0..depth {
+                let (request, head) = options.build_request(&payloads.next_entry(), &host);
+                buf.extend_from_slice(&request);
+                head_requests.push(head);
+            }
+
+            let sent_at = std::time::Instant::now();
+            if stream.write_all(&buf).await.is_err() {
+                connected = false;
+                errors += depth;
+                continue;
+            }
+            packets_sent += depth;
+            bytes_sent += buf.len();
+
+            let mut answered = 0;
+            for head in head_requests {
+                answered += 1;
+                match reader.read_response(&mut stream, head).await {
+                    Ok(response) => {
+                        latency.record(sent_at.elapsed());
+                        *statuses.entry(response.status).or_insert(0) += 1;
+                        if response.close {
+                            connected = false;
+                            errors += depth - answered;
+                            break;
+                        }
+                    }
+                    Err(_) => {
+                        connected = false;
+                        // This request and the ones pipelined behind it get no response
+                        errors += depth - answered + 1;
+                        break;
+                    }
+                }
+            }
+            if !options.keep_alive {
+                connected = false;
             }
         }
 
-        send_stats(rate, bytes_sent, packets_error, &stats_tx).await;
+        let _ = stats_tx.send(StatPacket::Latency(latency)).await;
+        let _ = stats_tx.send(StatPacket::Statuses(statuses)).await;
+        send_reconnects(reconnects, &stats_tx).await;
+        send_stats(bytes_sent, packets_sent, errors, &stats_tx).await;
         maybe_sleep(start_time, one_sec).await;
     }
 }
 
-async fn send_stats(
-    rate: usize,
-    payload_len: usize,
-    packets_error: usize,
-    stats_tx: &AsyncSender<StatPacket>,
+/// Responses collected by the per-stream tasks of an HTTP/2 connection.
+#[derive(Default)]
+struct H2Round {
+    latency: Histogram,
+    statuses: BTreeMap<u16, usize>,
+    /// Requests left without a response, or with one late by more than
+    /// the response timeout.
+    errors: usize,
+}
+
+pub async fn sender_task_http2(
+    id: usize,
+    stream: Box<dyn AsyncStream>,
+    target: StreamTarget,
+    options: HttpOptions,
+    mut payloads: PayloadSource,
+    mut control: ClientControl,
+    stats_tx: StatsSender,
 ) {
-    let packets_sent = rate - packets_error;
+    debug!("client {id} spawned");
+    let one_sec = Duration::new(1, 0);
+    let (scheme, host) = (target.scheme(), target.host());
+    let streams = Arc::new(Semaphore::new(options.max_concurrent_streams.max(1)));
+    let round = Arc::new(Mutex::new(H2Round::default()));
+    let mut client = h2_handshake(stream).await;
+
+    loop {
+        let start_time = Instant::now();
+        let mut packets_sent = 0;
+        let mut bytes_sent = 0;
+        let mut errors = 0;
+        let mut reconnects = 0;
+
+        let rate = control.round(&mut payloads).await;
+        for sending in 0..rate {
+            let mut send_request = match client.take() {
+                Some(send_request) => send_request,
+                None => {
+                    let reconnected = match reconnect_ephemeral(id, &target).await {
+                        Ok(stream) => h2_handshake(stream).await,
+                        Err(_) => None,
+                    };
+                    let Some(send_request) = reconnected else {
+                        errors += rate - sending;
+                        break;
+                    };
+                    reconnects += 1;
+                    send_request
+                }
+            };
+
+            let Ok((request, body)) =
+                options.build_h2_request(&payloads.next_entry(), scheme, &host)
+            else {
+                // Invalid method, path or header in the payload entry
+                client = Some(send_request);
+                errors += 1;
+                continue;
+            };
+            let permit = streams.clone().acquire_owned().await.unwrap();
+            send_request = match send_request.ready().await {
+                Ok(send_request) => send_request,
+                Err(_) => {
+                    errors += 1;
+                    continue;
+                }
+            };
+            let sent_at = std::time::Instant::now();
+            let Ok((response, mut body_stream)) = send_request.send_request(request, body.is_empty())
+            else {
+                errors += 1;
+                continue;
+            };
+            let len = body.len();
+            if !body.is_empty() && body_stream.send_data(body, true).is_err() {
+                errors += 1;
+                continue;
+            }
+            packets_sent += 1;
+            bytes_sent += len;
+            client = Some(send_request);
+
+            let round = round.clone();
+            let response_timeout = options.response_timeout;
+            spawn(async move {
+                let response = async {
+                    let response = response.await.ok()?;
+                    let status = response.status().as_u16();
+                    let mut body = response.into_body();
+                    while let Some(Ok(chunk)) = body.data().await {
+                        let _ = body.flow_control().release_capacity(chunk.len());
+                    }
+                    Some(status)
+                };
+                // A stalled stream gives its slot back instead of holding it forever
+                let status = timeout(response_timeout, response).await.ok().flatten();
+                let mut round = round.lock().unwrap();
+                match status {
+                    Some(status) => {
+                        round.latency.record(sent_at.elapsed());
+                        *round.statuses.entry(status).or_insert(0) += 1;
+                    }
+                    None => round.errors += 1,
+                }
+                drop(permit);
+            });
+        }
+
+        let H2Round { latency, statuses, errors: failed } = std::mem::take(&mut *round.lock().unwrap());
+        let _ = stats_tx.send(StatPacket::Streams(packets_sent)).await;
+        let _ = stats_tx.send(StatPacket::Latency(latency)).await;
+        let _ = stats_tx.send(StatPacket::Statuses(statuses)).await;
+        send_reconnects(reconnects, &stats_tx).await;
+        send_stats(bytes_sent, packets_sent, errors + failed, &stats_tx).await;
+        maybe_sleep(start_time, one_sec).await;
+    }
+}
+
+async fn h2_handshake(stream: Box<dyn AsyncStream>) -> Option<h2::client::SendRequest<Bytes>> {
+    let (send_request, connection) = h2::client::handshake(stream).await.ok()?;
+    spawn(async move {
+        let _ = connection.await;
+    });
+    Some(send_request)
+}
+
+pub async fn sender_task_websocket(
+    id: usize,
+    stream: Box<dyn AsyncStream>,
+    target: StreamTarget,
+    options: WsOptions,
+    mut payloads: PayloadSource,
+    mut control: ClientControl,
+    stats_tx: StatsSender,
+) {
+    debug!("client {id} spawned");
+    let one_sec = Duration::new(1, 0);
+    let host = target.host();
+    let mut session = ws_connect(id, stream, &host, &options, &stats_tx).await;
+
+    loop {
+        let start_time = Instant::now();
+        let mut packets_sent = 0;
+        let mut bytes_sent = 0;
+        let mut errors = 0;
+        let mut reconnects = 0;
+        let mut received = WsRound::default();
+
+        let rate = control.round(&mut payloads).await;
+        for sending in 0..rate {
+            let ws = match session.as_ref().filter(|ws| !ws.is_closed()) {
+                Some(ws) => ws,
+                None => {
+                    if let Some(ws) = session.take() {
+                        received.merge(ws.take_round());
+                    }
+                    if let Ok(stream) = reconnect_ephemeral(id, &target).await {
+                        session = ws_connect(id, stream, &host, &options, &stats_tx).await;
+                    }
+                    let Some(ws) = &session else {
+                        errors += rate - sending;
+                        break;
+                    };
+                    reconnects += 1;
+                    ws
+                }
+            };
+
+            let payload = payloads.next_payload();
+            if ws.send(&payload).await.is_ok() {
+                packets_sent += 1;
+                bytes_sent += payload.len();
+            } else {
+                errors += 1;
+            }
+        }
+
+        if let Some(ws) = &session {
+            received.merge(ws.take_round());
+        }
+        // Echoes given up on never get their latency recorded
+        errors += received.lost;
+        let _ = stats_tx
+            .send(StatPacket::Received {
+                bytes: received.bytes,
+                packets: received.frames,
+            })
+            .await;
+        let _ = stats_tx.send(StatPacket::Latency(received.latency)).await;
+        send_reconnects(reconnects, &stats_tx).await;
+        send_stats(bytes_sent, packets_sent, errors, &stats_tx).await;
+        maybe_sleep(start_time, one_sec).await;
+    }
+}
+
+async fn ws_connect(
+    id: usize,
+    stream: Box<dyn AsyncStream>,
+    host: &str,
+    options: &WsOptions,
+    stats_tx: &StatsSender,
+) -> Option<WsSession> {
+    let started = std::time::Instant::now();
+    match WsSession::connect(stream, host, options).await {
+        Ok(session) => {
+            let _ = stats_tx.send(StatPacket::Handshake(started.elapsed())).await;
+            Some(session)
+        }
+        Err(err) => {
+            debug!("client {id} WebSocket upgrade failed: {err}");
+            None
+        }
+    }
+}
+
+async fn send_stats(bytes_sent: usize, packets_sent: usize, errors: usize, stats_tx: &StatsSender) {
+    if errors > 0 {
+        let _ = stats_tx.send(StatPacket::Errors(errors)).await;
+    }
     let _ = stats_tx
-        .send((packets_sent * payload_len, packets_sent))
+        .send(StatPacket::Traffic {
+            bytes: bytes_sent,
+            packets: packets_sent,
+        })
         .await;
 }
 
+/// Opens a new stream after the server closed the connection of `id`.
+async fn reconnect_ephemeral(id: usize, target: &StreamTarget) -> io::Result<Box<dyn AsyncStream>> {
+    // Reconnect from an ephemeral port, the original one may still be in TIME_WAIT
+    target
+        .connect(0)
+        .await
+        .inspect_err(|err| debug!("client {id} failed to reconnect: {err}"))
+}
+
+async fn send_reconnects(reconnects: usize, stats_tx: &StatsSender) {
+    if reconnects > 0 {
+        let _ = stats_tx.send(StatPacket::Reconnects(reconnects)).await;
+    }
+}
+
 async fn maybe_sleep(start_time: Instant, duration: Duration) {
     let time_elapsed = Instant::now() - start_time;
 
@@ -0,0 +1,273 @@
+//! Sequence header carried in UDP/DTLS datagrams, and the receive side
+//! tracking of loss, duplicates, reordering and jitter (RFC 3550).
+
+use std::{
+    collections::HashSet,
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+
+const MAGIC: u32 = 0x434E_5351; // "CNSQ"
+pub const HEADER_LEN: usize = 24;
+
+/// Sequence numbers further behind the highest one received are no longer
+/// checked for duplicates.
+const DUPLICATE_WINDOW: u64 = 65536;
+
+/// Prepended to every datagram: magic, client id, sequence number and send
+/// time in microseconds since the Unix epoch, all big endian.
+#[derive(Debug, Clone, Copy)]
+pub struct SequenceHeader {
+    pub client: u32,
+    pub seq: u64,
+    pub sent_us: u64,
+}
+
+impl SequenceHeader {
+    pub fn new(client: u32, seq: u64) -> Self {
+        Self {
+            client,
+            seq,
+            sent_us: now_us(),
+        }
+    }
+
+    pub fn prepend(&self, payload: &[u8]) -> Vec<u8> {
+        let mut datagram = Vec::with_capacity(HEADER_LEN + payload.len());
+        datagram.extend_from_slice(&MAGIC.to_be_bytes());
+        datagram.extend_from_slice(&self.client.to_be_bytes());
+        datagram.extend_from_slice(&self.seq.to_be_bytes());
+        datagram.extend_from_slice(&self.sent_us.to_be_bytes());
+        datagram.extend_from_slice(payload);
+        datagram
+    }
+
+    /// Reads the header at the start of `datagram`, if there is one.
+    pub fn parse(datagram: &[u8]) -> Option<Self> {
+        let header = datagram.get(..HEADER_LEN)?;
+        if header[..4] != MAGIC.to_be_bytes() {
+            return None;
+        }
+        Some(Self {
+            client: u32::from_be_bytes(header[4..8].try_into().ok()?),
+            seq: u64::from_be_bytes(header[8..16].try_into().ok()?),
+            sent_us: u64::from_be_bytes(header[16..24].try_into().ok()?),
+        })
+    }
+}
+
+fn now_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_micros() as u64
+}
+
+/// Sequence statistics over a period of time.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct SequenceStats {
+    /// Datagrams the sender numbered in the period.
+    pub expected: u64,
+    /// Distinct datagrams received in the period.
+    pub received: u64,
+    pub duplicates: u64,
+    /// Datagrams arriving after one with a higher sequence number.
+    pub reordered: u64,
+    /// Largest sequence distance of a reordered datagram.
+    pub max_reorder: u64,
+    /// Interarrival jitter estimate at the end of the period.
+    pub jitter: Duration,
+}
+
+impl SequenceStats {
+    pub fn lost(&self) -> u64 {
+        self.expected.saturating_sub(self.received)
+    }
+
+    pub fn loss_rate(&self) -> f64 {
+        self.lost() as f64 / self.expected.max(1) as f64
+    }
+
+    /// Appends the next period of the same client.
+    pub fn extend(&mut self, next: &SequenceStats) {
+        self.expected += next.expected;
+        self.received += next.received;
+        self.duplicates += next.duplicates;
+        self.reordered += next.reordered;
+        self.max_reorder = self.max_reorder.max(next.max_reorder);
+        self.jitter = next.jitter;
+    }
+
+    /// Combines the same period of different clients, keeping the worst jitter.
+    pub fn merge(&mut self, other: &SequenceStats) {
+        self.extend(other);
+        self.jitter = self.jitter.max(other.jitter);
+    }
+}
+
+impl fmt::Display for SequenceStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "received {}/{} (loss {:.2}%), duplicates {}, reordered {} (max distance {}), jitter {:.3} ms",
+            self.received,
+            self.expected,
+            self.loss_rate() * 100.,
+            self.duplicates,
+            self.reordered,
+            self.max_reorder,
+            self.jitter.as_secs_f64() * 1000.
+        )
+    }
+}
+
+/// Receive side state for the datagrams of one client.
+#[derive(Debug, Default)]
+pub struct SequenceTracker {
+    first: Option<u64>,
+    highest: u64,
+    seen: HashSet<u64>,
+    total: SequenceStats,
+    reported: SequenceStats,
+    jitter_us: f64,
+    last_transit: Option<i64>,
+}
+
+impl SequenceTracker {
+    pub fn record(&mut self, header: &SequenceHeader) {
+        let seq = header.seq;
+        let first = *self.first.get_or_insert(seq);
+        if self.total.received == 0 {
+            self.highest = seq;
+        }
+        // Datagrams older than the window cannot be told apart from duplicates
+        // and are counted as late arrivals
+        if seq.saturating_add(DUPLICATE_WINDOW) >= self.highest && !self.seen.insert(seq) {
+            self.total.duplicates += 1;
+            return;
+        }
+        self.total.received += 1;
+
+        if seq < self.highest {
+            self.total.reordered += 1;
+            self.total.max_reorder = self.total.max_reorder.max(self.highest - seq);
+        } else {
+            self.highest = seq;
+        }
+        self.first = Some(first.min(seq));
+        self.total.expected = self.highest - first.min(seq) + 1;
+
+        if self.seen.len() as u64 > 2 * DUPLICATE_WINDOW {
+            let floor = self.highest.saturating_sub(DUPLICATE_WINDOW);
+            self.seen.retain(|&seq| seq >= floor);
+        }
+
+        // Clock offsets between the hosts cancel out in the transit difference
+        let transit = now_us() as i64 - header.sent_us as i64;
+        if let Some(last) = self.last_transit {
+            let delta = (transit - last).unsigned_abs() as f64;
+            self.jitter_us += (delta - self.jitter_us) / 16.;
+        }
+        self.last_transit = Some(transit);
+        self.total.jitter = Duration::from_micros(self.jitter_us as u64);
+    }
+
+    /// Statistics since the previous call.
+    pub fn take_interval(&mut self) -> SequenceStats {
+        let interval = SequenceStats {
+            expected: self.total.expected - self.reported.expected,
+            received: self.total.received - self.reported.received,
+            duplicates: self.total.duplicates - self.reported.duplicates,
+            reordered: self.total.reordered - self.reported.reordered,
+            max_reorder: self.total.max_reorder,
+            jitter: self.total.jitter,
+        };
+        self.reported = self.total;
+        self.total.max_reorder = 0;
+        interval
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(seqs: impl IntoIterator<Item = u64>) -> SequenceTracker {
+        let mut tracker = SequenceTracker::default();
+        for seq in seqs {
+            tracker.record(&SequenceHeader::new(1, seq));
+        }
+        tracker
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let header = SequenceHeader::new(7, u64::MAX);
+        let datagram = header.prepend(b"data");
+        let parsed = SequenceHeader::parse(&datagram).unwrap();
+        assert_eq!((parsed.client, parsed.seq), (7, u64::MAX));
+        assert_eq!(&datagram[HEADER_LEN..], b"data");
+        assert!(SequenceHeader::parse(&datagram[..HEADER_LEN - 1]).is_none());
+        assert!(SequenceHeader::parse(&[0; HEADER_LEN]).is_none());
+    }
+
+    #[test]
+    fn counts_losses() {
+        let stats = track([0, 1, 3, 6]).take_interval();
+        assert_eq!((stats.expected, stats.received, stats.lost()), (7, 4, 3));
+        assert_eq!(stats.reordered, 0);
+    }
+
+    #[test]
+    fn counts_reordering_and_duplicates() {
+        let stats = track([0, 3, 1, 2, 3, 1]).take_interval();
+        assert_eq!((stats.expected, stats.received), (4, 4));
+        assert_eq!(stats.lost(), 0);
+        assert_eq!(stats.duplicates, 2);
+        assert_eq!(stats.reordered, 2);
+        assert_eq!(stats.max_reorder, 2);
+    }
+
+    #[test]
+    fn first_datagram_arriving_late() {
+        // The run started at 0 even if 2 arrived first
+        let stats = track([2, 0, 1]).take_interval();
+        assert_eq!((stats.expected, stats.received), (3, 3));
+        assert_eq!(stats.reordered, 2);
+    }
+
+    #[test]
+    fn sequence_numbers_near_the_end_of_the_range() {
+        let stats = track([u64::MAX - 2, u64::MAX, u64::MAX - 1, u64::MAX]).take_interval();
+        assert_eq!((stats.expected, stats.received), (3, 3));
+        assert_eq!((stats.duplicates, stats.reordered), (1, 1));
+    }
+
+    #[test]
+    fn intervals_report_the_difference() {
+        let mut tracker = track([0, 2, 1]);
+        let first = tracker.take_interval();
+        assert_eq!(
+            (first.expected, first.received, first.max_reorder),
+            (3, 3, 1)
+        );
+        tracker.record(&SequenceHeader::new(1, 5));
+        let second = tracker.take_interval();
+        assert_eq!((second.expected, second.received), (3, 1));
+        assert_eq!((second.reordered, second.max_reorder), (0, 0));
+        let mut total = first;
+        total.extend(&second);
+        assert_eq!((total.expected, total.lost()), (6, 2));
+    }
+
+    #[test]
+    fn datagrams_behind_the_window_are_late_not_duplicates() {
+        let mut tracker = track([0, DUPLICATE_WINDOW + 10]);
+        tracker.record(&SequenceHeader::new(1, 0));
+        let stats = tracker.take_interval();
+        assert_eq!(stats.duplicates, 0);
+        assert_eq!(stats.reordered, 1);
+    }
+}
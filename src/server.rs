@@ -0,0 +1,376 @@
+//! Built-in sink/echo server, useful to test crab-net itself or to measure
+//! the network path without the real service.
+
+use std::{collections::HashMap, fs, net::SocketAddr, path::Path, time::Duration};
+
+use derive_new::new;
+use log::{debug, error, info};
+use openssl::ssl::{SslContext, SslFiletype, SslMethod};
+use tokio::{
+    io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, UdpSocket},
+    select, spawn,
+    time::{interval_at, sleep, Instant},
+};
+use tokio_dtls_stream_sink::{Server, Session};
+use tokio_native_tls::{native_tls, TlsAcceptor};
+
+use crate::{
+    framing::Framing,
+    sequence::{SequenceHeader, SequenceTracker},
+    shutdown_signal,
+    statistics::{stats_task, StatPacket, StatsContext, StatsOptions, StatsSender},
+};
+
+/// Capacity of the channel towards the statistics task, the number of
+/// clients is not known in advance.
+const STATS_CHANNEL: usize = 1024;
+const READ_BUFFER: usize = 65536;
+/// Echoed data waiting for a stream client to read it, received data is not
+/// echoed beyond that.
+const ECHO_BUFFER: usize = 1 << 20;
+/// Pause after a failed accept, e.g. while out of file descriptors.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+/// UDP clients silent for this long are no longer tracked.
+const UDP_IDLE: Duration = Duration::from_secs(5);
+
+#[derive(new)]
+pub struct ServeParameters {
+    listen: SocketAddr,
+    udp: bool,
+    tls: bool,
+    echo: bool,
+    certs: CertFiles,         // Credentials for TLS/DTLS
+    framing: Framing,         // Splits TCP/TLS streams into messages
+    stats_interval: Duration, // Period of the statistics lines
+}
+
+/// PEM files of a TLS/DTLS server.
+#[derive(Debug, Clone, Default)]
+pub struct CertFiles {
+    /// Certificate chain.
+    pub cert: Option<String>,
+    /// PKCS#8 private key.
+    pub key: Option<String>,
+}
+
+/// Bound socket of the server, with the TLS/DTLS setup of its clients.
+enum Listener {
+    Udp(UdpSocket),
+    Dtls(UdpSocket, SslContext),
+    Tcp(TcpListener, Option<TlsAcceptor>),
+}
+
+/// Accepts clients until Ctrl+C, then prints the final statistics.
+pub async fn serve(params: ServeParameters) {
+    if params.tls && (params.certs.cert.is_none() || params.certs.key.is_none()) {
+        error!("TLS/DTLS server requires --cert and --key");
+        return;
+    }
+    // Certificates and the port are checked before any statistics line
+    let listener = match listen(&params).await {
+        Ok(listener) => listener,
+        Err(err) => {
+            error!("{err}");
+            return;
+        }
+    };
+
+    let options = StatsOptions {
+        interval: params.stats_interval,
+        ..StatsOptions::default()
+    };
+    let stats_tracker = stats_task(
+        STATS_CHANNEL,
+        StatsContext {
+            options,
+            ..StatsContext::default()
+        },
+    );
+    select! {
+        _ = accept_clients(&params, listener, stats_tracker.tx.clone()) => {}
+        _ = shutdown_signal() => {}
+    }
+    stats_tracker.finish().await;
+}
+
+async fn listen(params: &ServeParameters) -> Result<Listener, String> {
+    let listen = params.listen;
+    let bind_err = |err| format!("Failed to listen on {listen}: {err}");
+    if params.udp {
+        let ctx = params.tls.then(|| dtls_context(params)).transpose()?;
+        let socket = UdpSocket::bind(listen).await.map_err(bind_err)?;
+        Ok(match ctx {
+            Some(ctx) => Listener::Dtls(socket, ctx),
+            None => Listener::Udp(socket),
+        })
+    } else {
+        let acceptor = params.tls.then(|| tls_acceptor(params)).transpose()?;
+        let listener = TcpListener::bind(listen).await.map_err(bind_err)?;
+        Ok(Listener::Tcp(listener, acceptor))
+    }
+}
+
+fn dtls_context(params: &ServeParameters) -> Result<SslContext, String> {
+    let (cert, key) = (
+        params.certs.cert.as_ref().unwrap(),
+        params.certs.key.as_ref().unwrap(),
+    );
+    let mut ctx = SslContext::builder(SslMethod::dtls())
+        .map_err(|err| format!("Failed to set up DTLS: {err}"))?;
+    ctx.set_certificate_chain_file(cert)
+        .map_err(|err| format!("Failed to load certificate {cert}: {err}"))?;
+    ctx.set_private_key_file(key, SslFiletype::PEM)
+        .map_err(|err| format!("Failed to load private key {key}: {err}"))?;
+    ctx.check_private_key()
+        .map_err(|err| format!("Private key {key} does not match certificate {cert}: {err}"))?;
+    Ok(ctx.build())
+}
+
+fn tls_acceptor(params: &ServeParameters) -> Result<TlsAcceptor, String> {
+    let (cert_file, key_file) = (
+        params.certs.cert.as_ref().unwrap(),
+        params.certs.key.as_ref().unwrap(),
+    );
+    let cert = fs::read(Path::new(cert_file))
+        .map_err(|err| format!("Failed to read certificate {cert_file}: {err}"))?;
+    let key = fs::read(Path::new(key_file))
+        .map_err(|err| format!("Failed to read private key {key_file}: {err}"))?;
+    let identity = native_tls::Identity::from_pkcs8(&cert, &key).map_err(|err| {
+        format!("Failed to load certificate {cert_file} with key {key_file}: {err}")
+    })?;
+    let acceptor = native_tls::TlsAcceptor::new(identity)
+        .map_err(|err| format!("Failed to set up TLS: {err}"))?;
+    Ok(TlsAcceptor::from(acceptor))
+}
+
+async fn accept_clients(params: &ServeParameters, listener: Listener, stats_tx: StatsSender) {
+    match listener {
+        Listener::Udp(socket) => {
+            info!("Listening on {} (UDP)", params.listen);
+            serve_udp(socket, params.echo, stats_tx).await;
+        }
+        Listener::Dtls(socket, ctx) => {
+            info!("Listening on {} (DTLS)", params.listen);
+
+            let mut server = Server::new(socket);
+            loop {
+                match server.accept(Some(&ctx)).await {
+                    Ok(session) => {
+                        spawn(serve_session(session, params.echo, stats_tx.clone()));
+                    }
+                    Err(err) => debug!("Failed to accept datagram session: {err}"),
+                }
+            }
+        }
+        Listener::Tcp(listener, acceptor) => {
+            info!(
+                "Listening on {} ({})",
+                params.listen,
+                if acceptor.is_some() { "TLS" } else { "TCP" }
+            );
+
+            loop {
+                let (stream, peer) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(err) => {
+                        debug!("Failed to accept client: {err}");
+                        sleep(ACCEPT_BACKOFF).await;
+                        continue;
+                    }
+                };
+                let (echo, framing) = (params.echo, params.framing.clone());
+                let stats_tx = stats_tx.clone();
+                match acceptor.clone() {
+                    Some(acceptor) => spawn(async move {
+                        match acceptor.accept(stream).await {
+                            Ok(stream) => serve_stream(peer, stream, echo, framing, stats_tx).await,
+                            Err(err) => debug!("client {peer} TLS handshake failed: {err}"),
+                        }
+                    }),
+                    None => spawn(serve_stream(peer, stream, echo, framing, stats_tx)),
+                };
+            }
+        }
+    }
+}
+
+/// Traffic of one client since the last report.
+#[derive(Default)]
+struct Round {
+    packets: usize,
+    bytes: usize,
+    echoed_packets: usize,
+    echoed_bytes: usize,
+    max_gap: Duration,
+    last_packet: Option<Instant>,
+    sequence: Option<SequenceTracker>,
+}
+
+impl Round {
+    fn record_datagram(&mut self, datagram: &[u8]) {
+        if let Some(header) = SequenceHeader::parse(datagram) {
+            self.sequence
+                .get_or_insert_with(Default::default)
+                .record(&header);
+        }
+        self.record(1, datagram.len());
+    }
+
+    fn record(&mut self, packets: usize, bytes: usize) {
+        let now = Instant::now();
+        if let Some(last) = self.last_packet {
+            self.max_gap = self.max_gap.max(now - last);
+        }
+        self.last_packet = Some(now);
+        self.packets += packets;
+        self.bytes += bytes;
+    }
+
+    async fn flush(&mut self, peer: SocketAddr, stats_tx: &StatsSender) {
+        let _ = stats_tx
+            .send(StatPacket::Received {
+                bytes: self.bytes,
+                packets: self.packets,
+            })
+            .await;
+        if self.echoed_packets > 0 {
+            let _ = stats_tx
+                .send(StatPacket::Traffic {
+                    bytes: self.echoed_bytes,
+                    packets: self.echoed_packets,
+                })
+                .await;
+        }
+        let _ = stats_tx.send(StatPacket::Gap(self.max_gap)).await;
+        if let Some(tracker) = &mut self.sequence {
+            let stats = tracker.take_interval();
+            let peer = peer.to_string();
+            let _ = stats_tx.send(StatPacket::Sequence { peer, stats }).await;
+        }
+        *self = Round {
+            last_packet: self.last_packet,
+            sequence: self.sequence.take(),
+            ..Default::default()
+        };
+    }
+}
+
+async fn serve_stream<S: AsyncRead + AsyncWrite + Unpin>(
+    peer: SocketAddr,
+    stream: S,
+    echo: bool,
+    framing: Framing,
+    stats_tx: StatsSender,
+) {
+    debug!("client {peer} connected");
+    let one_sec = Duration::from_secs(1);
+    let mut timer = interval_at(Instant::now() + one_sec, one_sec);
+    let mut buf = vec![0; READ_BUFFER];
+    let mut pending = Vec::new();
+    // Received data not echoed yet, written as the client reads it
+    let mut echoes = Vec::new();
+    let mut round = Round::default();
+    let (mut reader, mut writer) = split(stream);
+
+    loop {
+        select! {
+            _ = timer.tick() => round.flush(peer, &stats_tx).await,
+            read = reader.read(&mut buf) => {
+                let len = match read {
+                    Ok(0) | Err(_) => break,
+                    Ok(len) => len,
+                };
+                pending.extend_from_slice(&buf[..len]);
+                let frames = framing.split(&mut pending);
+                round.record(frames, len);
+                // Reading goes on when the client does not read the echoes
+                if echo && echoes.len() + len <= ECHO_BUFFER {
+                    echoes.extend_from_slice(&buf[..len]);
+                    round.echoed_packets += frames;
+                    round.echoed_bytes += len;
+                }
+            }
+            written = writer.write(&echoes), if !echoes.is_empty() => match written {
+                Ok(0) | Err(_) => break,
+                Ok(len) => {
+                    echoes.drain(..len);
+                }
+            },
+        }
+    }
+    round.flush(peer, &stats_tx).await;
+    debug!("client {peer} disconnected");
+}
+
+/// Plain UDP is served from a single socket, tracking every client by its
+/// source address until it goes idle.
+async fn serve_udp(socket: UdpSocket, echo: bool, stats_tx: StatsSender) {
+    let one_sec = Duration::from_secs(1);
+    let mut timer = interval_at(Instant::now() + one_sec, one_sec);
+    let mut buf = vec![0; READ_BUFFER];
+    let mut rounds: HashMap<SocketAddr, Round> = HashMap::new();
+
+    loop {
+        select! {
+            _ = timer.tick() => {
+                for (peer, round) in rounds.iter_mut() {
+                    round.flush(*peer, &stats_tx).await;
+                }
+                rounds.retain(|peer, round| {
+                    let idle = round
+                        .last_packet
+                        .is_some_and(|last| last.elapsed() >= UDP_IDLE);
+                    if idle {
+                        debug!("client {peer} idle, no longer tracked");
+                    }
+                    !idle
+                });
+            }
+            read = socket.recv_from(&mut buf) => {
+                let Ok((len, peer)) = read else {
+                    continue;
+                };
+                let round = rounds.entry(peer).or_insert_with(|| {
+                    debug!("client {peer} connected");
+                    Round::default()
+                });
+                round.record_datagram(&buf[..len]);
+                if echo && socket.send_to(&buf[..len], peer).await.is_ok() {
+                    round.echoed_packets += 1;
+                    round.echoed_bytes += len;
+                }
+            }
+        }
+    }
+}
+
+async fn serve_session(mut session: Session, echo: bool, stats_tx: StatsSender) {
+    let peer = session.peer();
+    debug!("client {peer} connected");
+    let one_sec = Duration::from_secs(1);
+    let mut timer = interval_at(Instant::now() + one_sec, one_sec);
+    let mut buf = vec![0; READ_BUFFER];
+    let mut round = Round::default();
+
+    loop {
+        select! {
+            _ = timer.tick() => round.flush(peer, &stats_tx).await,
+            read = session.read(&mut buf) => {
+                let Ok(len) = read else {
+                    break;
+                };
+                round.record_datagram(&buf[..len]);
+                if echo {
+                    if session.write(&buf[..len]).await.is_err() {
+                        break;
+                    }
+                    round.echoed_packets += 1;
+                    round.echoed_bytes += len;
+                }
+            }
+        }
+    }
+    round.flush(peer, &stats_tx).await;
+    debug!("client {peer} disconnected");
+}
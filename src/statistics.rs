@@ -1,40 +1,420 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::future::pending;
 use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
 use std::time::Duration;
 
 use byte_unit::Byte;
+use serde::{Deserialize, Serialize};
 use kanal::{bounded_async, AsyncReceiver, AsyncSender};
-use log::info;
+use log::{debug, info, warn};
 use tokio::{
     select, spawn,
-    sync::mpsc::Sender as TokioSender,
-    time::{interval_at, Instant},
+    sync::{
+        mpsc::{Receiver as TokioReceiver, Sender as TokioSender},
+        oneshot,
+    },
+    task::JoinHandle,
+    time::{interval_at, timeout, Instant, MissedTickBehavior},
 };
+use time::OffsetDateTime;
 
-pub type StatPacket = (usize, usize);
+use crate::sequence::SequenceStats;
 
-// Struct to track total packets sent across the application
+/// Update sent by the client tasks to the statistics task.
+#[derive(Debug, Clone)]
+pub enum StatPacket {
+    /// Payload traffic of one sender round.
+    Traffic { bytes: usize, packets: usize },
+    /// A connection completed its handshake in the given time.
+    Handshake(Duration),
+    /// Application streams opened on multiplexed connections.
+    Streams(usize),
+    /// Request/response latencies of one sender round.
+    Latency(Histogram),
+    /// Response status code counts of one sender round.
+    Statuses(BTreeMap<u16, usize>),
+    /// Messages read from the peer during one round.
+    Received { bytes: usize, packets: usize },
+    /// Longest pause between two consecutive messages of a peer.
+    Gap(Duration),
+    /// Sequence tracking of the datagrams received from a peer.
+    Sequence { peer: String, stats: SequenceStats },
+    /// The client sends to the given target, when there are several.
+    Target(String),
+    /// Sends and requests of one sender round that failed.
+    Errors(usize),
+    /// Connections re-established during one sender round.
+    Reconnects(usize),
+    /// The client is connected and starts sending.
+    Connected,
+    /// The client stopped, removed or after failing to connect.
+    Closed,
+}
+
+/// Handle the client tasks report through, tagging every update with the
+/// id of the client it belongs to.
 #[derive(Clone)]
+pub struct StatsSender {
+    client: usize,
+    tx: AsyncSender<(usize, StatPacket)>,
+}
+
+impl StatsSender {
+    /// Same channel, reporting on behalf of `client`.
+    pub fn for_client(&self, client: usize) -> Self {
+        Self {
+            client,
+            tx: self.tx.clone(),
+        }
+    }
+
+    pub async fn send(&self, packet: StatPacket) -> Result<(), kanal::SendError> {
+        self.tx.send((self.client, packet)).await
+    }
+
+    /// Blocking counterpart of [`StatsSender::send`] for threads outside the runtime.
+    #[cfg_attr(not(all(target_os = "linux", feature = "io-uring")), allow(dead_code))]
+    pub fn send_blocking(&self, packet: StatPacket) -> Result<(), kanal::SendError> {
+        self.tx.as_sync().send((self.client, packet))
+    }
+}
+
+/// Connections sending at less than this share of the median rate are slow.
+const SLOW_RATE: f64 = 0.5;
+/// Connections answered this many times slower than the median are slow.
+const SLOW_LATENCY: f64 = 2.;
+/// Connections compared for slowness must have been sending this long.
+const MIN_ACTIVE: Duration = Duration::from_secs(2);
+/// Fewer connections have no meaningful median.
+const MIN_COMPARED: usize = 3;
+
+/// Sub-buckets per power of two, bounding the relative error to ~3%.
+const SUB_BUCKETS: u64 = 32;
+
+/// Log-linear latency histogram with microsecond resolution. Cheap to
+/// record into and to merge, so senders aggregate locally and ship whole
+/// histograms to the statistics task.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Histogram {
+    buckets: Vec<u64>,
+    count: u64,
+    sum_us: u64,
+    min_us: u64,
+    max_us: u64,
+}
+
+impl Histogram {
+    pub fn record(&mut self, value: Duration) {
+        let us = value.as_micros() as u64;
+        let idx = bucket_index(us);
+        if idx >= self.buckets.len() {
+            self.buckets.resize(idx + 1, 0);
+        }
+        self.buckets[idx] += 1;
+        if self.count == 0 || us < self.min_us {
+            self.min_us = us;
+        }
+        self.max_us = self.max_us.max(us);
+        self.sum_us += us;
+        self.count += 1;
+    }
+
+    pub fn merge(&mut self, other: &Histogram) {
+        if other.count == 0 {
+            return;
+        }
+        if other.buckets.len() > self.buckets.len() {
+            self.buckets.resize(other.buckets.len(), 0);
+        }
+        for (bucket, count) in self.buckets.iter_mut().zip(&other.buckets) {
+            *bucket += count;
+        }
+        if self.count == 0 || other.min_us < self.min_us {
+            self.min_us = other.min_us;
+        }
+        self.max_us = self.max_us.max(other.max_us);
+        self.sum_us += other.sum_us;
+        self.count += other.count;
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn mean(&self) -> Duration {
+        Duration::from_micros(self.sum_us / self.count.max(1))
+    }
+
+    pub fn max(&self) -> Duration {
+        Duration::from_micros(self.max_us)
+    }
+
+    /// Value below which `quantile` (0..=1) of the samples fall.
+    pub fn percentile(&self, quantile: f64) -> Duration {
+        let rank = ((self.count as f64 * quantile).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (idx, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let us = bucket_lower_bound(idx).clamp(self.min_us, self.max_us);
+                return Duration::from_micros(us);
+            }
+        }
+        self.max()
+    }
+}
+
+impl std::fmt::Display for Histogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let ms = |d: Duration| d.as_secs_f64() * 1000.;
+        write!(
+            f,
+            "{} (avg/p50/p90/p99/max {:.2}/{:.2}/{:.2}/{:.2}/{:.2} ms)",
+            self.count,
+            ms(self.mean()),
+            ms(self.percentile(0.5)),
+            ms(self.percentile(0.9)),
+            ms(self.percentile(0.99)),
+            ms(self.max())
+        )
+    }
+}
+
+fn bucket_index(us: u64) -> usize {
+    if us < SUB_BUCKETS {
+        return us as usize;
+    }
+    let shift = (63 - us.leading_zeros() as u64) - SUB_BUCKETS.trailing_zeros() as u64;
+    ((shift + 1) * SUB_BUCKETS + (us >> shift) - SUB_BUCKETS) as usize
+}
+
+fn bucket_lower_bound(idx: usize) -> u64 {
+    let idx = idx as u64;
+    if idx < SUB_BUCKETS {
+        return idx;
+    }
+    let shift = idx / SUB_BUCKETS - 1;
+    (idx % SUB_BUCKETS + SUB_BUCKETS) << shift
+}
+
+/// Totals of a whole run.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct RunReport {
+    /// From the start of the run until the clients stopped.
+    pub elapsed: Duration,
+    pub packets_sent: usize,
+    pub bytes_sent: u64,
+    pub packets_received: usize,
+    pub bytes_received: u64,
+    /// Sends and requests that failed.
+    pub errors: usize,
+    /// Connections re-established after the server closed them.
+    pub reconnects: usize,
+    /// Connection handshake times.
+    pub handshakes: Histogram,
+    /// Request/response and echo latencies.
+    pub latency: Histogram,
+    /// HTTP response status code counts.
+    pub status_codes: BTreeMap<u16, usize>,
+    /// Application streams opened on multiplexed connections.
+    pub streams_opened: usize,
+    /// Longest pause between two consecutive messages of a peer.
+    pub max_gap: Duration,
+    /// Traffic per target, when the clients were spread over several.
+    pub targets: BTreeMap<String, TargetReport>,
+    /// Sequence tracking per peer echoing datagrams back.
+    pub sequence: BTreeMap<String, SequenceStats>,
+    /// Traffic per client id, only filled in the final report.
+    pub connections: BTreeMap<usize, ConnectionReport>,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct TargetReport {
+    pub clients: usize,
+    pub packets_sent: usize,
+    pub bytes_sent: u64,
+}
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ConnectionReport {
+    pub packets_sent: usize,
+    pub bytes_sent: u64,
+    pub errors: usize,
+    pub reconnects: usize,
+    /// Packets per second achieved while connected.
+    pub rate: f64,
+    /// Request/response and echo latencies.
+    pub latency: Histogram,
+    /// Sending at less than half the median rate of the connections, or
+    /// answered more than twice as slowly on average.
+    pub slow: bool,
+}
+
+impl fmt::Display for ConnectionReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Sent {} packets --- Rate {:.1} pkt/s",
+            self.packets_sent, self.rate
+        )?;
+        if self.errors > 0 {
+            write!(f, " --- Errors: {}", self.errors)?;
+        }
+        if self.reconnects > 0 {
+            write!(f, " --- Reconnects: {}", self.reconnects)?;
+        }
+        if self.latency.count() > 0 {
+            write!(f, " --- Responses: {}", self.latency)?;
+        }
+        if self.slow {
+            write!(f, " --- SLOW")?;
+        }
+        Ok(())
+    }
+}
+
+impl RunReport {
+    /// Adds the totals of a run that went on side by side, e.g. on another
+    /// host. The per-connection statistics are left out, client ids of
+    /// separate runs overlap.
+    pub fn merge(&mut self, other: &RunReport) {
+        self.elapsed = self.elapsed.max(other.elapsed);
+        self.packets_sent += other.packets_sent;
+        self.bytes_sent += other.bytes_sent;
+        self.packets_received += other.packets_received;
+        self.bytes_received += other.bytes_received;
+        self.errors += other.errors;
+        self.reconnects += other.reconnects;
+        self.handshakes.merge(&other.handshakes);
+        self.latency.merge(&other.latency);
+        for (code, count) in &other.status_codes {
+            *self.status_codes.entry(*code).or_insert(0) += count;
+        }
+        self.streams_opened += other.streams_opened;
+        self.max_gap = self.max_gap.max(other.max_gap);
+        for (target, report) in &other.targets {
+            let totals = self.targets.entry(target.clone()).or_default();
+            totals.clients += report.clients;
+            totals.packets_sent += report.packets_sent;
+            totals.bytes_sent += report.bytes_sent;
+        }
+        for (peer, stats) in &other.sequence {
+            self.sequence.entry(peer.clone()).or_default().merge(stats);
+        }
+    }
+
+    /// Ids of the connections far behind the others.
+    pub fn slow_connections(&self) -> Vec<usize> {
+        self.connections
+            .iter()
+            .filter(|(_, report)| report.slow)
+            .map(|(id, _)| *id)
+            .collect()
+    }
+}
+
+// Struct to track total packets sent across the application
 pub struct StatsTracker {
     pub total_packets: Arc<AtomicUsize>,
-    pub tx: AsyncSender<StatPacket>,
+    pub tx: StatsSender,
+    finish_tx: oneshot::Sender<()>,
+    handle: JoinHandle<RunReport>,
 }
 
 impl StatsTracker {
-    pub fn new(tx: AsyncSender<StatPacket>, total_packets: Arc<AtomicUsize>) -> Self {
-        Self { tx, total_packets }
+    /// Stops the statistics task once it has printed the final report and
+    /// returns the totals of the run.
+    pub async fn finish(self) -> RunReport {
+        let _ = self.finish_tx.send(());
+        match timeout(Duration::from_secs(1), self.handle).await {
+            Ok(Ok(report)) => report,
+            // The statistics task is stuck, the packet counter is still right
+            _ => RunReport {
+                packets_sent: self.total_packets.load(Ordering::Relaxed),
+                ..Default::default()
+            },
+        }
     }
+}
+
+/// Requests for the totals of a running test, answered on the enclosed
+/// channel.
+pub type SnapshotReceiver = TokioReceiver<oneshot::Sender<RunReport>>;
+
+/// One second of a running test, streamed to the dashboard.
+#[derive(Debug, Clone)]
+pub(crate) struct Sample {
+    pub packets_sent: usize,
+    pub bytes_sent: u64,
+    pub packets_received: usize,
+    pub errors: usize,
+    pub latency: Histogram,
+    /// Totals of the test so far.
+    pub totals: RunReport,
+    pub connections: Vec<ConnectionSample>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ConnectionState {
+    /// Sent during the last couple of seconds.
+    Active,
+    Idle,
+    /// Sends or requests failed during the last second.
+    Failing,
+    Closed,
+}
 
-    pub fn get_total_packets(&self) -> usize {
-        self.total_packets.load(Ordering::Relaxed)
+#[derive(Debug, Clone)]
+pub(crate) struct ConnectionSample {
+    pub id: usize,
+    pub state: ConnectionState,
+    pub packets_sent: usize,
+    pub bytes_sent: u64,
+    pub errors: usize,
+    pub reconnects: usize,
+    /// Packets sent during the last second.
+    pub rate: usize,
+}
+
+/// How the statistics of a run are reported.
+#[derive(Debug, Clone)]
+pub struct StatsOptions {
+    /// Client group prefixed to the statistics lines.
+    pub label: Option<String>,
+    /// Period of the statistics lines.
+    pub interval: Duration,
+    /// Log every connection with each statistics line.
+    pub per_connection: bool,
+    /// Show the terminal dashboard instead of the statistics lines.
+    pub tui: bool,
+}
+
+impl Default for StatsOptions {
+    fn default() -> Self {
+        Self {
+            label: None,
+            interval: Duration::from_secs(10),
+            per_connection: false,
+            tui: false,
+        }
     }
 }
 
-pub fn stats_task(
-    clients: usize, 
-    max_packets: Option<usize>,
-    quit_tx: Option<TokioSender<()>>,
-) -> StatsTracker {
+/// Settings and outlets of the statistics task.
+#[derive(Default)]
+pub(crate) struct StatsContext {
+    pub options: StatsOptions,
+    /// Packets sent before `quit_tx` ends the run.
+    pub max_packets: Option<usize>,
+    pub quit_tx: Option<TokioSender<()>>,
+    pub snapshots: Option<SnapshotReceiver>,
+    /// Per second samples of the dashboard.
+    pub samples: Option<TokioSender<Sample>>,
+}
+
+pub fn stats_task(clients: usize, context: StatsContext) -> StatsTracker {
     // Define channel to send statistics update
     let (stats_tx, stats_rx) = bounded_async(clients);
     
@@ -42,58 +422,744 @@ pub fn stats_task(
     let total_packets = Arc::new(AtomicUsize::new(0));
     let total_packets_clone = total_packets.clone();
     
-    spawn(async move {
-        stats_loop(stats_rx, total_packets_clone, max_packets, quit_tx).await;
+    let (finish_tx, finish_rx) = oneshot::channel();
+    let handle = spawn(async move {
+        stats_loop(stats_rx, total_packets_clone, finish_rx, context).await
     });
     
-    StatsTracker::new(stats_tx, total_packets)
+    StatsTracker {
+        total_packets,
+        tx: StatsSender {
+            client: 0,
+            tx: stats_tx,
+        },
+        finish_tx,
+        handle,
+    }
 }
 
 async fn stats_loop(
-    stats_rx: AsyncReceiver<StatPacket>,
+    stats_rx: AsyncReceiver<(usize, StatPacket)>,
     total_packets: Arc<AtomicUsize>,
-    max_packets: Option<usize>,
-    quit_tx: Option<TokioSender<()>>,
-) {
-    let timer_duration = 10.;
-    let duration = Duration::from_secs(timer_duration as u64);
-    let mut timer = interval_at(Instant::now() + duration, duration);
+    mut finish_rx: oneshot::Receiver<()>,
+    context: StatsContext,
+) -> RunReport {
+    let StatsContext { options, max_packets, quit_tx, mut snapshots, samples } = context;
+    let StatsOptions { label, interval, per_connection, .. } = options;
+    let started = Instant::now();
+    // Tells apart the reports of client groups running side by side
+    let prefix = label.map_or(String::new(), |label| format!("[{label}] "));
+    let mut timer = interval_at(Instant::now() + interval, interval);
+    // A stalled loop must not be followed by a burst of near empty windows
+    timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut window_start = Instant::now();
+    let second = Duration::from_secs(1);
+    let mut sample_timer = interval_at(Instant::now() + second, second);
 
-    let mut bytes_sent = 0.;
-    let mut interval_packets_sent = 0;
+    let mut window = IntervalStats::default();
+    let mut total_received = 0;
+    let mut sample = SampleWindow::default();
+    let mut connections: BTreeMap<usize, ConnectionStats> = BTreeMap::new();
+    let mut sequence_peers: BTreeMap<String, SequenceStats> = BTreeMap::new();
+    let mut client_targets: HashMap<usize, String> = HashMap::new();
+    let mut targets: BTreeMap<String, TargetStats> = BTreeMap::new();
+    let mut totals = RunReport::default();
     
     loop {
         select! {
             _ = timer.tick() => {
-                bytes_sent *= 8.;
-                let bandwidth = Byte::from_f64(bytes_sent / timer_duration)
-                    .unwrap_or_default()
-                    .get_appropriate_unit(byte_unit::UnitType::Decimal)
-                    .to_string();
-                let bandwidth = &bandwidth[0..bandwidth.len()-1];
-                
+                let now = Instant::now();
                 let total = total_packets.load(Ordering::Relaxed);
-                info!("Sent {interval_packets_sent} packets --- Bandwidth {bandwidth}bit/s --- Total packets: {total}");
-                
-                bytes_sent = 0.;
-                interval_packets_sent = 0;
+                let stamp = timestamp(now - started);
+                window.log(&prefix, &stamp, now - window_start, total, total_received, &mut targets);
+                log_connections(&prefix, &mut connections, window_start, now, per_connection);
+                window = IntervalStats::default();
+                window_start = now;
             }
-            stat = stats_rx.recv() => if let Ok((bytes, packets)) = stat {
-                bytes_sent += bytes as f64;
-                interval_packets_sent += packets;
-                
-                // Update total packets counter
-                let new_total = total_packets.fetch_add(packets, Ordering::Relaxed) + packets;
-                
-                // Check if we've reached the maximum packets limit
-                if let Some(max) = max_packets {
-                    if new_total >= max && quit_tx.is_some() {
-                        let _ = quit_tx.unwrap().send(()).await;
-                        info!("Reached target of {max} packets. Total sent: {new_total}");
-                        break;
+            _ = sample_timer.tick(), if samples.is_some() => {
+                let mut totals = current_report(&totals, &total_packets, total_received, &targets, &sequence_peers);
+                totals.elapsed = started.elapsed();
+                let now = Instant::now();
+                let connections = connections
+                    .iter_mut()
+                    .map(|(id, stats)| stats.take_sample(*id, now))
+                    .collect();
+                let window = std::mem::take(&mut sample);
+                let update = Sample {
+                    packets_sent: window.packets_sent,
+                    bytes_sent: window.bytes_sent,
+                    packets_received: window.packets_received,
+                    errors: window.errors,
+                    latency: window.latency,
+                    totals,
+                    connections,
+                };
+                // A dashboard lagging behind skips samples rather than stalling the statistics
+                let _ = samples.as_ref().unwrap().try_send(update);
+            }
+            stat = stats_rx.recv() => match stat {
+                Ok((client, StatPacket::Traffic { bytes, packets })) => {
+                    window.bytes_sent += bytes as f64;
+                    window.packets_sent += packets;
+                    totals.bytes_sent += bytes as u64;
+                    sample.packets_sent += packets;
+                    sample.bytes_sent += bytes as u64;
+                    connections.entry(client).or_default().record(bytes, packets);
+                    if let Some(target) = client_targets.get(&client).and_then(|t| targets.get_mut(t)) {
+                        target.bytes += bytes as f64;
+                        target.packets += packets;
+                        target.total_packets += packets;
+                        target.total_bytes += bytes as u64;
+                    }
+
+                    // Update total packets counter
+                    let new_total = total_packets.fetch_add(packets, Ordering::Relaxed) + packets;
+
+                    // Check if we've reached the maximum packets limit
+                    if let Some(max) = max_packets {
+                        if let Some(quit_tx) = quit_tx.as_ref().filter(|_| new_total >= max) {
+                            let _ = quit_tx.send(()).await;
+                            info!("{prefix}Reached target of {max} packets. Total sent: {new_total}");
+                            break;
+                        }
+                    }
+                }
+                Ok((_, StatPacket::Handshake(elapsed))) => {
+                    window.handshakes.record(elapsed);
+                    totals.handshakes.record(elapsed);
+                }
+                Ok((_, StatPacket::Streams(count))) => {
+                    window.streams_opened += count;
+                    totals.streams_opened += count;
+                }
+                Ok((client, StatPacket::Latency(histogram))) => {
+                    window.latency.merge(&histogram);
+                    totals.latency.merge(&histogram);
+                    sample.latency.merge(&histogram);
+                    if histogram.count() > 0 {
+                        let connection = connections.entry(client).or_default();
+                        connection.totals.latency.merge(&histogram);
+                        connection.interval.latency.merge(&histogram);
                     }
                 }
+                Ok((_, StatPacket::Statuses(counts))) => {
+                    for (code, count) in counts {
+                        *window.statuses.entry(code).or_insert(0) += count;
+                        *totals.status_codes.entry(code).or_insert(0) += count;
+                    }
+                }
+                Ok((_, StatPacket::Received { bytes, packets })) => {
+                    window.received_bytes += bytes as f64;
+                    window.received_packets += packets;
+                    total_received += packets;
+                    totals.bytes_received += bytes as u64;
+                    sample.packets_received += packets;
+                }
+                Ok((_, StatPacket::Gap(gap))) => {
+                    window.max_gap = window.max_gap.max(gap);
+                    totals.max_gap = totals.max_gap.max(gap);
+                }
+                Ok((_, StatPacket::Sequence { peer, stats })) => {
+                    if stats.expected > 0 || stats.duplicates > 0 {
+                        debug!("{peer}: {stats}");
+                    }
+                    window.sequence.get_or_insert_with(SequenceStats::default).merge(&stats);
+                    sequence_peers.entry(peer).or_default().extend(&stats);
+                }
+                Ok((client, StatPacket::Target(target))) => {
+                    targets.entry(target.clone()).or_default().clients += 1;
+                    client_targets.insert(client, target);
+                }
+                Ok((client, StatPacket::Errors(count))) => {
+                    window.errors += count;
+                    totals.errors += count;
+                    sample.errors += count;
+                    let connection = connections.entry(client).or_default();
+                    connection.totals.errors += count;
+                    connection.interval.errors += count;
+                    connection.sample_errors += count;
+                }
+                Ok((client, StatPacket::Reconnects(count))) => {
+                    window.reconnects += count;
+                    totals.reconnects += count;
+                    let connection = connections.entry(client).or_default();
+                    connection.totals.reconnects += count;
+                    connection.interval.reconnects += count;
+                }
+                Ok((client, StatPacket::Connected)) => {
+                    connections.entry(client).or_default().connected_at = Some(Instant::now());
+                }
+                Ok((client, StatPacket::Closed)) => {
+                    connections.entry(client).or_default().closed_at = Some(Instant::now());
+                }
+                Err(_) => break,
+            },
+            Some(reply) = async {
+                match &mut snapshots {
+                    Some(snapshots) => snapshots.recv().await,
+                    None => pending().await,
+                }
+            } => {
+                let mut snapshot = current_report(&totals, &total_packets, total_received, &targets, &sequence_peers);
+                snapshot.elapsed = started.elapsed();
+                let _ = reply.send(snapshot);
+            }
+            _ = &mut finish_rx => break,
+        }
+    }
+
+    // The last window is usually cut short by the end of the test
+    if !window.is_empty() {
+        let now = Instant::now();
+        let total = total_packets.load(Ordering::Relaxed);
+        let window_secs = now - window_start;
+        let stamp = format!("{}, last {:.3}s", timestamp(now - started), window_secs.as_secs_f64());
+        window.log(&prefix, &stamp, window_secs, total, total_received, &mut targets);
+        log_connections(&prefix, &mut connections, window_start, now, per_connection);
+    }
+
+    for (target, stats) in &targets {
+        info!("{prefix}Final statistics for {target}: {} clients, {} packets sent", stats.clients, stats.total_packets);
+    }
+    for (peer, stats) in &sequence_peers {
+        info!("{prefix}Final sequence statistics for {peer}: {stats}");
+    }
+
+    let mut report = current_report(&totals, &total_packets, total_received, &targets, &sequence_peers);
+    let now = Instant::now();
+    let mut reports: Vec<_> = connections
+        .iter()
+        .map(|(id, stats)| (*id, stats.report(now), stats.active_time(started, now)))
+        .collect();
+    flag_slow(&mut reports);
+    report.connections = reports.into_iter().map(|(id, report, _)| (id, report)).collect();
+    if per_connection {
+        for (id, connection) in &report.connections {
+            info!("{prefix}Final statistics for client {id}: {connection}");
+        }
+    }
+    log_slow(&prefix, "Slow connections over the whole run", &report.slow_connections());
+    report
+}
+
+/// Logs the connections falling behind during the window that started at
+/// `window_start`, and with `per_connection` every connection, then starts
+/// their next window.
+fn log_connections(
+    prefix: &str,
+    connections: &mut BTreeMap<usize, ConnectionStats>,
+    window_start: Instant,
+    now: Instant,
+    per_connection: bool,
+) {
+    let mut reports: Vec<_> = connections
+        .iter_mut()
+        .filter(|(_, stats)| stats.closed_at.is_none_or(|closed_at| closed_at > window_start))
+        .map(|(id, stats)| {
+            let active = stats.active_time(window_start, now);
+            (*id, stats.take_interval(active), active)
+        })
+        .collect();
+    flag_slow(&mut reports);
+    if per_connection {
+        for (id, report, _) in &reports {
+            info!("  {prefix}client {id}: {report}");
+        }
+    }
+    let slow: Vec<_> = reports
+        .iter()
+        .filter(|(_, report, _)| report.slow)
+        .map(|(id, _, _)| *id)
+        .collect();
+    log_slow(prefix, "Slow connections", &slow);
+}
+
+fn log_slow(prefix: &str, title: &str, slow: &[usize]) {
+    if slow.is_empty() {
+        return;
+    }
+    let mut ids: Vec<_> = slow.iter().take(20).map(usize::to_string).collect();
+    if slow.len() > ids.len() {
+        ids.push(format!("and {} more", slow.len() - ids.len()));
+    }
+    warn!("{prefix}{title}: {}", ids.join(", "));
+}
+
+/// Flags the connections sending at less than [`SLOW_RATE`] times the
+/// median rate, or answered [`SLOW_LATENCY`] times slower than the median,
+/// among those sending for at least [`MIN_ACTIVE`].
+fn flag_slow(reports: &mut [(usize, ConnectionReport, Duration)]) {
+    let compared = |active: &Duration| *active >= MIN_ACTIVE;
+    let rates = reports
+        .iter()
+        .filter(|(_, _, active)| compared(active))
+        .map(|(_, report, _)| report.rate);
+    let latencies = reports
+        .iter()
+        .filter(|(_, report, active)| compared(active) && report.latency.count() > 0)
+        .map(|(_, report, _)| report.latency.mean().as_secs_f64());
+    let median_rate = median(rates.collect());
+    let median_latency = median(latencies.collect());
+    for (_, report, active) in reports.iter_mut() {
+        if !compared(active) {
+            continue;
+        }
+        let slow_rate = median_rate.is_some_and(|median| report.rate < median * SLOW_RATE);
+        let slow_latency = median_latency.is_some_and(|median| {
+            report.latency.count() > 0 && report.latency.mean().as_secs_f64() > median * SLOW_LATENCY
+        });
+        report.slow = slow_rate || slow_latency;
+    }
+}
+
+fn median(mut values: Vec<f64>) -> Option<f64> {
+    if values.len() < MIN_COMPARED {
+        return None;
+    }
+    values.sort_by(f64::total_cmp);
+    let mid = values.len() / 2;
+    match values.len() % 2 {
+        0 => Some((values[mid - 1] + values[mid]) / 2.),
+        _ => Some(values[mid]),
+    }
+}
+
+/// Completes the counters accumulated in `totals` with the ones tracked
+/// separately.
+fn current_report(
+    totals: &RunReport,
+    total_packets: &AtomicUsize,
+    total_received: usize,
+    targets: &BTreeMap<String, TargetStats>,
+    sequence_peers: &BTreeMap<String, SequenceStats>,
+) -> RunReport {
+    let targets = targets
+        .iter()
+        .map(|(target, stats)| {
+            let report = TargetReport {
+                clients: stats.clients,
+                packets_sent: stats.total_packets,
+                bytes_sent: stats.total_bytes,
+            };
+            (target.clone(), report)
+        })
+        .collect();
+    RunReport {
+        packets_sent: total_packets.load(Ordering::Relaxed),
+        packets_received: total_received,
+        targets,
+        sequence: sequence_peers.clone(),
+        ..totals.clone()
+    }
+}
+
+/// Counters of the current statistics line.
+#[derive(Default)]
+struct IntervalStats {
+    bytes_sent: f64,
+    packets_sent: usize,
+    handshakes: Histogram,
+    streams_opened: usize,
+    latency: Histogram,
+    statuses: BTreeMap<u16, usize>,
+    received_packets: usize,
+    received_bytes: f64,
+    max_gap: Duration,
+    errors: usize,
+    reconnects: usize,
+    sequence: Option<SequenceStats>,
+}
+
+impl IntervalStats {
+    fn is_empty(&self) -> bool {
+        self.packets_sent == 0
+            && self.received_packets == 0
+            && self.errors == 0
+            && self.handshakes.count() == 0
+    }
+
+    /// Logs the statistics of a window lasting `elapsed`, rates included,
+    /// then clears the per-target window counters.
+    fn log(
+        &self,
+        prefix: &str,
+        stamp: &str,
+        elapsed: Duration,
+        total: usize,
+        total_received: usize,
+        targets: &mut BTreeMap<String, TargetStats>,
+    ) {
+        let seconds = elapsed.as_secs_f64();
+        let bandwidth = bit_rate(self.bytes_sent, seconds);
+        // A sink that does not echo has nothing to say about sent traffic
+        let mut report = match self.packets_sent == 0 && self.received_packets > 0 {
+            true => String::new(),
+            false => format!(
+                "Sent {} packets --- Bandwidth {bandwidth}bit/s --- Total packets: {total}",
+                self.packets_sent
+            ),
+        };
+        if self.handshakes.count() > 0 {
+            report += &format!(" --- Handshakes: {}", self.handshakes);
+        }
+        if self.streams_opened > 0 {
+            report += &format!(" --- Streams opened: {}", self.streams_opened);
+        }
+        if self.received_packets > 0 {
+            let received_bandwidth = bit_rate(self.received_bytes, seconds);
+            if !report.is_empty() {
+                report += " --- ";
+            }
+            report += &format!(
+                "Received {} packets --- Bandwidth {received_bandwidth}bit/s --- Total received: {total_received}",
+                self.received_packets
+            );
+        }
+        if let Some(stats) = &self.sequence {
+            report += &format!(" --- Sequence: {stats}");
+        }
+        if self.max_gap > Duration::ZERO {
+            report += &format!(" --- Longest gap: {:.2} ms", self.max_gap.as_secs_f64() * 1000.);
+        }
+        if self.latency.count() > 0 {
+            report += &format!(" --- Responses: {}", self.latency);
+        }
+        if self.errors > 0 {
+            report += &format!(" --- Errors: {}", self.errors);
+        }
+        if self.reconnects > 0 {
+            report += &format!(" --- Reconnects: {}", self.reconnects);
+        }
+        if !self.statuses.is_empty() {
+            let codes: Vec<_> = self
+                .statuses
+                .iter()
+                .map(|(code, count)| format!("{code}: {count}"))
+                .collect();
+            report += &format!(" --- Status codes: {}", codes.join(", "));
+        }
+        info!("{prefix}[{stamp}] {report}");
+        for (target, stats) in targets.iter_mut() {
+            let bandwidth = bit_rate(stats.bytes, seconds);
+            info!(
+                "  {prefix}{target} ({} clients): Sent {} packets --- Bandwidth {bandwidth}bit/s --- Total packets: {}",
+                stats.clients, stats.packets, stats.total_packets
+            );
+            stats.packets = 0;
+            stats.bytes = 0.;
+        }
+    }
+}
+
+/// Wall-clock time in UTC followed by the time elapsed since the start.
+fn timestamp(elapsed: Duration) -> String {
+    let now = OffsetDateTime::now_utc();
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z +{:.3}s",
+        now.year(),
+        now.month() as u8,
+        now.day(),
+        now.hour(),
+        now.minute(),
+        now.second(),
+        now.millisecond(),
+        elapsed.as_secs_f64()
+    )
+}
+
+/// Counters of the dashboard's current one second window.
+#[derive(Default)]
+struct SampleWindow {
+    packets_sent: usize,
+    bytes_sent: u64,
+    packets_received: usize,
+    errors: usize,
+    latency: Histogram,
+}
+
+/// Traffic of a single client.
+#[derive(Default)]
+struct ConnectionStats {
+    connected_at: Option<Instant>,
+    closed_at: Option<Instant>,
+    last_sent: Option<Instant>,
+    totals: ConnectionReport,
+    /// Since the last statistics line.
+    interval: ConnectionReport,
+    /// Since the last dashboard sample.
+    sample_packets: usize,
+    sample_errors: usize,
+}
+
+impl ConnectionStats {
+    fn record(&mut self, bytes: usize, packets: usize) {
+        for counters in [&mut self.totals, &mut self.interval] {
+            counters.packets_sent += packets;
+            counters.bytes_sent += bytes as u64;
+        }
+        self.sample_packets += packets;
+        if packets > 0 {
+            self.last_sent = Some(Instant::now());
+        }
+    }
+
+    /// Time spent connected between `since` and `now`.
+    fn active_time(&self, since: Instant, now: Instant) -> Duration {
+        match self.connected_at {
+            Some(connected_at) => {
+                let end = self.closed_at.unwrap_or(now);
+                end.saturating_duration_since(connected_at.max(since))
             }
+            None => Duration::ZERO,
+        }
+    }
+
+    /// Totals with the rate achieved since connecting.
+    fn report(&self, now: Instant) -> ConnectionReport {
+        let mut report = self.totals.clone();
+        if let Some(connected_at) = self.connected_at {
+            report.rate = rate(report.packets_sent, self.active_time(connected_at, now));
+        }
+        report
+    }
+
+    /// Closes the current statistics window, connected for `active`.
+    fn take_interval(&mut self, active: Duration) -> ConnectionReport {
+        let mut report = std::mem::take(&mut self.interval);
+        report.rate = rate(report.packets_sent, active);
+        report
+    }
+
+    /// Closes the current dashboard window.
+    fn take_sample(&mut self, id: usize, now: Instant) -> ConnectionSample {
+        let active = self
+            .last_sent
+            .is_some_and(|last_sent| now - last_sent < Duration::from_secs(2));
+        let state = if self.closed_at.is_some() {
+            ConnectionState::Closed
+        } else if self.sample_errors > 0 {
+            ConnectionState::Failing
+        } else if active {
+            ConnectionState::Active
+        } else {
+            ConnectionState::Idle
+        };
+        let sample = ConnectionSample {
+            id,
+            state,
+            packets_sent: self.totals.packets_sent,
+            bytes_sent: self.totals.bytes_sent,
+            errors: self.totals.errors,
+            reconnects: self.totals.reconnects,
+            rate: self.sample_packets,
+        };
+        self.sample_packets = 0;
+        self.sample_errors = 0;
+        sample
+    }
+}
+
+fn rate(packets: usize, elapsed: Duration) -> f64 {
+    match elapsed.is_zero() {
+        true => 0.,
+        false => packets as f64 / elapsed.as_secs_f64(),
+    }
+}
+
+/// Traffic sent to one of several targets.
+#[derive(Default)]
+struct TargetStats {
+    clients: usize,
+    packets: usize,
+    bytes: f64,
+    total_packets: usize,
+    total_bytes: u64,
+}
+
+/// Formats `bytes` transferred over `seconds` as a decimal bit rate without
+/// the trailing unit letter, e.g. `12.5 M`.
+pub(crate) fn bit_rate(bytes: f64, seconds: f64) -> String {
+    let rate = Byte::from_f64(bytes * 8. / seconds)
+        .unwrap_or_default()
+        .get_appropriate_unit(byte_unit::UnitType::Decimal)
+        .to_string();
+    rate[0..rate.len() - 1].to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn histogram(values_us: impl IntoIterator<Item = u64>) -> Histogram {
+        let mut histogram = Histogram::default();
+        for us in values_us {
+            histogram.record(Duration::from_micros(us));
+        }
+        histogram
+    }
+
+    #[test]
+    fn bucket_index_edges() {
+        // One bucket per microsecond below SUB_BUCKETS
+        assert_eq!(bucket_index(0), 0);
+        assert_eq!(bucket_index(31), 31);
+        // Then SUB_BUCKETS buckets per power of two
+        assert_eq!(bucket_index(32), 32);
+        assert_eq!(bucket_index(63), 63);
+        assert_eq!(bucket_index(64), 64);
+        assert_eq!(bucket_index(65), 64);
+        assert_eq!(bucket_index(127), 95);
+        assert_eq!(bucket_index(128), 96);
+        assert_eq!(bucket_index(u64::MAX), 1919);
+        assert_eq!(bucket_lower_bound(1919), 63 << 58);
+    }
+
+    #[test]
+    fn bucket_bounds_round_trip() {
+        for idx in 0..1920 {
+            assert_eq!(bucket_index(bucket_lower_bound(idx)), idx);
         }
+        let powers = (0..20).map(|shift| 1000u64 << shift);
+        for us in powers.chain([1, 33, 999, u64::MAX]) {
+            let lower = bucket_lower_bound(bucket_index(us));
+            assert!(lower <= us && us - lower <= us / SUB_BUCKETS, "{us}");
+        }
+    }
+
+    #[test]
+    fn percentile_of_empty_histogram() {
+        let empty = Histogram::default();
+        assert_eq!(empty.percentile(0.5), Duration::ZERO);
+        assert_eq!(empty.mean(), Duration::ZERO);
+    }
+
+    #[test]
+    fn percentile_of_single_value() {
+        let single = histogram([1234]);
+        for quantile in [0., 0.5, 1.] {
+            assert_eq!(single.percentile(quantile), Duration::from_micros(1234));
+        }
+    }
+
+    #[test]
+    fn percentile_ranks() {
+        let histogram = histogram(1..=100);
+        assert_eq!(histogram.percentile(0.), Duration::from_micros(1));
+        assert_eq!(histogram.percentile(0.1), Duration::from_micros(10));
+        assert_eq!(histogram.percentile(0.5), Duration::from_micros(50));
+        // 98 and 99 share a bucket past 64us
+        assert_eq!(histogram.percentile(0.99), Duration::from_micros(98));
+        assert_eq!(histogram.percentile(1.), Duration::from_micros(100));
+        assert_eq!(histogram.mean(), Duration::from_micros(50));
+    }
+
+    #[test]
+    fn percentile_of_max_value() {
+        let histogram = histogram([u64::MAX]);
+        assert_eq!(histogram.percentile(1.), Duration::from_micros(u64::MAX));
+        assert_eq!(histogram.max(), Duration::from_micros(u64::MAX));
+    }
+
+    #[test]
+    fn merge_histograms() {
+        let mut merged = Histogram::default();
+        merged.merge(&Histogram::default());
+        assert_eq!(merged.count(), 0);
+
+        // Into an empty histogram, then with a longer bucket list
+        merged.merge(&histogram([10, 20]));
+        merged.merge(&histogram([5, 100_000]));
+        assert_eq!(merged.count(), 4);
+        assert_eq!(merged.percentile(0.), Duration::from_micros(5));
+        assert_eq!(merged.max(), Duration::from_micros(100_000));
+        assert_eq!(merged.mean(), Duration::from_micros(25_008));
+        assert_eq!(merged.percentile(0.5), Duration::from_micros(10));
+
+        // An empty histogram leaves the minimum alone
+        merged.merge(&Histogram::default());
+        assert_eq!(merged.count(), 4);
+        assert_eq!(merged.percentile(0.), Duration::from_micros(5));
+
+        let mut short = histogram([7]);
+        short.merge(&merged);
+        assert_eq!(short.count(), 5);
+        assert_eq!(short.percentile(0.4), Duration::from_micros(7));
+    }
+
+    fn report(rate: f64, latency_us: Option<u64>) -> ConnectionReport {
+        ConnectionReport {
+            rate,
+            latency: histogram(latency_us),
+            ..Default::default()
+        }
+    }
+
+    /// Runs [`flag_slow`] over connections active for [`MIN_ACTIVE`] and
+    /// returns the flags.
+    fn slow(reports: Vec<ConnectionReport>) -> Vec<bool> {
+        let mut reports: Vec<_> = reports
+            .into_iter()
+            .enumerate()
+            .map(|(id, report)| (id, report, MIN_ACTIVE))
+            .collect();
+        flag_slow(&mut reports);
+        reports.iter().map(|(_, report, _)| report.slow).collect()
+    }
+
+    #[test]
+    fn median_values() {
+        assert_eq!(median(Vec::new()), None);
+        assert_eq!(median(vec![1., 2.]), None);
+        assert_eq!(median(vec![3., 1., 2.]), Some(2.));
+        assert_eq!(median(vec![10., 1., 3., 2.]), Some(2.5));
+    }
+
+    #[test]
+    fn flag_slow_without_median() {
+        assert!(slow(Vec::new()).is_empty());
+        assert_eq!(slow(vec![report(1., Some(1_000_000))]), [false]);
+        assert_eq!(
+            slow(vec![report(100., None), report(1., None)]),
+            [false, false]
+        );
+    }
+
+    #[test]
+    fn flag_slow_rate_threshold() {
+        // Median of 100 pkt/s, slow below 50 pkt/s
+        let flags = slow(vec![
+            report(100., None),
+            report(100., None),
+            report(100., None),
+            report(50., None),
+            report(49.9, None),
+        ]);
+        assert_eq!(flags, [false, false, false, false, true]);
+    }
+
+    #[test]
+    fn flag_slow_latency_threshold() {
+        // Median of 1ms, slow above 2ms
+        let flags = slow(vec![
+            report(100., Some(1000)),
+            report(100., Some(1000)),
+            report(100., Some(1000)),
+            report(100., Some(2000)),
+            report(100., Some(2001)),
+            report(100., None),
+        ]);
+        assert_eq!(flags, [false, false, false, false, true, false]);
+    }
+
+    #[test]
+    fn flag_slow_skips_short_connections() {
+        let mut reports = vec![
+            (0, report(100., None), MIN_ACTIVE),
+            (1, report(100., None), MIN_ACTIVE),
+            (2, report(100., None), MIN_ACTIVE),
+            (3, report(1., None), MIN_ACTIVE / 2),
+        ];
+        flag_slow(&mut reports);
+        assert!(reports.iter().all(|(_, report, _)| !report.slow));
     }
 }
@@ -0,0 +1,266 @@
+//! Multiple destinations and how the simulated clients are spread across them.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs::File,
+    hash::{Hash, Hasher},
+    io::Read,
+    net::SocketAddr,
+    path::Path,
+    str::FromStr,
+};
+
+use serde::Deserialize;
+
+use crate::Destination;
+
+/// A destination and its share of the clients.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Target {
+    pub destination: Destination,
+    pub weight: usize,
+    /// `HOST:PORT` as given, when the address is a hostname.
+    pub host: Option<String>,
+}
+
+impl Target {
+    fn new(addr: &str, weight: usize) -> Result<Self, String> {
+        if weight == 0 {
+            return Err(format!("target weight must be > 0: {addr}"));
+        }
+        let hostname = !addr.starts_with("unix:") && addr.parse::<SocketAddr>().is_err();
+        Ok(Self {
+            destination: addr.parse()?,
+            weight,
+            host: hostname.then(|| addr.to_owned()),
+        })
+    }
+
+    /// Name used in the statistics, the hostname rather than the address
+    /// it resolved to.
+    pub fn label(&self) -> String {
+        self.host
+            .clone()
+            .unwrap_or_else(|| self.destination.to_string())
+    }
+}
+
+impl FromStr for Target {
+    type Err = String;
+
+    /// Parses `ADDR[@WEIGHT]`, the weight defaulting to 1.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, weight) = match s.rsplit_once('@') {
+            // Unix socket paths may contain '@' themselves
+            Some((addr, weight)) if weight.chars().all(|c| c.is_ascii_digit()) => {
+                let weight = weight
+                    .parse()
+                    .map_err(|_| format!("invalid target weight: {weight}"))?;
+                (addr, weight)
+            }
+            _ => (s, 1),
+        };
+        Self::new(addr, weight)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TargetsFile {
+    targets: Vec<TargetEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TargetEntry {
+    address: String,
+    #[serde(default = "default_weight")]
+    weight: usize,
+}
+
+fn default_weight() -> usize {
+    1
+}
+
+/// Reads a YAML file with a `targets` list of `address`/`weight` entries.
+pub fn load_targets<P: AsRef<Path>>(path: P) -> Result<Vec<Target>, Box<dyn std::error::Error>> {
+    let mut file = File::open(path)?;
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let file: TargetsFile = serde_yaml::from_str(&contents)?;
+    let targets = file
+        .targets
+        .into_iter()
+        .map(|entry| Target::new(&entry.address, entry.weight))
+        .collect::<Result<Vec<_>, String>>()?;
+    if targets.is_empty() {
+        return Err("targets file lists no targets".into());
+    }
+    Ok(targets)
+}
+
+/// How clients are assigned to targets.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Assignment {
+    /// Client `n` goes to target `n % targets`, weights are ignored.
+    #[default]
+    RoundRobin,
+    /// Smooth weighted round-robin, interleaving the targets in proportion
+    /// to their weights.
+    Weighted,
+    /// Weighted, but derived from a hash of the client id so the same
+    /// client always lands on the same target.
+    Hash,
+}
+
+impl FromStr for Assignment {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Self::RoundRobin),
+            "weighted" => Ok(Self::Weighted),
+            "hash" => Ok(Self::Hash),
+            _ => Err(format!("unknown target assignment: {s}")),
+        }
+    }
+}
+
+pub struct TargetSelector {
+    targets: Vec<Target>,
+    assignment: Assignment,
+    // Smooth weighted round-robin state, one entry per target
+    current: Vec<isize>,
+}
+
+impl TargetSelector {
+    pub fn new(targets: Vec<Target>, assignment: Assignment) -> Self {
+        let current = vec![0; targets.len()];
+        Self {
+            targets,
+            assignment,
+            current,
+        }
+    }
+
+    pub fn targets(&self) -> &[Target] {
+        &self.targets
+    }
+
+    /// Target the given client connects to.
+    pub fn select(&mut self, client: usize) -> &Target {
+        let idx = match self.assignment {
+            Assignment::RoundRobin => client % self.targets.len(),
+            Assignment::Weighted => {
+                let total: isize = self.targets.iter().map(|t| t.weight as isize).sum();
+                for (current, target) in self.current.iter_mut().zip(&self.targets) {
+                    *current += target.weight as isize;
+                }
+                let (idx, _) = self
+                    .current
+                    .iter()
+                    .enumerate()
+                    .max_by_key(|(idx, current)| (**current, std::cmp::Reverse(*idx)))
+                    .unwrap();
+                self.current[idx] -= total;
+                idx
+            }
+            Assignment::Hash => {
+                let mut hasher = DefaultHasher::new();
+                client.hash(&mut hasher);
+                let total: usize = self.targets.iter().map(|t| t.weight).sum();
+                let mut point = (hasher.finish() % total as u64) as usize;
+                self.targets
+                    .iter()
+                    .position(|t| {
+                        if point < t.weight {
+                            return true;
+                        }
+                        point -= t.weight;
+                        false
+                    })
+                    .unwrap()
+            }
+        };
+        &self.targets[idx]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn targets(specs: &[&str]) -> Vec<Target> {
+        specs.iter().map(|spec| spec.parse().unwrap()).collect()
+    }
+
+    /// Clients per target over `clients` selections.
+    fn spread(selector: &mut TargetSelector, clients: usize) -> Vec<usize> {
+        let mut counts = vec![0; selector.targets().len()];
+        for client in 0..clients {
+            let target = selector.select(client).clone();
+            let idx = selector
+                .targets()
+                .iter()
+                .position(|t| *t == target)
+                .unwrap();
+            counts[idx] += 1;
+        }
+        counts
+    }
+
+    #[test]
+    fn parses_weights() {
+        let [plain, weighted] = &targets(&["127.0.0.1:1", "127.0.0.1:2@3"])[..] else {
+            panic!("expected two targets");
+        };
+        assert_eq!((plain.weight, weighted.weight), (1, 3));
+        assert_eq!(weighted.label(), "127.0.0.1:2");
+        assert!("127.0.0.1:1@0".parse::<Target>().is_err());
+        assert!("127.0.0.1:1@".parse::<Target>().is_err());
+    }
+
+    #[test]
+    fn round_robin_ignores_weights() {
+        let mut selector = TargetSelector::new(
+            targets(&["127.0.0.1:1@5", "127.0.0.1:2"]),
+            Assignment::RoundRobin,
+        );
+        assert_eq!(spread(&mut selector, 10), [5, 5]);
+    }
+
+    #[test]
+    fn weighted_interleaves_in_proportion() {
+        let mut selector = TargetSelector::new(
+            targets(&["127.0.0.1:1@3", "127.0.0.1:2"]),
+            Assignment::Weighted,
+        );
+        let picks: Vec<_> = (0..4)
+            .map(|client| selector.select(client).weight)
+            .collect();
+        // Smooth: the lighter target is not left for the end of the cycle
+        assert_eq!(picks, [3, 3, 1, 3]);
+        assert_eq!(spread(&mut selector, 400), [300, 100]);
+    }
+
+    #[test]
+    fn zero_weight_target_gets_no_clients() {
+        let mut targets = targets(&["127.0.0.1:1", "127.0.0.1:2"]);
+        targets[1].weight = 0;
+        for assignment in [Assignment::Weighted, Assignment::Hash] {
+            let mut selector = TargetSelector::new(targets.clone(), assignment);
+            assert_eq!(spread(&mut selector, 100), [100, 0], "{assignment:?}");
+        }
+    }
+
+    #[test]
+    fn hash_is_stable_per_client() {
+        let targets = targets(&["127.0.0.1:1@2", "127.0.0.1:2", "127.0.0.1:3"]);
+        let mut first = TargetSelector::new(targets.clone(), Assignment::Hash);
+        let mut second = TargetSelector::new(targets, Assignment::Hash);
+        for client in (0..50).rev() {
+            assert_eq!(second.select(client), first.select(client));
+        }
+        let counts = spread(&mut first, 4000);
+        assert!(counts.iter().all(|&count| count > 0), "{counts:?}");
+        assert!(counts[0] > counts[1] && counts[0] > counts[2], "{counts:?}");
+    }
+}
@@ -0,0 +1,495 @@
+//! io_uring sender backend.
+//!
+//! A fixed pool of driver threads each owns one ring. Connections are spread
+//! across the drivers round-robin; once per second a driver queues the next
+//! second worth of payloads for every connection it owns and submits them to
+//! the kernel in batches, so a whole round costs a handful of syscalls instead
+//! of one future and one syscall per packet. Sends that would block are
+//! resubmitted behind a linked writability poll. A round waits for its
+//! sends for at most a second, those left over carry on in the next round so
+//! that a peer that stops reading only holds up its own connection.
+//!
+//! The backend is only available on Linux when crab-net is built with the
+//! `io-uring` cargo feature; elsewhere [`UringBackend::new`] fails and the
+//! caller falls back to the Tokio senders.
+
+use std::{
+    io,
+    net::{TcpStream, UdpSocket},
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use tokio::sync::oneshot;
+
+use crate::{control::ClientControl, payload::PayloadSource, statistics::StatsSender};
+
+/// Socket handed over to the io_uring backend. It must already be connected.
+pub enum UringSocket {
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+
+#[cfg_attr(not(all(target_os = "linux", feature = "io-uring")), allow(dead_code))]
+struct Connection {
+    id: usize,
+    socket: UringSocket,
+    payloads: PayloadSource,
+    control: ClientControl,
+    stats_tx: StatsSender,
+    // Dropped together with the connection to wake up the manager task,
+    // closed when the manager removed the client
+    done: oneshot::Sender<()>,
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+mod driver {
+    use std::{
+        collections::{HashMap, VecDeque},
+        io,
+        os::fd::{AsRawFd, RawFd},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use io_uring::{opcode, squeue, types, IoUring};
+    use log::{debug, error};
+
+    use super::{Connection, UringSocket};
+    use crate::statistics::StatPacket;
+
+    pub const RING_ENTRIES: u32 = 1024;
+
+    /// Send failures after which the connection is gone for good.
+    const FATAL_ERRORS: [i32; 3] = [libc::EPIPE, libc::ECONNRESET, libc::ECONNREFUSED];
+    /// Set in the `user_data` of the writability poll linked to a send.
+    const POLL: u64 = 1 << 63;
+    /// `user_data` of the cancellations, whose completions are skipped.
+    const CANCEL: u64 = u64::MAX;
+    /// Longest wait for completions, so that a stop is noticed soon.
+    const WAIT_SLICE: Duration = Duration::from_millis(100);
+
+    /// A send operation, indexed by its `user_data`.
+    struct Op {
+        conn: usize,
+        fd: RawFd,
+        buf: Vec<u8>,
+        offset: usize,
+        packets: usize,
+        /// The socket was full, wait for it to be writable before sending.
+        poll: bool,
+        in_flight: bool,
+        /// The connection is gone, the operation is only waited for.
+        cancelled: bool,
+    }
+
+    /// Traffic of a connection since the last report.
+    #[derive(Default)]
+    struct Sent {
+        bytes: usize,
+        packets: usize,
+        errors: usize,
+        failed: bool,
+    }
+
+    /// Send operations of a driver. Those not complete at the end of a round
+    /// are carried over to the next one rather than waited for, so that a
+    /// peer that stops reading does not hold up the other connections.
+    #[derive(Default)]
+    struct Ops {
+        ops: Vec<Option<Op>>,
+        free: Vec<usize>,
+        queue: VecDeque<usize>,
+        in_flight: usize,
+        /// Unfinished operations per connection id.
+        pending: HashMap<usize, usize>,
+        sent: HashMap<usize, Sent>,
+    }
+
+    impl Ops {
+        fn push(&mut self, op: Op) {
+            *self.pending.entry(op.conn).or_default() += 1;
+            let idx = match self.free.pop() {
+                Some(idx) => {
+                    self.ops[idx] = Some(op);
+                    idx
+                }
+                None => {
+                    self.ops.push(Some(op));
+                    self.ops.len() - 1
+                }
+            };
+            self.queue.push_back(idx);
+        }
+
+        fn finish(&mut self, idx: usize) -> Op {
+            let op = self.ops[idx].take().unwrap();
+            self.free.push(idx);
+            if let Some(pending) = self.pending.get_mut(&op.conn) {
+                *pending -= 1;
+                if *pending == 0 {
+                    self.pending.remove(&op.conn);
+                }
+            }
+            op
+        }
+
+        fn is_pending(&self, conn: usize) -> bool {
+            self.pending.contains_key(&conn)
+        }
+
+        /// Drops the queued operations of `conn` and cancels those in flight.
+        /// The socket must outlive the cancelled ones.
+        fn cancel(&mut self, ring: &mut IoUring, conn: usize) {
+            let queued: Vec<_> = self
+                .queue
+                .iter()
+                .copied()
+                .filter(|&idx| self.ops[idx].as_ref().is_some_and(|op| op.conn == conn))
+                .collect();
+            self.queue.retain(|idx| !queued.contains(idx));
+            for idx in queued {
+                self.finish(idx);
+            }
+            let mut submission = ring.submission();
+            for (idx, op) in self.ops.iter_mut().enumerate() {
+                let Some(op) = op.as_mut().filter(|op| op.conn == conn && op.in_flight) else {
+                    continue;
+                };
+                op.cancelled = true;
+                let user_data = match op.poll {
+                    true => idx as u64 | POLL,
+                    false => idx as u64,
+                };
+                let entry = opcode::AsyncCancel::new(user_data)
+                    .build()
+                    .user_data(CANCEL);
+                // SAFETY: a cancellation carries no buffer. With a full queue
+                // the operation is left to complete on its own.
+                let _ = unsafe { submission.push(&entry) };
+            }
+        }
+
+        /// Submits the queued operations and reaps their completions until
+        /// all are done, `deadline` passes or the driver is stopped.
+        fn drive(
+            &mut self,
+            ring: &mut IoUring,
+            deadline: Instant,
+            stop: &AtomicBool,
+        ) -> io::Result<()> {
+            loop {
+                {
+                    let mut submission = ring.submission();
+                    while let Some(&idx) = self.queue.front() {
+                        let op = self.ops[idx].as_ref().unwrap();
+                        let send = opcode::Send::new(
+                            types::Fd(op.fd),
+                            op.buf[op.offset..].as_ptr(),
+                            (op.buf.len() - op.offset) as u32,
+                        )
+                        .build()
+                        .user_data(idx as u64);
+                        // SAFETY: the buffer lives in `ops` until the completion is reaped,
+                        // and the socket until its operations are done
+                        let pushed = if op.poll {
+                            // The send only starts once the poll reports the socket writable
+                            let poll = opcode::PollAdd::new(types::Fd(op.fd), libc::POLLOUT as u32)
+                                .build()
+                                .flags(squeue::Flags::IO_LINK)
+                                .user_data(idx as u64 | POLL);
+                            unsafe { submission.push_multiple(&[poll, send]) }
+                        } else {
+                            unsafe { submission.push(&send) }
+                        };
+                        if pushed.is_err() {
+                            break;
+                        }
+                        self.queue.pop_front();
+                        self.ops[idx].as_mut().unwrap().in_flight = true;
+                        self.in_flight += 1;
+                    }
+                }
+                let now = Instant::now();
+                if self.in_flight == 0 || now >= deadline || stop.load(Ordering::Relaxed) {
+                    ring.submit()?;
+                    self.reap(ring);
+                    return Ok(());
+                }
+
+                let timeout = types::Timespec::from((deadline - now).min(WAIT_SLICE));
+                let args = types::SubmitArgs::new().timespec(&timeout);
+                if let Err(err) = ring.submitter().submit_with_args(1, &args) {
+                    // Running out of time only ends the wait
+                    if !matches!(err.raw_os_error(), Some(libc::ETIME | libc::EINTR)) {
+                        return Err(err);
+                    }
+                }
+                self.reap(ring);
+            }
+        }
+
+        fn reap(&mut self, ring: &mut IoUring) {
+            let completions: Vec<_> = ring
+                .completion()
+                .filter(|cqe| cqe.user_data() != CANCEL && cqe.user_data() & POLL == 0)
+                .map(|cqe| (cqe.user_data() as usize, cqe.result()))
+                .collect();
+            for (idx, result) in completions {
+                self.in_flight -= 1;
+                let op = self.ops[idx].as_mut().unwrap();
+                op.in_flight = false;
+                if op.cancelled {
+                    self.finish(idx);
+                // A failed poll cancels its send, which is then retried
+                } else if [libc::EAGAIN, libc::EINTR, libc::ECANCELED].contains(&-result) {
+                    op.poll = result != -libc::EINTR;
+                    self.queue.push_back(idx);
+                } else if result >= 0 {
+                    op.poll = false;
+                    op.offset += result as usize;
+                    if op.offset < op.buf.len() {
+                        self.queue.push_back(idx);
+                    } else {
+                        let op = self.finish(idx);
+                        let sent = self.sent.entry(op.conn).or_default();
+                        sent.bytes += op.buf.len();
+                        sent.packets += op.packets;
+                    }
+                } else {
+                    let op = self.finish(idx);
+                    let sent = self.sent.entry(op.conn).or_default();
+                    sent.errors += op.packets;
+                    sent.failed |= FATAL_ERRORS.contains(&-result);
+                }
+            }
+        }
+    }
+
+    pub fn probe() -> io::Result<()> {
+        let ring = IoUring::new(RING_ENTRIES)?;
+        match ring.params().is_feature_ext_arg() {
+            true => Ok(()),
+            false => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "kernel lacks io_uring wait timeouts",
+            )),
+        }
+    }
+
+    pub fn spawn(rx: kanal::Receiver<Connection>, stop: Arc<AtomicBool>) {
+        thread::spawn(move || {
+            let mut connections = Vec::new();
+            // Connections gone, kept until their operations are cancelled
+            let mut closing: Vec<Connection> = Vec::new();
+            let mut ops = Ops::default();
+            // Declared last to be dropped first, cancelling what is in flight
+            let mut ring = match IoUring::new(RING_ENTRIES) {
+                Ok(ring) => ring,
+                Err(err) => {
+                    error!("Unable to create io_uring instance: {err}");
+                    return;
+                }
+            };
+            let one_sec = Duration::from_secs(1);
+
+            while !stop.load(Ordering::Relaxed) {
+                let start_time = Instant::now();
+                loop {
+                    match rx.try_recv() {
+                        Ok(Some(conn)) => {
+                            debug!("client {} spawned", conn.id);
+                            connections.push(conn);
+                        }
+                        Ok(None) => break,
+                        Err(_) if connections.is_empty() => return,
+                        Err(_) => break,
+                    }
+                }
+                let (gone, kept) = connections
+                    .into_iter()
+                    .partition(|conn| conn.done.is_closed());
+                connections = kept;
+                retire(&mut ring, &mut ops, &mut closing, gone);
+
+                queue_round(&mut connections, &mut ops);
+                if let Err(err) = ops.drive(&mut ring, start_time + one_sec, &stop) {
+                    error!("io_uring driver failed: {err}");
+                    return;
+                }
+
+                let (failed, kept) = connections.into_iter().partition(|conn| {
+                    let failed = ops.sent.get(&conn.id).is_some_and(|sent| sent.failed);
+                    report(conn, ops.sent.remove(&conn.id).unwrap_or_default());
+                    failed
+                });
+                connections = kept;
+                for conn in &failed {
+                    debug!("client {} lost its connection", conn.id);
+                }
+                retire(&mut ring, &mut ops, &mut closing, failed);
+                closing.retain(|conn| ops.is_pending(conn.id));
+
+                let elapsed = start_time.elapsed();
+                if elapsed < one_sec {
+                    thread::sleep(one_sec - elapsed);
+                }
+            }
+        });
+    }
+
+    /// Queues one second worth of payloads for every connection without
+    /// unfinished sends. A connection whose peer does not keep up sends
+    /// nothing more until its backlog is written.
+    fn queue_round(connections: &mut [Connection], ops: &mut Ops) {
+        for connection in connections {
+            let rate = connection.control.poll_round(&mut connection.payloads);
+            if rate == 0 || ops.is_pending(connection.id) {
+                continue;
+            }
+            let conn = connection.id;
+            match &connection.socket {
+                UringSocket::Udp(socket) => {
+                    let fd = socket.as_raw_fd();
+                    for _ in 0..rate {
+                        ops.push(Op {
+                            conn,
+                            fd,
+                            buf: connection.payloads.next_payload(),
+                            offset: 0,
+                            packets: 1,
+                            poll: false,
+                            in_flight: false,
+                            cancelled: false,
+                        });
+                    }
+                }
+                // A single write per stream keeps the bytes in order
+                UringSocket::Tcp(stream) => ops.push(Op {
+                    conn,
+                    fd: stream.as_raw_fd(),
+                    buf: (0..rate)
+                        .flat_map(|_| connection.payloads.next_payload())
+                        .collect(),
+                    offset: 0,
+                    packets: rate,
+                    poll: false,
+                    in_flight: false,
+                    cancelled: false,
+                }),
+            }
+        }
+    }
+
+    /// Cancels the operations of connections that are gone. Dropping them
+    /// lets the manager see them close, their sockets are only closed once
+    /// no operation uses them.
+    fn retire(
+        ring: &mut IoUring,
+        ops: &mut Ops,
+        closing: &mut Vec<Connection>,
+        gone: Vec<Connection>,
+    ) {
+        for conn in gone {
+            ops.cancel(ring, conn.id);
+            ops.sent.remove(&conn.id);
+            if ops.is_pending(conn.id) {
+                closing.push(conn);
+            }
+        }
+    }
+
+    fn report(connection: &Connection, sent: Sent) {
+        if sent.errors > 0 {
+            let _ = connection
+                .stats_tx
+                .send_blocking(StatPacket::Errors(sent.errors));
+        }
+        let _ = connection.stats_tx.send_blocking(StatPacket::Traffic {
+            bytes: sent.bytes,
+            packets: sent.packets,
+        });
+    }
+}
+
+/// Handle to the io_uring driver threads. Dropping it stops the drivers.
+pub struct UringBackend {
+    drivers: Vec<kanal::Sender<Connection>>,
+    next: AtomicUsize,
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    stop: std::sync::Arc<std::sync::atomic::AtomicBool>,
+}
+
+impl UringBackend {
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    pub fn new(threads: usize) -> io::Result<Self> {
+        use std::sync::{atomic::AtomicBool, Arc};
+
+        driver::probe()?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let drivers = (0..threads.max(1))
+            .map(|_| {
+                let (tx, rx) = kanal::unbounded();
+                driver::spawn(rx, stop.clone());
+                tx
+            })
+            .collect();
+        Ok(Self {
+            drivers,
+            next: AtomicUsize::new(0),
+            stop,
+        })
+    }
+
+    #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+    pub fn new(_threads: usize) -> io::Result<Self> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "crab-net was built without the io-uring feature",
+        ))
+    }
+
+    /// Hands `socket` over to a driver thread. The returned receiver resolves
+    /// once the driver has dropped the connection.
+    pub fn register(
+        &self,
+        id: usize,
+        socket: UringSocket,
+        payloads: PayloadSource,
+        control: ClientControl,
+        stats_tx: StatsSender,
+    ) -> io::Result<oneshot::Receiver<()>> {
+        // Blocking sockets would have the kernel punt full sends to io-wq
+        // worker threads, the drivers poll for writability instead
+        match &socket {
+            UringSocket::Udp(socket) => socket.set_nonblocking(true)?,
+            UringSocket::Tcp(stream) => stream.set_nonblocking(true)?,
+        }
+
+        let (done_tx, done_rx) = oneshot::channel();
+        let connection = Connection {
+            id,
+            socket,
+            payloads,
+            control,
+            stats_tx,
+            done: done_tx,
+        };
+        let next = self.next.fetch_add(1, Ordering::Relaxed);
+        let driver = &self.drivers[next % self.drivers.len()];
+        driver
+            .send(connection)
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "io_uring driver stopped"))?;
+        Ok(done_rx)
+    }
+}
+
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+impl Drop for UringBackend {
+    fn drop(&mut self) {
+        self.stop.store(true, std::sync::atomic::Ordering::Relaxed);
+    }
+}
@@ -0,0 +1,521 @@
+//! WebSocket (RFC 6455) client: opening handshake, frame codec and sessions.
+
+use std::{
+    collections::VecDeque,
+    io,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha1::{Digest, Sha1};
+use tokio::{
+    io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, WriteHalf},
+    spawn,
+    sync::Mutex as AsyncMutex,
+    task::JoinHandle,
+};
+
+use crate::{statistics::Histogram, AsyncStream};
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+const MAX_HEADERS: usize = 64;
+/// Upper bound of a single frame and of a fragmented message received from
+/// the server, larger ones close the session instead of exhausting memory.
+const MAX_FRAME: u64 = 16 * 1024 * 1024;
+const MAX_MESSAGE: usize = 64 * 1024 * 1024;
+/// Echoed messages awaiting their answer, older ones are given up on.
+const MAX_PENDING: usize = 65536;
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+#[derive(Debug, Clone)]
+pub struct WsOptions {
+    pub path: String,
+    /// Extra headers as `Name: value` lines, sent with the upgrade request.
+    pub headers: Vec<String>,
+    /// Send payloads as binary frames instead of text frames.
+    pub binary: bool,
+    /// The server answers every message with one message, so the oldest
+    /// unanswered send time gives the round-trip latency.
+    pub echo: bool,
+}
+
+/// Messages received by a [`WsSession`] since the last call to
+/// [`WsSession::take_round`].
+#[derive(Debug, Default)]
+pub struct WsRound {
+    pub frames: usize,
+    pub bytes: usize,
+    pub latency: Histogram,
+    /// Echoed messages given up on without an answer.
+    pub lost: usize,
+}
+
+impl WsRound {
+    pub fn merge(&mut self, other: WsRound) {
+        self.frames += other.frames;
+        self.bytes += other.bytes;
+        self.latency.merge(&other.latency);
+        self.lost += other.lost;
+    }
+}
+
+#[derive(Default)]
+struct SessionState {
+    round: WsRound,
+    pending: VecDeque<Instant>,
+    closed: bool,
+}
+
+impl SessionState {
+    /// Records the send time of an echoed message, dropping the oldest one
+    /// once [`MAX_PENDING`] messages are unanswered.
+    fn sent(&mut self, at: Instant) {
+        if self.pending.len() >= MAX_PENDING {
+            self.pending.pop_front();
+            self.round.lost += 1;
+        }
+        self.pending.push_back(at);
+    }
+}
+
+/// Upgraded connection. Server frames are read by a background task that
+/// answers pings and close frames on its own.
+pub struct WsSession {
+    writer: Arc<AsyncMutex<WriteHalf<Box<dyn AsyncStream>>>>,
+    state: Arc<Mutex<SessionState>>,
+    reader: JoinHandle<()>,
+    opcode: u8,
+    echo: bool,
+}
+
+impl WsSession {
+    pub async fn connect(
+        mut stream: Box<dyn AsyncStream>,
+        host: &str,
+        options: &WsOptions,
+    ) -> io::Result<Self> {
+        let leftover = handshake(&mut stream, host, options).await?;
+        let (read, write) = split(stream);
+        let writer = Arc::new(AsyncMutex::new(write));
+        let state = Arc::new(Mutex::new(SessionState::default()));
+
+        let reader = spawn(read_frames(
+            read,
+            FrameReader {
+                buf: leftover,
+                message: 0,
+            },
+            writer.clone(),
+            state.clone(),
+            options.echo,
+        ));
+
+        Ok(Self {
+            writer,
+            state,
+            reader,
+            opcode: if options.binary { OP_BINARY } else { OP_TEXT },
+            echo: options.echo,
+        })
+    }
+
+    /// Sends `payload` as a single masked data frame.
+    pub async fn send(&self, payload: &[u8]) -> io::Result<()> {
+        let frame = encode_frame(self.opcode, payload);
+        let mut writer = self.writer.lock().await;
+        if self.echo {
+            self.state.lock().unwrap().sent(Instant::now());
+        }
+        writer.write_all(&frame).await
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.state.lock().unwrap().closed
+    }
+
+    pub fn take_round(&self) -> WsRound {
+        std::mem::take(&mut self.state.lock().unwrap().round)
+    }
+}
+
+impl Drop for WsSession {
+    fn drop(&mut self) {
+        self.reader.abort();
+    }
+}
+
+async fn read_frames<R: AsyncRead + Unpin>(
+    mut read: R,
+    mut reader: FrameReader,
+    writer: Arc<AsyncMutex<WriteHalf<Box<dyn AsyncStream>>>>,
+    state: Arc<Mutex<SessionState>>,
+    echo: bool,
+) {
+    while let Ok(frame) = reader.read_frame(&mut read).await {
+        match frame.opcode {
+            OP_PING => {
+                let pong = encode_frame(OP_PONG, &frame.payload);
+                if writer.lock().await.write_all(&pong).await.is_err() {
+                    break;
+                }
+            }
+            OP_PONG => {}
+            OP_CLOSE => {
+                // Echo the status code back, as the closing handshake asks for
+                let code = frame.payload.get(..2).unwrap_or_default();
+                let _ = writer
+                    .lock()
+                    .await
+                    .write_all(&encode_frame(OP_CLOSE, code))
+                    .await;
+                break;
+            }
+            OP_CONTINUATION | OP_TEXT | OP_BINARY => {
+                let mut state = state.lock().unwrap();
+                state.round.frames += 1;
+                state.round.bytes += frame.payload.len();
+                if echo && frame.fin {
+                    if let Some(sent_at) = state.pending.pop_front() {
+                        state.round.latency.record(sent_at.elapsed());
+                    }
+                }
+            }
+            _ => break,
+        }
+    }
+    state.lock().unwrap().closed = true;
+}
+
+/// Performs the opening handshake and returns the bytes the server sent
+/// past the response head.
+async fn handshake<S: AsyncRead + AsyncWrite + Unpin + ?Sized>(
+    stream: &mut S,
+    host: &str,
+    options: &WsOptions,
+) -> io::Result<Vec<u8>> {
+    let key = STANDARD.encode(fastrand::u128(..).to_be_bytes());
+    let headers: Vec<_> = options
+        .headers
+        .iter()
+        .filter_map(|h| h.split_once(':'))
+        .map(|(name, value)| (name.trim(), value.trim()))
+        .collect();
+
+    let mut request = format!("GET {} HTTP/1.1\r\n", options.path);
+    if !headers
+        .iter()
+        .any(|(name, _)| name.eq_ignore_ascii_case("host"))
+    {
+        request += &format!("Host: {host}\r\n");
+    }
+    request += "Upgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Version: 13\r\n";
+    request += &format!("Sec-WebSocket-Key: {key}\r\n");
+    for (name, value) in &headers {
+        request += &format!("{name}: {value}\r\n");
+    }
+    request += "\r\n";
+    stream.write_all(request.as_bytes()).await?;
+
+    let mut buf = Vec::new();
+    loop {
+        if stream.read_buf(&mut buf).await? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut response = httparse::Response::new(&mut headers);
+        if let httparse::Status::Complete(head_len) =
+            response.parse(&buf).map_err(io::Error::other)?
+        {
+            let status = response.code.unwrap_or_default();
+            if status != 101 {
+                return Err(io::Error::other(format!(
+                    "upgrade refused with status {status}"
+                )));
+            }
+            let accept = response
+                .headers
+                .iter()
+                .find(|h| h.name.eq_ignore_ascii_case("sec-websocket-accept"))
+                .map(|h| h.value);
+            if accept != Some(accept_key(&key).as_bytes()) {
+                return Err(io::Error::other("invalid Sec-WebSocket-Accept"));
+            }
+            buf.drain(..head_len);
+            return Ok(buf);
+        }
+    }
+}
+
+fn accept_key(key: &str) -> String {
+    let mut sha1 = Sha1::new();
+    sha1.update(key.as_bytes());
+    sha1.update(ACCEPT_GUID.as_bytes());
+    STANDARD.encode(sha1.finalize())
+}
+
+/// Encodes a single final frame, masked with a fresh key as required for
+/// client frames.
+fn encode_frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 14);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len if len < 126 => frame.push(0x80 | len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    let mask = fastrand::u32(..).to_be_bytes();
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(b, m)| b ^ m));
+    frame
+}
+
+struct Frame {
+    fin: bool,
+    opcode: u8,
+    payload: Vec<u8>,
+}
+
+/// Incremental frame decoder keeping partial frames between reads.
+struct FrameReader {
+    buf: Vec<u8>,
+    /// Bytes of the data message being received so far.
+    message: usize,
+}
+
+impl FrameReader {
+    async fn read_frame<S: AsyncRead + Unpin>(&mut self, stream: &mut S) -> io::Result<Frame> {
+        loop {
+            if let Some((frame, len)) = parse_frame(&self.buf)? {
+                self.buf.drain(..len);
+                match frame.opcode {
+                    OP_TEXT | OP_BINARY => self.message = frame.payload.len(),
+                    OP_CONTINUATION => self.message += frame.payload.len(),
+                    _ => {}
+                }
+                if self.message > MAX_MESSAGE {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("message larger than {MAX_MESSAGE} bytes"),
+                    ));
+                }
+                return Ok(frame);
+            }
+            if stream.read_buf(&mut self.buf).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+}
+
+/// Decodes the frame at the start of `buf`, if it is complete, along with
+/// its encoded length. Fails on frames larger than [`MAX_FRAME`].
+fn parse_frame(buf: &[u8]) -> io::Result<Option<(Frame, usize)>> {
+    let Some((first, len, offset, mask)) = parse_header(buf) else {
+        return Ok(None);
+    };
+    if len > MAX_FRAME {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame of {len} bytes is larger than {MAX_FRAME} bytes"),
+        ));
+    }
+    let len = len as usize;
+    let Some(payload) = buf.get(offset..offset + len) else {
+        return Ok(None);
+    };
+
+    let mut payload = payload.to_vec();
+    if let Some(mask) = mask {
+        for (b, m) in payload.iter_mut().zip(mask.iter().cycle()) {
+            *b ^= m;
+        }
+    }
+    let frame = Frame {
+        fin: first & 0x80 != 0,
+        opcode: first & 0x0F,
+        payload,
+    };
+    Ok(Some((frame, offset + len)))
+}
+
+/// Decodes the first byte, payload length, payload offset and mask of the
+/// frame at the start of `buf`, if the header is complete.
+fn parse_header(buf: &[u8]) -> Option<(u8, u64, usize, Option<[u8; 4]>)> {
+    let (&first, &second) = (buf.first()?, buf.get(1)?);
+    let (len, mut offset) = match second & 0x7F {
+        126 => (
+            u16::from_be_bytes(buf.get(2..4)?.try_into().ok()?) as u64,
+            4,
+        ),
+        127 => (u64::from_be_bytes(buf.get(2..10)?.try_into().ok()?), 10),
+        len => (len as u64, 2),
+    };
+    let mask = if second & 0x80 != 0 {
+        let mask: [u8; 4] = buf.get(offset..offset + 4)?.try_into().ok()?;
+        offset += 4;
+        Some(mask)
+    } else {
+        None
+    };
+    Some((first, len, offset, mask))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Unmasks a client frame produced by [`encode_frame`].
+    fn decode(frame: &[u8]) -> Frame {
+        let (parsed, len) = parse_frame(frame).unwrap().unwrap();
+        assert_eq!(len, frame.len());
+        parsed
+    }
+
+    #[test]
+    fn accept_key_matches_rfc_sample() {
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+
+    #[test]
+    fn encode_frame_masks_payload() {
+        let frame = encode_frame(OP_TEXT, b"hello");
+        assert_eq!(frame[0], 0x80 | OP_TEXT);
+        assert_eq!(frame[1], 0x80 | 5);
+        let mask = &frame[2..6];
+        let masked: Vec<u8> = frame[6..]
+            .iter()
+            .zip(mask.iter().cycle())
+            .map(|(b, m)| b ^ m)
+            .collect();
+        assert_eq!(masked, b"hello");
+
+        let decoded = decode(&frame);
+        assert!(decoded.fin);
+        assert_eq!(
+            (decoded.opcode, decoded.payload.as_slice()),
+            (OP_TEXT, &b"hello"[..])
+        );
+    }
+
+    #[test]
+    fn encode_frame_lengths() {
+        // 7 bit, 16 bit and 64 bit payload lengths, each at its limits
+        for (len, header) in [
+            (0, 6),
+            (125, 6),
+            (126, 8),
+            (u16::MAX as usize, 8),
+            (u16::MAX as usize + 1, 14),
+        ] {
+            let payload = vec![0x5A; len];
+            let frame = encode_frame(OP_BINARY, &payload);
+            assert_eq!(frame.len(), header + len, "payload of {len} bytes");
+            let (_, parsed_len, offset, mask) = parse_header(&frame).unwrap();
+            assert_eq!((parsed_len, offset), (len as u64, header));
+            assert!(mask.is_some());
+            assert_eq!(decode(&frame).payload, payload);
+        }
+    }
+
+    #[test]
+    fn parse_header_needs_complete_header() {
+        assert_eq!(parse_header(&[]), None);
+        assert_eq!(parse_header(&[0x81]), None);
+        assert_eq!(parse_header(&[0x81, 126, 0x01]), None);
+        assert_eq!(parse_header(&[0x82, 127, 0, 0, 0, 0, 0, 1]), None);
+        assert_eq!(parse_header(&[0x81, 0x85, 1, 2, 3]), None);
+        assert_eq!(
+            parse_header(&[0x81, 126, 0x01, 0x00]),
+            Some((0x81, 256, 4, None))
+        );
+        assert_eq!(
+            parse_header(&[0x01, 0x83, 1, 2, 3, 4]),
+            Some((0x01, 3, 6, Some([1, 2, 3, 4])))
+        );
+    }
+
+    #[test]
+    fn parse_frame_waits_for_partial_input() {
+        let frame = [0x81, 0x05, b'h', b'e', b'l', b'l', b'o', 0x89];
+        for end in 0..7 {
+            assert!(parse_frame(&frame[..end]).unwrap().is_none(), "{end} bytes");
+        }
+        // The trailing byte belongs to the next frame
+        let (parsed, len) = parse_frame(&frame).unwrap().unwrap();
+        assert_eq!((parsed.payload.as_slice(), len), (&b"hello"[..], 7));
+    }
+
+    #[test]
+    fn parse_frame_control_frames() {
+        let (ping, _) = parse_frame(&[0x89, 0x02, b'h', b'i']).unwrap().unwrap();
+        assert_eq!(
+            (ping.opcode, ping.payload.as_slice()),
+            (OP_PING, &b"hi"[..])
+        );
+        let (close, _) = parse_frame(&[0x88, 0x02, 0x03, 0xE8]).unwrap().unwrap();
+        assert_eq!(
+            (close.opcode, close.payload.as_slice()),
+            (OP_CLOSE, &[0x03, 0xE8][..])
+        );
+        // Unfragmented data frame versus the first fragment of a message
+        let (first, _) = parse_frame(&[0x01, 0x00]).unwrap().unwrap();
+        assert!(!first.fin);
+    }
+
+    #[test]
+    fn parse_frame_rejects_large_frames() {
+        let mut frame = vec![0x82, 127];
+        frame.extend_from_slice(&(MAX_FRAME + 1).to_be_bytes());
+        let err = parse_frame(&frame).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut frame = vec![0x82, 127];
+        frame.extend_from_slice(&MAX_FRAME.to_be_bytes());
+        assert!(parse_frame(&frame).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn frame_reader_limits_messages() {
+        let mut reader = FrameReader {
+            buf: Vec::new(),
+            message: MAX_MESSAGE - 2,
+        };
+        let mut input = &[0x00, 0x02, b'a', b'b', 0x80, 0x01, b'c'][..];
+        // Fragments up to the limit pass, the one beyond fails
+        assert_eq!(reader.read_frame(&mut input).await.unwrap().payload, b"ab");
+        let err = reader.read_frame(&mut input).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // A new message starts counting over
+        let mut input = &[0x82, 0x01, b'd'][..];
+        assert_eq!(reader.read_frame(&mut input).await.unwrap().payload, b"d");
+        assert_eq!(reader.message, 1);
+    }
+
+    #[test]
+    fn pending_echoes_are_capped() {
+        let mut state = SessionState::default();
+        let now = Instant::now();
+        for _ in 0..MAX_PENDING + 3 {
+            state.sent(now);
+        }
+        assert_eq!(state.pending.len(), MAX_PENDING);
+        assert_eq!(state.round.lost, 3);
+    }
+}
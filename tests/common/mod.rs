@@ -0,0 +1,54 @@
+//! Helpers shared by the integration tests.
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::AsyncReadExt,
+    net::TcpListener,
+    spawn,
+    time::{sleep, timeout},
+};
+
+/// Counts the bytes received by a TCP listener, over all its connections.
+pub async fn tcp_sink() -> (SocketAddr, Arc<AtomicU64>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let received = Arc::new(AtomicU64::new(0));
+    let counter = received.clone();
+    spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let counter = counter.clone();
+            spawn(async move {
+                let mut buf = [0; 4096];
+                while let Ok(len @ 1..) = stream.read(&mut buf).await {
+                    counter.fetch_add(len as u64, Ordering::Relaxed);
+                }
+            });
+        }
+    });
+    (addr, received)
+}
+
+/// Waits for the sinks to have received `expected` bytes between them.
+pub async fn wait_for(sinks: &[&AtomicU64], expected: u64) {
+    let total = || {
+        sinks
+            .iter()
+            .map(|received| received.load(Ordering::Relaxed))
+            .sum::<u64>()
+    };
+    let arrived = async {
+        while total() < expected {
+            sleep(Duration::from_millis(10)).await;
+        }
+    };
+    let _ = timeout(Duration::from_secs(5), arrived).await;
+    assert_eq!(total(), expected);
+}
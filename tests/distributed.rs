@@ -0,0 +1,59 @@
+mod common;
+
+use std::{net::SocketAddr, sync::atomic::Ordering};
+
+use common::{tcp_sink, wait_for};
+use crab_net::{
+    distributed::{coordinate, serve_coordinators},
+    LoadTest,
+};
+use tokio::{net::TcpListener, spawn};
+
+const PAYLOAD: &str = "hello";
+
+/// Starts an in-process agent on an ephemeral port. The scenario pushed by
+/// the coordinator is just the address of the sink to send to.
+async fn local_agent() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    spawn(serve_coordinators(listener, |scenario: &str| {
+        let sink: SocketAddr = scenario.parse().map_err(|err| format!("{err}"))?;
+        let test = LoadTest::builder()
+            .tcp(sink)
+            .connections(2)
+            .rate(5)
+            .payload(PAYLOAD)
+            .max_packets(10)
+            .build()?;
+        Ok(vec![vec![test.into()]])
+    }));
+    addr
+}
+
+#[tokio::test]
+async fn coordinator_merges_agent_reports() {
+    let (first_sink, first_received) = tcp_sink().await;
+    let (second_sink, second_received) = tcp_sink().await;
+    let agents = vec![
+        (local_agent().await, first_sink.to_string()),
+        (local_agent().await, second_sink.to_string()),
+    ];
+
+    let report = coordinate(agents).await.unwrap();
+
+    assert!(
+        report.packets_sent >= 20,
+        "sent {} packets",
+        report.packets_sent
+    );
+    assert_eq!(
+        report.bytes_sent,
+        (report.packets_sent * PAYLOAD.len()) as u64
+    );
+    assert_eq!(report.errors, 0);
+    wait_for(&[&first_received, &second_received], report.bytes_sent).await;
+    // Each agent ran the whole scenario against its own sink
+    for received in [&first_received, &second_received] {
+        assert!(received.load(Ordering::Relaxed) >= 10 * PAYLOAD.len() as u64);
+    }
+}
@@ -0,0 +1,149 @@
+mod common;
+
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use common::{tcp_sink, wait_for};
+use crab_net::{
+    framing::Framing,
+    server::{serve, CertFiles, ServeParameters},
+    LoadTest,
+};
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    spawn,
+    time::sleep,
+};
+
+const PAYLOAD: &str = "hello";
+
+/// Counts the bytes of the datagrams received by a UDP socket.
+async fn udp_sink() -> (SocketAddr, Arc<AtomicU64>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let addr = socket.local_addr().unwrap();
+    let received = Arc::new(AtomicU64::new(0));
+    let counter = received.clone();
+    spawn(async move {
+        let mut buf = [0; 65536];
+        while let Ok(len) = socket.recv(&mut buf).await {
+            counter.fetch_add(len as u64, Ordering::Relaxed);
+        }
+    });
+    (addr, received)
+}
+
+#[tokio::test]
+async fn tcp_run_reports_sent_traffic() {
+    let (addr, received) = tcp_sink().await;
+    let report = LoadTest::builder()
+        .tcp(addr)
+        .connections(2)
+        .rate(5)
+        .payload(PAYLOAD)
+        .max_packets(10)
+        .build()
+        .unwrap()
+        .run()
+        .await
+        .unwrap();
+
+    assert!(
+        report.packets_sent >= 10,
+        "sent {} packets",
+        report.packets_sent
+    );
+    assert_eq!(
+        report.bytes_sent,
+        (report.packets_sent * PAYLOAD.len()) as u64
+    );
+    assert_eq!(report.errors, 0);
+    wait_for(&[&received], report.bytes_sent).await;
+}
+
+#[tokio::test]
+async fn udp_run_reports_sent_traffic() {
+    let (addr, received) = udp_sink().await;
+    let report = LoadTest::builder()
+        .udp(addr)
+        .connections(2)
+        .rate(5)
+        .payload(PAYLOAD)
+        .max_packets(10)
+        .build()
+        .unwrap()
+        .run()
+        .await
+        .unwrap();
+
+    assert!(
+        report.packets_sent >= 10,
+        "sent {} packets",
+        report.packets_sent
+    );
+    assert_eq!(
+        report.bytes_sent,
+        (report.packets_sent * PAYLOAD.len()) as u64
+    );
+    assert_eq!(report.errors, 0);
+    wait_for(&[&received], report.bytes_sent).await;
+}
+
+#[test]
+fn missing_ca_file_is_a_builder_error() {
+    let addr: SocketAddr = "127.0.0.1:4433".parse().unwrap();
+    let err = LoadTest::builder()
+        .tls(addr)
+        .ca("does/not/exist.pem")
+        .build()
+        .err()
+        .unwrap();
+    assert!(err.contains("does/not/exist.pem"), "{err}");
+}
+
+#[tokio::test]
+async fn tcp_run_against_echo_server() {
+    // Free port for the built-in server, which binds it itself
+    let addr = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let server = ServeParameters::new(
+        addr,
+        false,
+        false,
+        true,
+        CertFiles::default(),
+        Framing::None,
+        Duration::from_secs(10),
+    );
+    spawn(serve(server));
+    sleep(Duration::from_millis(100)).await;
+
+    // Enough to fill the socket buffers if the echoes were not read
+    let payload = "x".repeat(1000);
+    let report = LoadTest::builder()
+        .tcp(addr)
+        .rate(5000)
+        .payload(payload.as_str())
+        .duration(Duration::from_secs(3))
+        .build()
+        .unwrap()
+        .run()
+        .await
+        .unwrap();
+
+    assert!(
+        report.packets_sent >= 10000,
+        "sent {} packets",
+        report.packets_sent
+    );
+    assert_eq!(report.errors, 0);
+    assert!(report.bytes_received > 0);
+}